@@ -0,0 +1,295 @@
+use core::fmt;
+
+use serde::{
+    de::{
+        self,
+        value::{MapDeserializer, SeqDeserializer},
+        IntoDeserializer, Visitor,
+    },
+    forward_to_deserialize_any, Deserialize,
+};
+
+use crate::BencodeTypes;
+
+/// Why a `BencodeTypes` didn't fit the type it was deserialized into
+#[derive(Debug, PartialEq, Eq)]
+pub struct DeError(pub String);
+
+impl fmt::Display for DeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DeError {}
+
+impl de::Error for DeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        DeError(msg.to_string())
+    }
+}
+
+/// Fills a `T: Deserialize` from decoded bencode, e.g. a torrent's
+/// `BencodeTypes::Dict(decode_dict(&mut 0, &buf)?)`
+pub fn from_bencode<'de, T: Deserialize<'de>>(value: BencodeTypes) -> Result<T, DeError> {
+    T::deserialize(value)
+}
+
+impl<'de> IntoDeserializer<'de, DeError> for BencodeTypes {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+/// Strings are utf-8 text or, through `deserialize_bytes`, raw bytes. `Bytes`, `Pieces`
+/// (the hashes one after another) and `InfoHash` are only bytes, which is what `serde_bytes`
+/// reads. Integers are 0 or 1 for a `bool`, compact peers are a list of `ip:port` strings
+impl<'de> de::Deserializer<'de> for BencodeTypes {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self {
+            BencodeTypes::String(s) => visitor.visit_string(s),
+            BencodeTypes::Integer(i) => visitor.visit_u64(i),
+            BencodeTypes::List(list) => visitor.visit_seq(SeqDeserializer::new(list.into_iter())),
+            BencodeTypes::Dict(dict) => visitor.visit_map(MapDeserializer::new(dict.into_iter())),
+            BencodeTypes::InfoHash(hash) => visitor.visit_byte_buf(hash.to_vec()),
+            BencodeTypes::Pieces(pieces) => visitor.visit_byte_buf(pieces.concat()),
+            BencodeTypes::PeersCompact(peers) => visitor.visit_seq(SeqDeserializer::new(
+                peers.into_iter().map(|peer| peer.to_string()),
+            )),
+            BencodeTypes::Bytes(bytes) => visitor.visit_byte_buf(bytes),
+            BencodeTypes::BytesDict(dict) => visitor.visit_map(MapDeserializer::new(
+                dict.into_iter()
+                    .map(|(key, value)| (BencodeTypes::Bytes(key), value)),
+            )),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self {
+            BencodeTypes::String(s) => visitor.visit_byte_buf(s.into_bytes()),
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self {
+            BencodeTypes::Integer(i @ (0 | 1)) => visitor.visit_bool(i == 1),
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    /// Bencode has no null, a key that is there is always `Some`
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Only unit variants, named by a string
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        match self {
+            BencodeTypes::String(s) => visitor.visit_enum(s.into_deserializer()),
+            _ => Err(de::Error::custom(
+                "expected a string naming an enum variant",
+            )),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_dict;
+    use serde::de::{Deserializer, MapAccess};
+
+    /// What `serde_bytes::ByteBuf` does: asks for bytes instead of a sequence
+    #[derive(Debug, PartialEq)]
+    struct ByteBuf(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for ByteBuf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct BytesVisitor;
+
+            impl Visitor<'_> for BytesVisitor {
+                type Value = ByteBuf;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("bytes")
+                }
+
+                fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<ByteBuf, E> {
+                    Ok(ByteBuf(v))
+                }
+            }
+
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Info {
+        name: String,
+        length: u32,
+        pieces: ByteBuf,
+        private: Option<bool>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Torrent {
+        announce: String,
+        info_hash: ByteBuf,
+        info: Info,
+    }
+
+    // serde_derive isn't in the offline registry, these are the impls
+    // `#[derive(Deserialize)]` would generate, unknown keys ignored
+    impl<'de> Deserialize<'de> for Info {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct InfoVisitor;
+
+            impl<'de> Visitor<'de> for InfoVisitor {
+                type Value = Info;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("an info dict")
+                }
+
+                fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Info, A::Error> {
+                    let (mut name, mut length, mut pieces, mut private) = (None, None, None, None);
+                    while let Some(key) = map.next_key::<String>()? {
+                        match key.as_str() {
+                            "name" => name = Some(map.next_value()?),
+                            "length" => length = Some(map.next_value()?),
+                            "pieces" => pieces = Some(map.next_value()?),
+                            "private" => private = map.next_value()?,
+                            _ => map.next_value::<de::IgnoredAny>().map(|_| ())?,
+                        }
+                    }
+                    Ok(Info {
+                        name: name.ok_or_else(|| de::Error::missing_field("name"))?,
+                        length: length.ok_or_else(|| de::Error::missing_field("length"))?,
+                        pieces: pieces.ok_or_else(|| de::Error::missing_field("pieces"))?,
+                        private,
+                    })
+                }
+            }
+
+            deserializer.deserialize_struct("Info", &["name", "length", "pieces"], InfoVisitor)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Torrent {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct TorrentVisitor;
+
+            impl<'de> Visitor<'de> for TorrentVisitor {
+                type Value = Torrent;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("a torrent dict")
+                }
+
+                fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Torrent, A::Error> {
+                    let (mut announce, mut info_hash, mut info) = (None, None, None);
+                    while let Some(key) = map.next_key::<String>()? {
+                        match key.as_str() {
+                            "announce" => announce = Some(map.next_value()?),
+                            "info_hash" => info_hash = Some(map.next_value()?),
+                            "info" => info = Some(map.next_value()?),
+                            _ => map.next_value::<de::IgnoredAny>().map(|_| ())?,
+                        }
+                    }
+                    Ok(Torrent {
+                        announce: announce.ok_or_else(|| de::Error::missing_field("announce"))?,
+                        info_hash: info_hash
+                            .ok_or_else(|| de::Error::missing_field("info_hash"))?,
+                        info: info.ok_or_else(|| de::Error::missing_field("info"))?,
+                    })
+                }
+            }
+
+            deserializer.deserialize_struct(
+                "Torrent",
+                &["announce", "info_hash", "info"],
+                TorrentVisitor,
+            )
+        }
+    }
+
+    fn torrent(info_extra: &[u8]) -> Vec<u8> {
+        let mut buf = b"d8:announce31:http://tracker.example/announce7:comment2:hi4:info".to_vec();
+        buf.extend_from_slice(b"d6:lengthi40e4:name8:test.bin12:piece lengthi16e6:pieces60:");
+        buf.extend(0..60);
+        buf.extend_from_slice(info_extra);
+        buf.extend_from_slice(b"ee");
+        buf
+    }
+
+    #[test]
+    fn fills_a_struct_from_a_torrent() {
+        let buf = torrent(b"7:privatei1e");
+        let dict = decode_dict(&mut 0, &buf).unwrap();
+        let info_hash = crate::TorrentFile::parse(&buf).unwrap().info_hash;
+
+        let torrent: Torrent = from_bencode(BencodeTypes::Dict(dict)).unwrap();
+
+        assert_eq!(
+            torrent,
+            Torrent {
+                announce: String::from("http://tracker.example/announce"),
+                info_hash: ByteBuf(info_hash.to_vec()),
+                info: Info {
+                    name: String::from("test.bin"),
+                    length: 40,
+                    pieces: ByteBuf((0..60).collect()),
+                    private: Some(true),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn reports_values_that_dont_fit() {
+        let dict = |buf: &[u8]| BencodeTypes::Dict(decode_dict(&mut 0, buf).unwrap());
+
+        let missing = from_bencode::<Torrent>(dict(b"d8:announce1:xe")).unwrap_err();
+        assert_eq!(missing, DeError(String::from("missing field `info_hash`")));
+
+        // `private` isn't 0 or 1, and 2^32 doesn't fit `length`
+        let private = from_bencode::<Torrent>(dict(&torrent(b"7:privatei2e")));
+        assert!(private.is_err());
+        let long = String::from_utf8_lossy(&torrent(b"")).replace("i40e", "i4294967296e");
+        assert!(from_bencode::<Torrent>(dict(long.as_bytes())).is_err());
+
+        assert_eq!(
+            from_bencode::<Vec<String>>(BencodeTypes::PeersCompact(vec!["127.0.0.1:6881"
+                .parse()
+                .unwrap()])),
+            Ok(vec![String::from("127.0.0.1:6881")])
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use core::str;
-use std::{collections::HashMap, process::exit};
-
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 
 use crate::{errors::ParseError, get_hash};
 
@@ -10,7 +12,12 @@ const DICTIONARY_START: u8 = 0x64; // 'd'
 const LIST_START: u8 = 0x6C; // 'l'
 const END_OF_TYPE: u8 = 0x65; // 'e'
 
-pub type Peers = Vec<((u8, u8, u8, u8), u16)>;
+pub type Peers = Vec<SocketAddr>;
+
+/// Compact IPv4 peer entry, 4 bytes ip + 2 bytes port
+pub const COMPACT_PEER_V4_LEN: usize = 6;
+/// Compact IPv6 peer entry (BEP 7), 16 bytes ip + 2 bytes port
+pub const COMPACT_PEER_V6_LEN: usize = 18;
 
 #[derive(Debug, PartialEq)]
 pub enum BencodeTypes {
@@ -21,6 +28,10 @@ pub enum BencodeTypes {
     InfoHash([u8; 20]),
     Pieces(Vec<[u8; 20]>),
     PeersCompact(Peers),
+    /// A string that isn't utf-8, e.g. the node ids and tokens of DHT messages
+    Bytes(Vec<u8>),
+    /// A dict with keys that aren't all utf-8, e.g. the raw info hashes keying a scrape's `files`
+    BytesDict(HashMap<Vec<u8>, BencodeTypes>),
 }
 
 fn parse_to_utf8(slice: &[u8]) -> Result<String, ParseError> {
@@ -28,16 +39,25 @@ fn parse_to_utf8(slice: &[u8]) -> Result<String, ParseError> {
     Ok(string_slice.to_string())
 }
 
-fn parse_to_usize(slice: &[u8]) -> Result<usize, ParseError> {
+pub(crate) fn parse_to_usize(slice: &[u8]) -> Result<usize, ParseError> {
     let string = parse_to_utf8(slice)?;
     Ok(string.parse()?)
 }
 
-fn get_string_len(pointer: &mut usize, buf: &Vec<u8>) -> Result<usize, ParseError> {
+/// `buf[pointer]`, truncated input is a `BadFile` instead of a panic
+fn byte_at(pointer: usize, buf: &[u8]) -> Result<u8, ParseError> {
+    buf.get(pointer).copied().ok_or(ParseError::BadFile)
+}
+
+fn slice_of(buf: &[u8], start: usize, end: usize) -> Result<&[u8], ParseError> {
+    buf.get(start..end).ok_or(ParseError::BadFile)
+}
+
+fn get_string_len(pointer: &mut usize, buf: &[u8]) -> Result<usize, ParseError> {
     let mut temp = Vec::new();
 
     // Increment and push to vec until delim
-    while buf[*pointer] != STRING_DELIM {
+    while byte_at(*pointer, buf)? != STRING_DELIM {
         temp.push(buf[*pointer]);
         *pointer += 1;
     }
@@ -49,32 +69,77 @@ fn get_string_len(pointer: &mut usize, buf: &Vec<u8>) -> Result<usize, ParseErro
         return Ok(0);
     }
 
-    Ok(parse_to_usize(&temp)?)
+    parse_to_usize(&temp)
+}
+
+/// Reads a length prefix and returns where the string after it ends,
+/// a length that overflows `usize` is a `BadFile` too
+fn get_string_end(pointer: &mut usize, buf: &[u8]) -> Result<usize, ParseError> {
+    get_string_len(pointer, buf)?
+        .checked_add(*pointer)
+        .ok_or(ParseError::BadFile)
 }
 
-pub fn decode_string(pointer: &mut usize, buf: &Vec<u8>) -> Result<String, ParseError> {
-    let string_len = get_string_len(pointer, buf)?;
+pub fn decode_string(pointer: &mut usize, buf: &[u8]) -> Result<String, ParseError> {
+    let string_len = get_string_end(pointer, buf)?;
+    let slice: &[u8] = slice_of(buf, *pointer, string_len)?;
 
-    if string_len == 0 {
-        return Ok("".to_string());
-    }
+    // Place pointer at the byte after the string (after the last char)
+    *pointer = string_len;
 
-    let string_len = string_len + *pointer;
-    let slice: &[u8] = &buf[*pointer..string_len];
+    parse_to_utf8(slice)
+}
+
+/// A `String` if the bytes are utf-8, `Bytes` otherwise
+fn decode_string_or_bytes(pointer: &mut usize, buf: &[u8]) -> Result<BencodeTypes, ParseError> {
+    let string_len = get_string_end(pointer, buf)?;
+    let slice = slice_of(buf, *pointer, string_len)?;
 
     // Place pointer at the byte after the string (after the last char)
     *pointer = string_len;
 
-    Ok(parse_to_utf8(slice)?)
+    Ok(string_or_bytes(slice.to_vec()))
 }
 
-pub fn decode_int(pointer: &mut usize, buf: &Vec<u8>) -> Result<u64, ParseError> {
+/// Shared with `BencodeReader` so both decoders agree on string values
+pub(crate) fn string_or_bytes(bytes: Vec<u8>) -> BencodeTypes {
+    match String::from_utf8(bytes) {
+        Ok(s) => BencodeTypes::String(s),
+        Err(e) => BencodeTypes::Bytes(e.into_bytes()),
+    }
+}
+
+/// A `Dict` if every key is utf-8, `BytesDict` otherwise. Shared with `BencodeReader` too
+pub(crate) fn dict_or_bytes_dict(entries: Vec<(Vec<u8>, BencodeTypes)>) -> BencodeTypes {
+    if entries.iter().all(|(key, _)| str::from_utf8(key).is_ok()) {
+        BencodeTypes::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (String::from_utf8(key).expect("checked above"), value))
+                .collect(),
+        )
+    } else {
+        BencodeTypes::BytesDict(entries.into_iter().collect())
+    }
+}
+
+/// Keys of a top-level dict have to be utf-8
+pub(crate) fn into_string_keys(
+    entries: Vec<(Vec<u8>, BencodeTypes)>,
+) -> Result<HashMap<String, BencodeTypes>, ParseError> {
+    entries
+        .into_iter()
+        .map(|(key, value)| Ok((String::from_utf8(key).map_err(|e| e.utf8_error())?, value)))
+        .collect()
+}
+
+pub fn decode_int(pointer: &mut usize, buf: &[u8]) -> Result<u64, ParseError> {
     let mut int_bytes = Vec::new();
 
     // Place pointer at start of int (after "i")
     *pointer += 1;
 
-    while buf[*pointer] != END_OF_TYPE {
+    while byte_at(*pointer, buf)? != END_OF_TYPE {
         int_bytes.push(buf[*pointer]);
         *pointer += 1;
     }
@@ -85,19 +150,19 @@ pub fn decode_int(pointer: &mut usize, buf: &Vec<u8>) -> Result<u64, ParseError>
     Ok(parse_to_usize(&int_bytes)? as u64)
 }
 
-pub fn decode_list(pointer: &mut usize, buf: &Vec<u8>) -> Result<Vec<BencodeTypes>, ParseError> {
+pub fn decode_list(pointer: &mut usize, buf: &[u8]) -> Result<Vec<BencodeTypes>, ParseError> {
     let mut list: Vec<BencodeTypes> = Vec::new();
 
     // Place pointer at start of list (after "l")
     *pointer += 1;
 
-    while buf[*pointer] != END_OF_TYPE {
+    while byte_at(*pointer, buf)? != END_OF_TYPE {
         list.push(match buf[*pointer] {
-            n if n.is_ascii_digit() => BencodeTypes::String(decode_string(pointer, buf)?),
+            n if n.is_ascii_digit() => decode_string_or_bytes(pointer, buf)?,
             INTEGER_START => BencodeTypes::Integer(decode_int(pointer, buf)?),
             LIST_START => BencodeTypes::List(decode_list(pointer, buf)?),
-            DICTIONARY_START => BencodeTypes::Dict(decode_dict(pointer, buf)?),
-            _ => todo!(),
+            DICTIONARY_START => dict_or_bytes_dict(decode_entries(pointer, buf)?),
+            _ => return Err(ParseError::BadFile),
         })
     }
 
@@ -107,21 +172,10 @@ pub fn decode_list(pointer: &mut usize, buf: &Vec<u8>) -> Result<Vec<BencodeType
     Ok(list)
 }
 
-pub fn decode_pieces(pointer: &mut usize, buf: &Vec<u8>) -> Result<Vec<[u8; 20]>, ParseError> {
-    let pieces_len = get_string_len(pointer, buf)?;
+pub fn decode_pieces(pointer: &mut usize, buf: &[u8]) -> Result<Vec<[u8; 20]>, ParseError> {
+    let pieces_len = get_string_end(pointer, buf)?;
 
-    let pieces_len = pieces_len + *pointer;
-
-    let pieces_vec: Vec<[u8; 20]> = buf[*pointer..pieces_len]
-        .chunks_exact(20)
-        .map(|h| match h.try_into() {
-            Ok(h) => h,
-            Err(e) => {
-                println!("bad pieces array! e: {e}");
-                exit(1);
-            }
-        })
-        .collect();
+    let pieces_vec = split_pieces(slice_of(buf, *pointer, pieces_len)?)?;
 
     // Place pointer at the byte after the string (after the last char)
     *pointer = pieces_len;
@@ -129,25 +183,49 @@ pub fn decode_pieces(pointer: &mut usize, buf: &Vec<u8>) -> Result<Vec<[u8; 20]>
     Ok(pieces_vec)
 }
 
-fn decode_peers(pointer: &mut usize, buf: &Vec<u8>) -> Result<BencodeTypes, ParseError> {
-    if buf[*pointer] == LIST_START {
-        let decoded = decode_list(pointer, buf)?;
-        return Ok(BencodeTypes::List(decoded));
+/// Cuts `pieces` into its SHA-1 hashes, shared with `BencodeReader`.
+/// Trailing bytes that don't make a whole hash are `Malformed`
+pub(crate) fn split_pieces(bytes: &[u8]) -> Result<Vec<[u8; 20]>, ParseError> {
+    if !bytes.len().is_multiple_of(20) {
+        return Err(ParseError::Malformed("pieces"));
     }
 
-    let peers_len = get_string_len(pointer, buf)?;
-
-    let peers_len = peers_len + *pointer;
+    Ok(bytes
+        .chunks_exact(20)
+        .map(|h| h.try_into().expect("chunks are 20 bytes"))
+        .collect())
+}
 
-    let peers_vec = buf[*pointer..peers_len]
-        .chunks_exact(6)
+/// Parses a compact peer list made of `entry_len` sized entries,
+/// either `COMPACT_PEER_V4_LEN` or `COMPACT_PEER_V6_LEN`
+pub fn parse_compact_peers(bytes: &[u8], entry_len: usize) -> Peers {
+    bytes
+        .chunks_exact(entry_len)
         .map(|item| {
-            (
-                (item[0], item[1], item[2], item[3]),
-                u16::from_be_bytes([item[4], item[5]]),
-            )
+            let (ip, port) = item.split_at(entry_len - 2);
+            let port = u16::from_be_bytes([port[0], port[1]]);
+
+            match <[u8; 16]>::try_from(ip) {
+                Ok(octets) => SocketAddr::new(Ipv6Addr::from(octets).into(), port),
+                Err(_) => SocketAddr::new(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).into(), port),
+            }
         })
-        .collect();
+        .collect()
+}
+
+fn decode_peers(
+    pointer: &mut usize,
+    buf: &[u8],
+    entry_len: usize,
+) -> Result<BencodeTypes, ParseError> {
+    if byte_at(*pointer, buf)? == LIST_START {
+        let decoded = decode_list(pointer, buf)?;
+        return Ok(BencodeTypes::List(decoded));
+    }
+
+    let peers_len = get_string_end(pointer, buf)?;
+
+    let peers_vec = parse_compact_peers(slice_of(buf, *pointer, peers_len)?, entry_len);
 
     // Place pointer at the byte after the string (after the last char)
     *pointer = peers_len;
@@ -157,25 +235,36 @@ fn decode_peers(pointer: &mut usize, buf: &Vec<u8>) -> Result<BencodeTypes, Pars
 
 pub fn decode_dict(
     pointer: &mut usize,
-    buf: &Vec<u8>,
+    buf: &[u8],
 ) -> Result<HashMap<String, BencodeTypes>, ParseError> {
-    if buf.len() == 0 {
+    into_string_keys(decode_entries(pointer, buf)?)
+}
+
+/// The key/value pairs of a dict, `info_hash` is added when it has an `info` dict
+fn decode_entries(
+    pointer: &mut usize,
+    buf: &[u8],
+) -> Result<Vec<(Vec<u8>, BencodeTypes)>, ParseError> {
+    if buf.get(*pointer) != Some(&DICTIONARY_START) {
         return Err(ParseError::BadFile);
     }
 
-    let mut dict: HashMap<String, BencodeTypes> = HashMap::new();
+    let mut entries = Vec::new();
 
     // Place pointer at start of dict (after "d")
     *pointer += 1;
 
     let mut is_key = true;
-    let mut temp_key = String::new();
+    let mut temp_key = Vec::new();
     let mut info_hash_start: usize = 0;
+    let mut info_hash_end: usize = 0;
 
-    while buf[*pointer] != END_OF_TYPE && *pointer != buf.len() {
+    while byte_at(*pointer, buf)? != END_OF_TYPE {
         if is_key {
-            temp_key = decode_string(pointer, buf)?;
-            if temp_key == "info" {
+            let key_end = get_string_end(pointer, buf)?;
+            temp_key = slice_of(buf, *pointer, key_end)?.to_vec();
+            *pointer = key_end;
+            if temp_key == b"info" {
                 info_hash_start = *pointer;
             }
             is_key = !is_key;
@@ -183,32 +272,131 @@ pub fn decode_dict(
         }
 
         let parsed = match buf[*pointer] {
-            n if n.is_ascii_digit() && temp_key == "pieces" => {
+            n if n.is_ascii_digit() && temp_key == b"pieces" => {
                 BencodeTypes::Pieces(decode_pieces(pointer, buf)?)
             }
-            n if n.is_ascii_digit() && temp_key == "peers" => decode_peers(pointer, buf)?,
-            n if n.is_ascii_digit() => BencodeTypes::String(decode_string(pointer, buf)?),
+            n if n.is_ascii_digit() && temp_key == b"peers" => {
+                decode_peers(pointer, buf, COMPACT_PEER_V4_LEN)?
+            }
+            n if n.is_ascii_digit() && temp_key == b"peers6" => {
+                decode_peers(pointer, buf, COMPACT_PEER_V6_LEN)?
+            }
+            n if n.is_ascii_digit() => decode_string_or_bytes(pointer, buf)?,
             INTEGER_START => BencodeTypes::Integer(decode_int(pointer, buf)?),
             LIST_START => BencodeTypes::List(decode_list(pointer, buf)?),
-            DICTIONARY_START => BencodeTypes::Dict(decode_dict(pointer, buf)?),
-            _ => todo!(),
+            DICTIONARY_START => dict_or_bytes_dict(decode_entries(pointer, buf)?),
+            _ => return Err(ParseError::BadFile),
         };
 
-        dict.insert(temp_key.clone(), parsed);
+        // Place info_hash_end right after the info dict's matching "e",
+        // before any sibling keys that follow it
+        if temp_key == b"info"
+            && matches!(parsed, BencodeTypes::Dict(_) | BencodeTypes::BytesDict(_))
+        {
+            info_hash_end = *pointer;
+        }
+
+        entries.push((temp_key.clone(), parsed));
         is_key = !is_key;
     }
 
     // info exists in file so we get the info_hash
-    if info_hash_start != 0 {
-        let slice = &buf[info_hash_start..*pointer];
+    if info_hash_end != 0 {
+        let slice = &buf[info_hash_start..info_hash_end];
         let hash = get_hash(slice)?;
-        dict.insert(String::from("info_hash"), BencodeTypes::InfoHash(hash));
+        entries.push((b"info_hash".to_vec(), BencodeTypes::InfoHash(hash)));
     }
 
     // Place pointer at end of type (after "e")
     *pointer += 1;
 
-    Ok(dict)
+    Ok(entries)
+}
+
+/// `decode_dict` that first checks `buf` is canonical bencode, so hashes of it
+/// (e.g. the info_hash) are the ones every other client computes
+pub fn decode_strict(
+    pointer: &mut usize,
+    buf: &[u8],
+) -> Result<HashMap<String, BencodeTypes>, ParseError> {
+    if buf.get(*pointer) != Some(&DICTIONARY_START) {
+        return Err(ParseError::BadFile);
+    }
+
+    let mut end = *pointer;
+    check_canonical(&mut end, buf)?;
+
+    decode_dict(pointer, buf)
+}
+
+/// Walks one value without decoding it, rejecting anything with more than one encoding
+fn check_canonical(pointer: &mut usize, buf: &[u8]) -> Result<(), ParseError> {
+    match byte_at(*pointer, buf)? {
+        n if n.is_ascii_digit() => {
+            check_string(pointer, buf)?;
+        }
+        INTEGER_START => {
+            *pointer += 1;
+            let start = *pointer;
+            while byte_at(*pointer, buf)? != END_OF_TYPE {
+                *pointer += 1;
+            }
+            check_digits(&buf[start..*pointer])?;
+            *pointer += 1;
+        }
+        LIST_START => {
+            *pointer += 1;
+            while byte_at(*pointer, buf)? != END_OF_TYPE {
+                check_canonical(pointer, buf)?;
+            }
+            *pointer += 1;
+        }
+        DICTIONARY_START => {
+            *pointer += 1;
+            let mut last_key: Option<&[u8]> = None;
+            while byte_at(*pointer, buf)? != END_OF_TYPE {
+                let key = check_string(pointer, buf)?;
+                match last_key {
+                    Some(last) if last == key => return Err(ParseError::DuplicateKey),
+                    Some(last) if last > key => return Err(ParseError::UnsortedKeys),
+                    _ => {}
+                }
+                last_key = Some(key);
+                check_canonical(pointer, buf)?;
+            }
+            *pointer += 1;
+        }
+        _ => return Err(ParseError::BadFile),
+    }
+
+    Ok(())
+}
+
+/// Checks the length prefix and returns the string's bytes
+fn check_string<'a>(pointer: &mut usize, buf: &'a [u8]) -> Result<&'a [u8], ParseError> {
+    let start = *pointer;
+    while byte_at(*pointer, buf)? != STRING_DELIM {
+        *pointer += 1;
+    }
+    // `-` is already rejected by `parse_to_usize`
+    let len_digits = &buf[start..*pointer];
+    check_digits(len_digits)?;
+
+    let string_start = *pointer + 1;
+    let string_end = string_start
+        .checked_add(parse_to_usize(len_digits)?)
+        .ok_or(ParseError::BadFile)?;
+    *pointer = string_end;
+
+    slice_of(buf, string_start, string_end)
+}
+
+fn check_digits(digits: &[u8]) -> Result<(), ParseError> {
+    match digits {
+        [b'-', b'0', ..] => Err(ParseError::NegativeZero),
+        [b'0', _, ..] => Err(ParseError::LeadingZero),
+        _ => Ok(()),
+    }
 }
 
 #[cfg(test)]
@@ -231,7 +419,7 @@ mod tests {
         let mut pointer = 0;
         let result = decode_int(&mut pointer, &test_vec).unwrap();
 
-        assert_eq!(5657 as u64, result);
+        assert_eq!(5657_u64, result);
         assert_eq!(pointer, 6);
     }
 
@@ -256,10 +444,10 @@ mod tests {
         assert_eq!(
             vec![
                 BencodeTypes::String(string.clone()),
-                BencodeTypes::Integer(int.clone()),
+                BencodeTypes::Integer(int),
                 BencodeTypes::List(vec![
                     BencodeTypes::String(string.clone()),
-                    BencodeTypes::Integer(int.clone()),
+                    BencodeTypes::Integer(int),
                 ]),
                 BencodeTypes::Dict(dict),
             ],
@@ -293,4 +481,170 @@ mod tests {
         assert_eq!(dict, result);
         assert_eq!(pointer, 44);
     }
+
+    #[test]
+    fn decodes_dicts_with_binary_keys() {
+        let info_hash = [0xff; 20];
+        let mut test_vec = b"d5:filesd20:".to_vec();
+        test_vec.extend_from_slice(&info_hash);
+        test_vec.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+
+        let mut pointer = 0;
+        let result = decode_dict(&mut pointer, &test_vec).unwrap();
+
+        let stats = HashMap::from([
+            (String::from("complete"), BencodeTypes::Integer(5)),
+            (String::from("downloaded"), BencodeTypes::Integer(50)),
+            (String::from("incomplete"), BencodeTypes::Integer(10)),
+        ]);
+
+        assert_eq!(
+            result,
+            HashMap::from([(
+                String::from("files"),
+                BencodeTypes::BytesDict(HashMap::from([(
+                    info_hash.to_vec(),
+                    BencodeTypes::Dict(stats)
+                )]))
+            )])
+        );
+        assert_eq!(pointer, test_vec.len());
+    }
+
+    #[test]
+    fn info_hash_ignores_keys_after_info() {
+        let info = b"d6:lengthi42e4:name4:teste";
+        let mut test_vec = b"d8:announce4:test4:info".to_vec();
+        test_vec.extend_from_slice(info);
+        test_vec.extend_from_slice(b"7:comment5:helloe");
+
+        let mut pointer = 0;
+        let mut result = decode_dict(&mut pointer, &test_vec).unwrap();
+
+        assert_eq!(
+            result.remove("info_hash"),
+            Some(BencodeTypes::InfoHash(get_hash(info).unwrap()))
+        );
+        assert_eq!(pointer, test_vec.len());
+    }
+
+    #[test]
+    fn decodes_compact_v4_and_v6_peers() {
+        let mut test_vec = b"d5:peers6:".to_vec();
+        test_vec.extend_from_slice(&[127, 0, 0, 1, 0x1A, 0xE1]);
+        test_vec.extend_from_slice(b"6:peers618:");
+        test_vec.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        test_vec.extend_from_slice(&[0x1A, 0xE1]);
+        test_vec.push(b'e');
+
+        let mut pointer = 0;
+        let mut dict = decode_dict(&mut pointer, &test_vec).unwrap();
+
+        assert_eq!(
+            dict.remove("peers"),
+            Some(BencodeTypes::PeersCompact(vec!["127.0.0.1:6881"
+                .parse()
+                .unwrap()]))
+        );
+        assert_eq!(
+            dict.remove("peers6"),
+            Some(BencodeTypes::PeersCompact(vec!["[::1]:6881"
+                .parse()
+                .unwrap()]))
+        );
+    }
+
+    #[test]
+    fn keeps_non_utf8_strings_as_bytes() {
+        let mut test_vec = b"d2:id3:".to_vec();
+        test_vec.extend_from_slice(&[0xff, 0x00, 0xfe]);
+        test_vec.extend_from_slice(b"6:valuesl2:");
+        test_vec.extend_from_slice(&[0xc3, 0x28]);
+        test_vec.extend_from_slice(b"2:okee");
+
+        let mut dict = decode_dict(&mut 0, &test_vec).unwrap();
+
+        assert_eq!(
+            dict.remove("id"),
+            Some(BencodeTypes::Bytes(vec![0xff, 0x00, 0xfe]))
+        );
+        assert_eq!(
+            dict.remove("values"),
+            Some(BencodeTypes::List(vec![
+                BencodeTypes::Bytes(vec![0xc3, 0x28]),
+                BencodeTypes::String(String::from("ok")),
+            ]))
+        );
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        for test_vec in [
+            &b"d2:id"[..],
+            b"d2:id20:short",
+            b"d1:ai42",
+            b"d1:al1:x",
+            b"d1:a",
+            b"d1:a18446744073709551615:x",
+            b"d6:pieces18446744073709551615:x",
+            b"d5:peers18446744073709551615:x",
+        ] {
+            assert!(matches!(
+                decode_dict(&mut 0, test_vec),
+                Err(ParseError::BadFile)
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_pieces_that_are_not_whole_hashes() {
+        let mut test_vec = b"d6:pieces21:".to_vec();
+        test_vec.extend([0u8; 21]);
+        test_vec.push(b'e');
+
+        assert!(matches!(
+            decode_dict(&mut 0, &test_vec),
+            Err(ParseError::Malformed("pieces"))
+        ));
+    }
+
+    #[test]
+    fn strict_accepts_canonical_bencode() {
+        let buf = b"d1:ai0e1:bl3:fooi5ee1:cd1:xi10eee";
+        let mut pointer = 0;
+
+        assert_eq!(
+            decode_strict(&mut pointer, buf).unwrap(),
+            decode_dict(&mut 0, buf).unwrap()
+        );
+        assert_eq!(pointer, buf.len());
+    }
+
+    #[test]
+    fn strict_rejects_non_canonical_bencode() {
+        let cases: [(&[u8], &str); 5] = [
+            (b"d1:ai05ee", "LeadingZero"),
+            (b"d1:a02:hie", "LeadingZero"),
+            (b"d1:ai-0ee", "NegativeZero"),
+            (b"d1:bi1e1:ai2ee", "UnsortedKeys"),
+            (b"d1:ad1:ai1e1:ai2eee", "DuplicateKey"),
+        ];
+
+        for (buf, expected) in cases {
+            let error = decode_strict(&mut 0, buf).unwrap_err();
+            assert_eq!(format!("{error:?}"), expected);
+            // The lenient decoder still takes them, except `-0` which isn't a u64
+            if !matches!(error, ParseError::NegativeZero) {
+                assert!(decode_dict(&mut 0, buf).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn strict_rejects_overflowing_string_lengths() {
+        assert!(matches!(
+            decode_strict(&mut 0, b"d18446744073709551615:ae"),
+            Err(ParseError::BadFile)
+        ));
+    }
 }
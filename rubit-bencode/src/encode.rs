@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::decode::BencodeTypes;
+
+/// Canonical bencoding of `value`: dict keys sorted by their raw bytes, as BEP 3
+/// requires, so re-encoding a decoded info dict gives back the same info hash
+pub fn encode(value: &BencodeTypes) -> Vec<u8> {
+    let mut buf = Vec::new();
+    encode_into(&mut buf, value);
+    buf
+}
+
+fn encode_into(buf: &mut Vec<u8>, value: &BencodeTypes) {
+    match value {
+        BencodeTypes::String(s) => encode_bytes(buf, s.as_bytes()),
+        BencodeTypes::Integer(i) => buf.extend_from_slice(format!("i{}e", i).as_bytes()),
+        BencodeTypes::List(list) => {
+            buf.push(b'l');
+            for item in list {
+                encode_into(buf, item);
+            }
+            buf.push(b'e');
+        }
+        BencodeTypes::Dict(dict) => encode_dict(buf, dict),
+        BencodeTypes::InfoHash(hash) => encode_bytes(buf, hash),
+        BencodeTypes::Bytes(bytes) => encode_bytes(buf, bytes),
+        BencodeTypes::Pieces(pieces) => encode_bytes(buf, pieces.concat().as_slice()),
+        BencodeTypes::PeersCompact(peers) => {
+            let compact: Vec<u8> = peers.iter().flat_map(compact_peer).collect();
+            encode_bytes(buf, &compact);
+        }
+        BencodeTypes::BytesDict(dict) => encode_dict(buf, dict),
+    }
+}
+
+fn encode_dict<K: AsRef<[u8]>>(buf: &mut Vec<u8>, dict: &HashMap<K, BencodeTypes>) {
+    let mut entries: Vec<_> = dict.iter().collect();
+    entries.sort_unstable_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+
+    buf.push(b'd');
+    for (key, value) in entries {
+        encode_bytes(buf, key.as_ref());
+        encode_into(buf, value);
+    }
+    buf.push(b'e');
+}
+
+fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn compact_peer(peer: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match peer.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&peer.port().to_be_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_dict, get_hash};
+
+    #[test]
+    fn sorts_dict_keys_by_raw_bytes() {
+        let dict = BencodeTypes::Dict(HashMap::from([
+            (String::from("b"), BencodeTypes::Integer(2)),
+            (String::from("a"), BencodeTypes::Integer(1)),
+            // uppercase sorts before lowercase, multi-byte utf-8 after ascii
+            (String::from("Z"), BencodeTypes::Integer(0)),
+            (String::from("é"), BencodeTypes::Integer(4)),
+            (String::from("ab"), BencodeTypes::Integer(3)),
+        ]));
+
+        assert_eq!(
+            encode(&dict),
+            "d1:Zi0e1:ai1e2:abi3e1:bi2e2:éi4ee".as_bytes()
+        );
+    }
+
+    /// Info dicts shaped like the ones common torrent creators emit
+    fn info_dicts() -> Vec<Vec<u8>> {
+        let pieces = |count: u8| {
+            let mut buf = format!("6:pieces{}:", count as usize * 20).into_bytes();
+            buf.extend((0..count as usize * 20).map(|i| (i * 7) as u8));
+            buf
+        };
+
+        let mut single = b"d6:lengthi1048576e4:name8:test.bin12:piece lengthi262144e".to_vec();
+        single.extend(pieces(4));
+        single.push(b'e');
+
+        let mut private = b"d6:lengthi40e4:name9:linux.iso12:piece lengthi16e".to_vec();
+        private.extend(pieces(3));
+        private.extend_from_slice(b"7:privatei1e6:source3:TRKe");
+
+        let mut multi =
+            b"d5:filesld6:lengthi10e4:pathl3:dir5:a.txteed6:lengthi22e4:pathl5:b.txteee".to_vec();
+        multi.extend_from_slice(b"4:name5:album12:piece lengthi16e");
+        multi.extend(pieces(2));
+        multi.push(b'e');
+
+        vec![single, private, multi]
+    }
+
+    #[test]
+    fn re_encoded_info_dicts_keep_their_info_hash() {
+        for info in info_dicts() {
+            let mut torrent = b"d8:announce31:http://tracker.example/announce4:info".to_vec();
+            torrent.extend_from_slice(&info);
+            torrent.push(b'e');
+
+            let mut dict = decode_dict(&mut 0, &torrent).unwrap();
+            let Some(BencodeTypes::InfoHash(info_hash)) = dict.remove("info_hash") else {
+                panic!("no info hash");
+            };
+            let re_encoded = encode(&dict.remove("info").unwrap());
+
+            assert_eq!(re_encoded, info);
+            assert_eq!(get_hash(&re_encoded).unwrap(), info_hash);
+        }
+    }
+}
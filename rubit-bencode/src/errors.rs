@@ -1,10 +1,29 @@
-use std::{num::ParseIntError, str::Utf8Error};
+use std::{io, num::ParseIntError, str::Utf8Error};
 
 #[derive(Debug)]
 pub enum ParseError {
     Int(ParseIntError),
     Str(Utf8Error),
     BadFile,
+    /// A required key is missing, e.g. `length` in a multi-file torrent
+    MissingField,
+    /// A required key is there but wrongly typed or unusable, like a 0 `piece length`
+    Malformed(&'static str),
+    /// `pieces` doesn't have one hash per `piece length` of `length`
+    PieceCountMismatch {
+        expected: u64,
+        found: usize,
+    },
+    /// The reader ended in the middle of a value
+    UnexpectedEof,
+    /// Non-canonical input rejected by `decode_strict`: `i05e`, `05:hello`
+    LeadingZero,
+    /// `i-0e`
+    NegativeZero,
+    /// Dict keys not sorted by their raw bytes
+    UnsortedKeys,
+    DuplicateKey,
+    Io(io::Error),
 }
 
 impl From<Utf8Error> for ParseError {
@@ -18,3 +37,12 @@ impl From<ParseIntError> for ParseError {
         ParseError::Int(error)
     }
 }
+
+impl From<io::Error> for ParseError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => ParseError::UnexpectedEof,
+            _ => ParseError::Io(error),
+        }
+    }
+}
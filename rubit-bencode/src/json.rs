@@ -0,0 +1,150 @@
+use std::fmt::Write;
+
+use crate::{decode::BencodeTypes, util::to_hex};
+
+/// JSON rendering of `value` for inspecting decoded data. Dict keys are sorted,
+/// `pieces` and info hashes become hex strings, compact peers `ip:port` strings
+/// and non utf-8 `Bytes` lossy strings, so it can't be turned back into bencode
+pub fn to_json(value: &BencodeTypes) -> String {
+    let mut json = String::new();
+    write_json(&mut json, value);
+    json
+}
+
+/// `s` as a quoted JSON string, e.g. for front-ends printing their own JSON
+pub fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    write_string(&mut json, s);
+    json
+}
+
+fn write_json(json: &mut String, value: &BencodeTypes) {
+    match value {
+        BencodeTypes::String(s) => write_string(json, s),
+        BencodeTypes::Integer(i) => write!(json, "{i}").unwrap(),
+        BencodeTypes::List(list) => write_list(json, list.iter(), write_json),
+        BencodeTypes::Dict(dict) => {
+            let mut entries: Vec<_> = dict.iter().collect();
+            entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+            write_object(json, entries.into_iter(), write_json);
+        }
+        BencodeTypes::InfoHash(hash) => write_string(json, &to_hex(hash)),
+        BencodeTypes::Pieces(pieces) => {
+            write_list(json, pieces.iter(), |json, piece| {
+                write_string(json, &to_hex(piece))
+            });
+        }
+        BencodeTypes::PeersCompact(peers) => {
+            write_list(json, peers.iter(), |json, peer| {
+                write_string(json, &peer.to_string())
+            });
+        }
+        BencodeTypes::Bytes(bytes) => write_string(json, &String::from_utf8_lossy(bytes)),
+        BencodeTypes::BytesDict(dict) => {
+            let mut entries: Vec<_> = dict.iter().map(|(k, v)| (to_hex(k), v)).collect();
+            entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            write_object(json, entries.into_iter(), write_json);
+        }
+    }
+}
+
+fn write_list<'a, T: 'a>(
+    json: &mut String,
+    items: impl Iterator<Item = &'a T>,
+    write_item: impl Fn(&mut String, &T),
+) {
+    json.push('[');
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write_item(json, item);
+    }
+    json.push(']');
+}
+
+fn write_object<'a, K: AsRef<str> + 'a, V: 'a>(
+    json: &mut String,
+    entries: impl Iterator<Item = (K, &'a V)>,
+    write_value: impl Fn(&mut String, &V),
+) {
+    json.push('{');
+    for (i, (key, value)) in entries.enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write_string(json, key.as_ref());
+        json.push(':');
+        write_value(json, value);
+    }
+    json.push('}');
+}
+
+fn write_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::decode_dict;
+
+    fn get_info_hash(dict: &HashMap<String, BencodeTypes>) -> [u8; 20] {
+        match dict["info_hash"] {
+            BencodeTypes::InfoHash(hash) => hash,
+            _ => panic!("no info hash"),
+        }
+    }
+
+    #[test]
+    fn renders_a_torrent_compactly() {
+        let mut torrent = b"d7:comment6:a \"b\"\n4:infod6:lengthi3e6:pieces20:".to_vec();
+        torrent.extend_from_slice(&[0xab; 20]);
+        torrent.extend_from_slice(b"e5:peers6:");
+        torrent.extend_from_slice(&[127, 0, 0, 1, 0x1A, 0xE1]);
+        torrent.extend_from_slice(b"4:tags2:");
+        torrent.extend_from_slice(&[0xff, b'x']);
+        torrent.push(b'e');
+        let dict = decode_dict(&mut 0, &torrent).unwrap();
+        let info_hash = to_hex(&get_info_hash(&dict));
+
+        assert_eq!(
+            to_json(&BencodeTypes::Dict(dict)),
+            format!(
+                "{{\"comment\":\"a \\\"b\\\"\\n\",\"info\":{{\"length\":3,\"pieces\":[\"{}\"]}},\
+                 \"info_hash\":\"{info_hash}\",\"peers\":[\"127.0.0.1:6881\"],\"tags\":\"\u{fffd}x\"}}",
+                "ab".repeat(20)
+            )
+        );
+    }
+
+    #[test]
+    fn renders_lists_and_empty_containers() {
+        let value = BencodeTypes::List(vec![
+            BencodeTypes::Integer(1),
+            BencodeTypes::List(Vec::new()),
+            BencodeTypes::Dict(Default::default()),
+        ]);
+
+        assert_eq!(to_json(&value), "[1,[],{}]");
+    }
+
+    #[test]
+    fn quotes_strings() {
+        assert_eq!(json_string("a\"b\\\u{1}\t"), "\"a\\\"b\\\\\\u0001\\t\"");
+    }
+}
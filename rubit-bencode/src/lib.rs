@@ -1,9 +1,19 @@
+#[cfg(feature = "serde")]
+mod de;
 mod decode;
+mod encode;
 mod errors;
+mod json;
+mod reader;
 mod torrent_file;
 mod util;
 
+#[cfg(feature = "serde")]
+pub use de::*;
 pub use decode::*;
+pub use encode::*;
 pub use errors::*;
+pub use json::*;
+pub use reader::*;
 pub use torrent_file::*;
 pub use util::*;
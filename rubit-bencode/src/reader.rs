@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    decode::{dict_or_bytes_dict, into_string_keys, parse_to_usize, split_pieces, string_or_bytes},
+    parse_compact_peers, BencodeTypes, ParseError, COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN,
+};
+
+/// Decodes bencode straight from a reader, pulling bytes as they are needed instead of
+/// buffering the whole input. Same grammar and special keys as `decode_dict`,
+/// reads byte by byte so wrap sockets and files in a `BufReader`
+pub struct BencodeReader<R: Read> {
+    reader: R,
+    peeked: Option<u8>,
+    /// Hashes the bytes of the `info` dict while it's read
+    info_hasher: Option<Sha1>,
+}
+
+impl<R: Read> BencodeReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            peeked: None,
+            info_hasher: None,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Next value, `None` if the reader ended cleanly before it
+    pub fn read_value(&mut self) -> Result<Option<BencodeTypes>, ParseError> {
+        if self.peek_opt()?.is_none() {
+            return Ok(None);
+        }
+        self.value(b"").map(Some)
+    }
+
+    /// Expects a dict, `info_hash` is added when an `info` dict was read
+    pub fn read_dict(&mut self) -> Result<HashMap<String, BencodeTypes>, ParseError> {
+        if self.peek()? != b'd' {
+            return Err(ParseError::BadFile);
+        }
+        into_string_keys(self.entries()?)
+    }
+
+    /// Skips a UTF-8 BOM and whitespace before the next value,
+    /// like `TorrentFile::parse` does for text editors that save them
+    pub fn skip_leading_junk(&mut self) -> Result<(), ParseError> {
+        if self.peek_opt()? == Some(0xEF) {
+            for expected in [0xEF, 0xBB, 0xBF] {
+                if self.next_byte()? != expected {
+                    return Err(ParseError::BadFile);
+                }
+            }
+        }
+        while self.peek_opt()?.is_some_and(|b| b.is_ascii_whitespace()) {
+            self.next_byte()?;
+        }
+        Ok(())
+    }
+
+    fn peek_opt(&mut self) -> Result<Option<u8>, ParseError> {
+        if self.peeked.is_none() {
+            let mut byte = [0u8; 1];
+            loop {
+                match self.reader.read(&mut byte) {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => break,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            self.peeked = Some(byte[0]);
+        }
+        Ok(self.peeked)
+    }
+
+    fn peek(&mut self) -> Result<u8, ParseError> {
+        self.peek_opt()?.ok_or(ParseError::UnexpectedEof)
+    }
+
+    fn next_byte(&mut self) -> Result<u8, ParseError> {
+        let byte = self.peek()?;
+        self.peeked = None;
+        if let Some(hasher) = &mut self.info_hasher {
+            hasher.update([byte]);
+        }
+        Ok(byte)
+    }
+
+    /// Bytes up to `delim`, which is consumed
+    fn read_until(&mut self, delim: u8) -> Result<Vec<u8>, ParseError> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.next_byte()? {
+                b if b == delim => return Ok(bytes),
+                b => bytes.push(b),
+            }
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, ParseError> {
+        let len = parse_to_usize(&self.read_until(b':')?)?;
+
+        // `take` grows the buffer as data arrives, a bogus length can't allocate it upfront
+        let mut bytes = Vec::new();
+        (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(ParseError::UnexpectedEof);
+        }
+
+        if let Some(hasher) = &mut self.info_hasher {
+            hasher.update(&bytes);
+        }
+        Ok(bytes)
+    }
+
+    fn int(&mut self) -> Result<u64, ParseError> {
+        // 'i'
+        self.next_byte()?;
+        Ok(parse_to_usize(&self.read_until(b'e')?)? as u64)
+    }
+
+    fn list(&mut self) -> Result<Vec<BencodeTypes>, ParseError> {
+        // 'l'
+        self.next_byte()?;
+        let mut list = Vec::new();
+        while self.peek()? != b'e' {
+            list.push(self.value(b"")?);
+        }
+        self.next_byte()?;
+        Ok(list)
+    }
+
+    fn entries(&mut self) -> Result<Vec<(Vec<u8>, BencodeTypes)>, ParseError> {
+        // 'd'
+        self.next_byte()?;
+        let mut entries = Vec::new();
+        let mut info_hash = None;
+
+        while self.peek()? != b'e' {
+            let key = self.bytes()?;
+
+            let hash_info = key == b"info" && self.info_hasher.is_none() && self.peek()? == b'd';
+            if hash_info {
+                self.info_hasher = Some(Sha1::new());
+            }
+            let value = self.value(&key)?;
+            if hash_info {
+                info_hash = self.info_hasher.take().map(|h| h.finalize().into());
+            }
+
+            entries.push((key, value));
+        }
+        self.next_byte()?;
+
+        if let Some(hash) = info_hash {
+            entries.push((b"info_hash".to_vec(), BencodeTypes::InfoHash(hash)));
+        }
+        Ok(entries)
+    }
+
+    /// `key` is the dict key the value belongs to, for the same special cases as `decode_dict`
+    fn value(&mut self, key: &[u8]) -> Result<BencodeTypes, ParseError> {
+        Ok(match self.peek()? {
+            n if n.is_ascii_digit() => {
+                let bytes = self.bytes()?;
+                match key {
+                    b"pieces" => BencodeTypes::Pieces(split_pieces(&bytes)?),
+                    b"peers" => {
+                        BencodeTypes::PeersCompact(parse_compact_peers(&bytes, COMPACT_PEER_V4_LEN))
+                    }
+                    b"peers6" => {
+                        BencodeTypes::PeersCompact(parse_compact_peers(&bytes, COMPACT_PEER_V6_LEN))
+                    }
+                    _ => string_or_bytes(bytes),
+                }
+            }
+            b'i' => BencodeTypes::Integer(self.int()?),
+            b'l' => BencodeTypes::List(self.list()?),
+            b'd' => dict_or_bytes_dict(self.entries()?),
+            _ => return Err(ParseError::BadFile),
+        })
+    }
+}
+
+/// Every top-level value until the reader ends
+impl<R: Read> Iterator for BencodeReader<R> {
+    type Item = Result<BencodeTypes, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_value().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_dict;
+
+    /// Hands out one byte per read like a slow socket
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn decodes_like_decode_dict() {
+        let mut torrent = b"d8:announce31:http://tracker.example/announce4:info".to_vec();
+        torrent.extend_from_slice(b"d6:lengthi32e4:name8:test.bin12:piece lengthi16e6:pieces40:");
+        torrent.extend((0..40).map(|i| i as u8 * 5));
+        torrent.extend_from_slice(b"e5:peers6:");
+        torrent.extend_from_slice(&[127, 0, 0, 1, 0x1A, 0xE1]);
+        torrent.extend_from_slice(b"2:id2:");
+        torrent.extend_from_slice(&[0xff, 0xfe]);
+        torrent.push(b'e');
+
+        let streamed = BencodeReader::new(Trickle(&torrent)).read_dict().unwrap();
+
+        assert_eq!(streamed, decode_dict(&mut 0, &torrent).unwrap());
+        assert!(streamed.contains_key("info_hash"));
+    }
+
+    #[test]
+    fn yields_values_until_the_end() {
+        let values: Vec<_> = BencodeReader::new(&b"i1e4:spaml1:aed1:bi2ee"[..])
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(
+            values,
+            vec![
+                BencodeTypes::Integer(1),
+                BencodeTypes::String(String::from("spam")),
+                BencodeTypes::List(vec![BencodeTypes::String(String::from("a"))]),
+                BencodeTypes::Dict(HashMap::from([(
+                    String::from("b"),
+                    BencodeTypes::Integer(2)
+                )])),
+            ]
+        );
+    }
+
+    #[test]
+    fn eof_mid_value_is_an_error() {
+        for input in [&b"d3:foo"[..], b"l1:a", b"10:short", b"i42", b"d4:info"] {
+            assert!(matches!(
+                BencodeReader::new(input).read_value(),
+                Err(ParseError::UnexpectedEof)
+            ));
+        }
+    }
+}
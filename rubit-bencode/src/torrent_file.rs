@@ -1,17 +1,49 @@
 use core::fmt;
-use std::{collections::HashMap, process::exit};
+use std::{collections::HashMap, io::Read};
 
 use crate::{
     decode::{decode_dict, BencodeTypes},
-    unwrap_announce_list, unwrap_dict, unwrap_info_hash, unwrap_integer, unwrap_pieces,
-    unwrap_string,
+    errors::ParseError,
+    reader::BencodeReader,
+    to_hex, unwrap_announce_list, unwrap_dict, unwrap_files, unwrap_info_hash, unwrap_integer,
+    unwrap_pieces, unwrap_string,
 };
 
 pub struct Info {
+    /// The file of a single-file torrent, the directory of a multi-file one
     pub name: String,
+    /// For multi-file torrents the length of all `files` together
     pub length: u64,
     pub piece_length: u64,
     pub pieces: Vec<[u8; 20]>,
+    /// BEP 27 private flag, `Some(true)` when set to 1
+    pub private: Option<bool>,
+    /// Tag some private trackers add so cross-seeded torrents get their own info hash
+    pub source: Option<String>,
+    /// Files of a multi-file torrent in the order their data follows each other,
+    /// `None` for single-file torrents
+    pub files: Option<Vec<FileEntry>>,
+}
+
+/// One file of a multi-file torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Path components below the torrent's directory, e.g. `["Season 1", "E01.mkv"]`
+    pub path: Vec<String>,
+    pub length: u64,
+}
+
+/// Why two torrents have a different info hash
+#[derive(Debug, PartialEq, Eq)]
+pub enum ContentDiff {
+    Private,
+    Source,
+    PieceLength,
+    Name,
+    Length,
+    Files,
+    /// Same layout but the data itself differs
+    Pieces,
 }
 
 pub struct TorrentFile {
@@ -21,86 +53,298 @@ pub struct TorrentFile {
     pub created_by: Option<String>,
     pub creation_date: Option<u64>,
     pub encoding: Option<String>,
+    pub comment: Option<String>,
+    /// Top-level `source`, informational only. The one private trackers use to change
+    /// the info hash lives in the info dict, see `Info::source`
+    pub source: Option<String>,
     pub info: Info,
+    /// Optional keys that were present but wrongly typed and so left as None,
+    /// info dict keys prefixed with `info.`
+    pub malformed: Vec<String>,
+}
+
+/// `make_torrent_file`, also rejecting torrents whose piece hashes don't cover `length`
+/// so piece indices can't point past `pieces` later
+fn into_torrent_file(mut dict: HashMap<String, BencodeTypes>) -> Result<TorrentFile, ParseError> {
+    let torrent_file = make_torrent_file(&mut dict)?;
+
+    let info = &torrent_file.info;
+    if info.piece_length == 0 {
+        return Err(ParseError::Malformed("piece length"));
+    }
+    let expected = info.length.div_ceil(info.piece_length);
+    if expected != info.pieces.len() as u64 {
+        return Err(ParseError::PieceCountMismatch {
+            expected,
+            found: info.pieces.len(),
+        });
+    }
+
+    Ok(torrent_file)
 }
 
-fn make_torrent_file<'a>(dict: &'a mut HashMap<String, BencodeTypes>) -> Option<TorrentFile> {
-    let info_hash = unwrap_info_hash(dict.remove("info_hash")?)?;
-    let announce = unwrap_string(dict.remove("announce")?)?;
-    let mut info_dict = unwrap_dict(dict.remove("info")?)?;
+fn make_torrent_file(dict: &mut HashMap<String, BencodeTypes>) -> Result<TorrentFile, ParseError> {
+    let info_hash = required(dict, "info_hash", unwrap_info_hash)?;
+    let announce = required(dict, "announce", unwrap_string)?;
+    let mut info_dict = required(dict, "info", unwrap_dict)?;
+
+    let name = required(&mut info_dict, "name", unwrap_string)?;
+    // Multi-file torrents list their files instead of having a `length`
+    let files = if info_dict.contains_key("files") {
+        Some(required(&mut info_dict, "files", unwrap_files)?)
+    } else {
+        None
+    };
+    let length = match &files {
+        Some(files) => files
+            .iter()
+            .try_fold(0u64, |sum, file| sum.checked_add(file.length))
+            .ok_or(ParseError::Malformed("files"))?,
+        None => required(&mut info_dict, "length", unwrap_integer)?,
+    };
+    let piece_length = required(&mut info_dict, "piece length", unwrap_integer)?;
+    let pieces = required(&mut info_dict, "pieces", unwrap_pieces)?;
 
-    let name = unwrap_string(info_dict.remove("name")?)?;
-    let length = unwrap_integer(info_dict.remove("length")?)?;
-    let piece_length = unwrap_integer(info_dict.remove("piece length")?)?;
-    let pieces = unwrap_pieces(info_dict.remove("pieces")?)?;
+    // Optional fields: missing or wrongly typed values become None
+    // without failing the whole torrent, the wrongly typed ones are listed in `malformed`
+    let mut info_malformed = Vec::new();
+    let private = optional(
+        &mut info_dict,
+        "private",
+        unwrap_integer,
+        &mut info_malformed,
+    )
+    .map(|i| i == 1);
+    let source = optional(&mut info_dict, "source", unwrap_string, &mut info_malformed);
 
     let info = Info {
         name,
         length,
         piece_length,
         pieces,
+        private,
+        source,
+        files,
     };
 
-    let announce_list = match dict.remove("announce-list") {
-        Some(l) => unwrap_announce_list(l),
-        None => None,
-    };
-
-    let created_by = match dict.remove("created by") {
-        Some(s) => unwrap_string(s),
-        None => None,
-    };
-
-    let creation_date = match dict.remove("creation date") {
-        Some(i) => unwrap_integer(i),
-        None => None,
-    };
-
-    let encoding = match dict.remove("encoding") {
-        Some(s) => unwrap_string(s),
-        None => None,
-    };
+    let mut malformed: Vec<String> = info_malformed
+        .into_iter()
+        .map(|key| format!("info.{key}"))
+        .collect();
+    let announce_list = optional(dict, "announce-list", unwrap_announce_list, &mut malformed);
+    let created_by = optional(dict, "created by", unwrap_string, &mut malformed);
+    let creation_date = optional(dict, "creation date", unwrap_integer, &mut malformed);
+    let encoding = optional(dict, "encoding", unwrap_string, &mut malformed);
+    let comment = optional(dict, "comment", unwrap_string, &mut malformed);
+    let source = optional(dict, "source", unwrap_string, &mut malformed);
 
-    Some(TorrentFile {
+    Ok(TorrentFile {
         info_hash,
         announce,
         announce_list,
         created_by,
         creation_date,
         encoding,
+        comment,
+        source,
         info,
+        malformed,
     })
 }
 
-impl From<Vec<u8>> for TorrentFile {
-    fn from(buf: Vec<u8>) -> Self {
-        let mut pointer = 0;
-        let mut dict = match decode_dict(&mut pointer, &buf) {
-            Ok(d) => d,
-            Err(e) => {
-                println!("bad torrent file!: {e:?}");
-                exit(1);
+/// `MissingField` if `key` isn't there, `Malformed` if it has the wrong type
+fn required<T>(
+    dict: &mut HashMap<String, BencodeTypes>,
+    key: &'static str,
+    unwrap: fn(BencodeTypes) -> Option<T>,
+) -> Result<T, ParseError> {
+    let value = dict.remove(key).ok_or(ParseError::MissingField)?;
+    unwrap(value).ok_or(ParseError::Malformed(key))
+}
+
+/// None if `key` isn't there or has the wrong type, the latter is added to `malformed`
+fn optional<T>(
+    dict: &mut HashMap<String, BencodeTypes>,
+    key: &str,
+    unwrap: fn(BencodeTypes) -> Option<T>,
+    malformed: &mut Vec<String>,
+) -> Option<T> {
+    let value = unwrap(dict.remove(key)?);
+    if value.is_none() {
+        malformed.push(key.to_string());
+    }
+    value
+}
+
+impl TorrentFile {
+    /// Bounds-checked access to the hash of the piece at `index`
+    pub fn piece_hash(&self, index: usize) -> Option<&[u8; 20]> {
+        self.info.pieces.get(index)
+    }
+
+    /// Lowercase hex form of the info hash, as shown by most clients
+    pub fn info_hash_hex(&self) -> String {
+        to_hex(&self.info_hash)
+    }
+
+    /// RFC 4648 base32 form of the info hash (uppercase, 32 chars), the older
+    /// `xt=urn:btih:` form some magnet links use
+    pub fn info_hash_base32(&self) -> String {
+        const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+        // 160 bits split evenly into 32 groups of 5, so no padding is needed
+        let mut base32 = String::with_capacity(32);
+        let mut bits = 0u16;
+        let mut bit_count = 0;
+        for byte in self.info_hash {
+            bits = (bits << 8) | byte as u16;
+            bit_count += 8;
+            while bit_count >= 5 {
+                bit_count -= 5;
+                base32.push(ALPHABET[(bits >> bit_count) as usize & 0x1f] as char);
             }
-        };
+        }
+        base32
+    }
+
+    /// `magnet:?xt=urn:btih:<hex>&dn=<name>` with a `tr` for every tracker, `announce`
+    /// first and then the announce list in tier order without duplicates
+    pub fn to_magnet(&self) -> String {
+        let mut magnet = format!(
+            "magnet:?xt=urn:btih:{}&dn={}",
+            self.info_hash_hex(),
+            percent_encode(&self.info.name)
+        );
 
-        match make_torrent_file(&mut dict) {
-            Some(t) => t,
-            None => {
-                println!("Bad torrent file!");
-                println!("Is it a single-file torrent?");
-                exit(1);
+        let mut trackers: Vec<&str> = Vec::new();
+        let tiers = self.announce_list.iter().flatten().flatten();
+        for tracker in std::iter::once(&self.announce).chain(tiers) {
+            if !tracker.is_empty() && !trackers.contains(&tracker.as_str()) {
+                trackers.push(tracker);
             }
         }
+        for tracker in trackers {
+            magnet.push_str("&tr=");
+            magnet.push_str(&percent_encode(tracker));
+        }
+
+        magnet
+    }
+
+    /// Compares against a hex info hash, ignoring case and surrounding whitespace
+    pub fn matches_info_hash(&self, hex: &str) -> bool {
+        self.info_hash_hex().eq_ignore_ascii_case(hex.trim())
+    }
+
+    /// BEP 27: private torrents only get peers from their trackers, no DHT or PEX.
+    /// A missing flag and `private=0` both mean public
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(true)
+    }
+
+    /// Every info field that differs from `other`, empty if both describe the same info dict.
+    /// Only `Pieces` means the content itself differs, the rest can still be cross-seeded
+    pub fn content_diff(&self, other: &TorrentFile) -> Vec<ContentDiff> {
+        let (a, b) = (&self.info, &other.info);
+        let mut diff = Vec::new();
+
+        if a.private != b.private {
+            diff.push(ContentDiff::Private);
+        }
+        if a.source != b.source {
+            diff.push(ContentDiff::Source);
+        }
+        if a.piece_length != b.piece_length {
+            diff.push(ContentDiff::PieceLength);
+        }
+        if a.name != b.name {
+            diff.push(ContentDiff::Name);
+        }
+        if a.length != b.length {
+            diff.push(ContentDiff::Length);
+        }
+        if a.files != b.files {
+            diff.push(ContentDiff::Files);
+        }
+        // Pieces can only be compared when they cut the data the same way
+        if a.piece_length == b.piece_length && a.length == b.length && a.pieces != b.pieces {
+            diff.push(ContentDiff::Pieces);
+        }
+
+        diff
+    }
+}
+
+impl TorrentFile {
+    /// Decodes straight from a borrowed buffer, e.g. a memory-mapped file
+    pub fn parse(buf: &[u8]) -> Result<Self, ParseError> {
+        let buf = skip_leading_junk(buf);
+        let mut pointer = 0;
+        let dict = decode_dict(&mut pointer, buf)?;
+
+        into_torrent_file(dict)
+    }
+
+    /// Like `parse` but decodes straight from `reader` with a `BencodeReader`
+    pub fn from_reader(reader: impl Read) -> Result<Self, ParseError> {
+        let mut reader = BencodeReader::new(reader);
+        reader.skip_leading_junk()?;
+        let dict = reader.read_dict()?;
+
+        into_torrent_file(dict)
+    }
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl TryFrom<Vec<u8>> for TorrentFile {
+    type Error = ParseError;
+
+    fn try_from(buf: Vec<u8>) -> Result<Self, Self::Error> {
+        Self::parse(&buf)
+    }
+}
+
+impl TryFrom<&[u8]> for TorrentFile {
+    type Error = ParseError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        Self::parse(buf)
     }
 }
 
+/// Text editors sometimes save a UTF-8 BOM or whitespace before the dict
+fn skip_leading_junk(buf: &[u8]) -> &[u8] {
+    let buf = buf.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(buf);
+    let start = buf
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(buf.len());
+    &buf[start..]
+}
+
 impl fmt::Debug for TorrentFile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("TorrentFile")
             .field("info_hash", &self.info_hash)
             .field("announce", &self.announce)
             .field("announce-list", &self.announce_list)
+            .field("comment", &self.comment)
+            .field("source", &self.source)
             .field("info", &self.info)
+            .field("malformed", &self.malformed)
             .finish()
     }
 }
@@ -111,7 +355,362 @@ impl fmt::Debug for Info {
             .field("name", &self.name)
             .field("length", &self.length)
             .field("piece length", &self.piece_length)
-            .field("pieces", &format!("too much to show!"))
+            .field("private", &self.private)
+            .field("source", &self.source)
+            .field("files", &self.files)
+            .field("pieces", &"too much to show!".to_string())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+
+    fn test_torrent() -> Vec<u8> {
+        torrent_with_keys(b"10:created by5:rubit13:creation datei1700000000e")
+    }
+
+    /// Single-file torrent with `keys` (bencoded key value pairs) placed before `info`
+    fn torrent_with_keys(keys: &[u8]) -> Vec<u8> {
+        let mut buf = b"d8:announce31:http://tracker.example/announce".to_vec();
+        buf.extend_from_slice(keys);
+        buf.extend_from_slice(b"4:info");
+        buf.extend_from_slice(
+            b"d6:lengthi1048576e4:name8:test.bin12:piece lengthi262144e6:pieces80:",
+        );
+        buf.extend((0..80).collect::<Vec<u8>>());
+        buf.extend_from_slice(b"e8:url-listl26:http://mirror.example/fileee");
+        buf
+    }
+
+    #[test]
+    fn info_hash_matches_known_torrent_with_trailing_keys() {
+        let buf = test_torrent();
+        let torrent_file = TorrentFile::try_from(buf.clone()).unwrap();
+
+        // sha1 of the info dict, from its "d" to its matching "e" before the `url-list` key
+        let find = |needle: &[u8]| buf.windows(needle.len()).position(|w| w == needle).unwrap();
+        let info = &buf[find(b"4:info") + 6..find(b"8:url-list")];
+        assert_eq!(torrent_file.info_hash, <[u8; 20]>::from(Sha1::digest(info)));
+        assert_eq!(
+            torrent_file.info_hash,
+            [
+                0xfc, 0xb9, 0xb1, 0x85, 0xb7, 0xb1, 0x6c, 0xbe, 0x80, 0xfc, 0x32, 0x41, 0xd7, 0x0a,
+                0xa3, 0x9d, 0xa7, 0x97, 0x52, 0x3e,
+            ]
+        );
+        assert_eq!(torrent_file.info.pieces.len(), 4);
+    }
+
+    #[test]
+    fn piece_hash_is_bounds_checked() {
+        let torrent_file = TorrentFile::try_from(test_torrent()).unwrap();
+
+        assert_eq!(
+            torrent_file.piece_hash(0),
+            Some(&torrent_file.info.pieces[0])
+        );
+        assert_eq!(
+            torrent_file.piece_hash(3),
+            Some(&torrent_file.info.pieces[3])
+        );
+        assert_eq!(torrent_file.piece_hash(4), None);
+    }
+
+    #[test]
+    fn wrongly_typed_optional_fields_are_none() {
+        let torrent_file = TorrentFile::try_from(torrent_with_keys(
+            b"10:created byi42e13:creation date5:today8:encodingl5:utf-8e",
+        ))
+        .unwrap();
+
+        assert_eq!(torrent_file.created_by, None);
+        assert_eq!(torrent_file.creation_date, None);
+        assert_eq!(torrent_file.encoding, None);
+        assert_eq!(torrent_file.info.name, "test.bin");
+        assert_eq!(torrent_file.info.length, 1048576);
+    }
+
+    #[test]
+    fn well_typed_optional_fields_are_parsed() {
+        let torrent_file = TorrentFile::try_from(torrent_with_keys(
+            b"10:created by5:rubit13:creation datei1700000000e8:encoding5:UTF-8",
+        ))
+        .unwrap();
+
+        assert_eq!(torrent_file.created_by.as_deref(), Some("rubit"));
+        assert_eq!(torrent_file.creation_date, Some(1700000000));
+        assert_eq!(torrent_file.encoding.as_deref(), Some("UTF-8"));
+    }
+
+    #[test]
+    fn parses_comment_and_top_level_source() {
+        let torrent_file =
+            TorrentFile::try_from(torrent_with_keys(b"7:comment12:hello, world6:source3:TOP"))
+                .unwrap();
+
+        assert_eq!(torrent_file.comment.as_deref(), Some("hello, world"));
+        assert_eq!(torrent_file.source.as_deref(), Some("TOP"));
+        assert_eq!(torrent_file.info.source, None);
+    }
+
+    #[test]
+    fn matches_expected_info_hash() {
+        let torrent_file = TorrentFile::try_from(test_torrent()).unwrap();
+
+        assert_eq!(
+            torrent_file.info_hash_hex(),
+            "fcb9b185b7b16cbe80fc3241d70aa39da797523e"
+        );
+        assert!(torrent_file.matches_info_hash("FCB9B185B7B16CBE80FC3241D70AA39DA797523E"));
+        assert!(!torrent_file.matches_info_hash("0000000000000000000000000000000000000000"));
+        assert!(!torrent_file.matches_info_hash("fcb9b185"));
+        assert_eq!(
+            torrent_file.info_hash_base32(),
+            "7S43DBNXWFWL5AH4GJA5OCVDTWTZOUR6"
+        );
+    }
+
+    #[test]
+    fn builds_a_magnet_link() {
+        let mut torrent_file = TorrentFile::try_from(test_torrent()).unwrap();
+        torrent_file.info.name = String::from("a b&c.iso");
+        torrent_file.announce = String::from("http://t1.example/announce?k=1");
+        torrent_file.announce_list = Some(vec![
+            vec![String::from("http://t1.example/announce?k=1")],
+            vec![String::from("udp://t2.example:80")],
+        ]);
+
+        assert_eq!(
+            torrent_file.to_magnet(),
+            "magnet:?xt=urn:btih:fcb9b185b7b16cbe80fc3241d70aa39da797523e&dn=a%20b%26c.iso\
+             &tr=http%3A%2F%2Ft1.example%2Fannounce%3Fk%3D1&tr=udp%3A%2F%2Ft2.example%3A80"
+        );
+    }
+
+    #[test]
+    fn reads_torrent_from_a_reader() {
+        let torrent_file = TorrentFile::from_reader(&test_torrent()[..]).unwrap();
+
+        assert_eq!(
+            torrent_file.info_hash_hex(),
+            "fcb9b185b7b16cbe80fc3241d70aa39da797523e"
+        );
+    }
+
+    #[test]
+    fn parses_private_and_source() {
+        let mut buf = b"d8:announce31:http://tracker.example/announce4:info".to_vec();
+        buf.extend_from_slice(b"d6:lengthi16e4:name8:test.bin12:piece lengthi16e6:pieces20:");
+        buf.extend([0u8; 20]);
+        buf.extend_from_slice(b"7:privatei1e6:source3:TRKee");
+
+        let torrent_file = TorrentFile::try_from(buf).unwrap();
+
+        assert_eq!(torrent_file.info.private, Some(true));
+        assert!(torrent_file.is_private());
+        assert!(!TorrentFile::try_from(test_torrent()).unwrap().is_private());
+        assert_eq!(torrent_file.info.source.as_deref(), Some("TRK"));
+    }
+
+    #[test]
+    fn reports_each_distinguishing_field() {
+        let torrent_file = TorrentFile::try_from(test_torrent()).unwrap();
+        assert!(torrent_file
+            .content_diff(&TorrentFile::try_from(test_torrent()).unwrap())
+            .is_empty());
+
+        let diff_with = |change: fn(&mut Info)| {
+            let mut other = TorrentFile::try_from(test_torrent()).unwrap();
+            change(&mut other.info);
+            torrent_file.content_diff(&other)
+        };
+
+        assert_eq!(
+            diff_with(|i| i.private = Some(true)),
+            vec![ContentDiff::Private]
+        );
+        assert_eq!(
+            diff_with(|i| i.source = Some("TRK".into())),
+            vec![ContentDiff::Source]
+        );
+        assert_eq!(
+            diff_with(|i| i.piece_length = 131072),
+            vec![ContentDiff::PieceLength]
+        );
+        assert_eq!(
+            diff_with(|i| i.name = "other.bin".into()),
+            vec![ContentDiff::Name]
+        );
+        assert_eq!(diff_with(|i| i.length = 1), vec![ContentDiff::Length]);
+        assert_eq!(
+            diff_with(|i| i.files = Some(Vec::new())),
+            vec![ContentDiff::Files]
+        );
+        assert_eq!(
+            diff_with(|i| i.pieces[2] = [0; 20]),
+            vec![ContentDiff::Pieces]
+        );
+    }
+
+    #[test]
+    fn parses_torrent_with_bom_and_leading_whitespace() {
+        let mut buf = b"\xEF\xBB\xBF \r\n".to_vec();
+        buf.extend(test_torrent());
+
+        let from_reader = TorrentFile::from_reader(&buf[..]).unwrap();
+        let torrent_file = TorrentFile::try_from(buf).unwrap();
+
+        assert_eq!(
+            torrent_file.info_hash_hex(),
+            "fcb9b185b7b16cbe80fc3241d70aa39da797523e"
+        );
+        assert_eq!(from_reader.info_hash, torrent_file.info_hash);
+    }
+
+    #[test]
+    fn bad_torrents_are_errors() {
+        assert!(matches!(
+            TorrentFile::try_from(b"not bencode".to_vec()),
+            Err(ParseError::BadFile)
+        ));
+
+        // a multi-file torrent without files
+        let mut buf = b"d8:announce31:http://tracker.example/announce4:info".to_vec();
+        buf.extend_from_slice(b"d5:filesle4:name4:test12:piece lengthi16e6:pieces20:");
+        buf.extend([0u8; 20]);
+        buf.extend_from_slice(b"ee");
+        assert!(matches!(
+            TorrentFile::parse(&buf),
+            Err(ParseError::Malformed("files"))
+        ));
+
+        // `length` there but a string
+        let mut buf = b"d8:announce31:http://tracker.example/announce4:info".to_vec();
+        buf.extend_from_slice(b"d6:length2:164:name4:test12:piece lengthi16e6:pieces20:");
+        buf.extend([0u8; 20]);
+        buf.extend_from_slice(b"ee");
+        assert!(matches!(
+            TorrentFile::parse(&buf),
+            Err(ParseError::Malformed("length"))
+        ));
+    }
+
+    #[test]
+    fn lists_wrongly_typed_optional_fields() {
+        let mut buf = b"d8:announce31:http://tracker.example/announce10:created byi1e".to_vec();
+        buf.extend_from_slice(b"13:creation datei5e4:infod6:lengthi16e4:name4:test");
+        buf.extend_from_slice(b"12:piece lengthi16e6:pieces20:");
+        buf.extend([0u8; 20]);
+        buf.extend_from_slice(b"7:private3:yesee");
+        let torrent_file = TorrentFile::parse(&buf).unwrap();
+
+        assert_eq!(torrent_file.created_by, None);
+        assert_eq!(torrent_file.creation_date, Some(5));
+        assert_eq!(torrent_file.info.private, None);
+        assert_eq!(torrent_file.malformed, ["info.private", "created by"]);
+
+        assert!(TorrentFile::try_from(test_torrent())
+            .unwrap()
+            .malformed
+            .is_empty());
+    }
+
+    #[test]
+    fn lists_wrongly_typed_announce_lists() {
+        let info = b"4:infod6:lengthi16e4:name4:test12:piece lengthi16e6:pieces20:";
+        let not_a_tier = b"13:announce-listli1ee".to_vec();
+        let not_utf8 = [&b"13:announce-listll2:"[..], &[0xc3, 0x28], b"ee"].concat();
+
+        for announce_list in [not_a_tier, not_utf8] {
+            let mut buf = b"d8:announce31:http://tracker.example/announce".to_vec();
+            buf.extend_from_slice(&announce_list);
+            buf.extend_from_slice(info);
+            buf.extend([0u8; 20]);
+            buf.extend_from_slice(b"ee");
+            let torrent_file = TorrentFile::parse(&buf).unwrap();
+
+            assert_eq!(torrent_file.announce_list, None);
+            assert_eq!(torrent_file.malformed, ["announce-list"]);
+        }
+    }
+
+    #[test]
+    fn parses_empty_torrents() {
+        let buf = b"d8:announce31:http://tracker.example/announce4:info\
+                    d6:lengthi0e4:name5:empty12:piece lengthi16e6:pieces0:ee";
+        let torrent_file = TorrentFile::parse(buf).unwrap();
+
+        assert_eq!(torrent_file.info.length, 0);
+        assert!(torrent_file.info.pieces.is_empty());
+        assert_eq!(torrent_file.piece_hash(0), None);
+    }
+
+    #[test]
+    fn rejects_piece_counts_not_matching_the_length() {
+        // 40 bytes in 16 byte pieces need 3 hashes
+        let mut buf = b"d8:announce31:http://tracker.example/announce4:info".to_vec();
+        buf.extend_from_slice(b"d6:lengthi40e4:name4:test12:piece lengthi16e6:pieces40:");
+        buf.extend([0u8; 40]);
+        buf.extend_from_slice(b"ee");
+
+        assert!(matches!(
+            TorrentFile::parse(&buf),
+            Err(ParseError::PieceCountMismatch {
+                expected: 3,
+                found: 2
+            })
+        ));
+        assert!(matches!(
+            TorrentFile::from_reader(&buf[..]),
+            Err(ParseError::PieceCountMismatch { .. })
+        ));
+
+        let zero = String::from_utf8_lossy(&buf).replace("lengthi16e", "lengthi0e");
+        assert!(matches!(
+            TorrentFile::parse(zero.as_bytes()),
+            Err(ParseError::Malformed("piece length"))
+        ));
+    }
+
+    #[test]
+    fn parses_multi_file_torrents() {
+        let mut buf = b"d8:announce31:http://tracker.example/announce4:infod5:filesl".to_vec();
+        buf.extend_from_slice(b"d6:lengthi10e4:pathl5:a.txteed6:lengthi30e4:pathl3:dir5:b.binee");
+        buf.extend_from_slice(b"e4:name4:test12:piece lengthi16e6:pieces60:");
+        buf.extend([0u8; 60]);
+        buf.extend_from_slice(b"ee");
+
+        let torrent_file = TorrentFile::parse(&buf).unwrap();
+        let from_reader = TorrentFile::from_reader(&buf[..]).unwrap();
+
+        assert_eq!(torrent_file.info.length, 40);
+        assert_eq!(
+            torrent_file.info.files,
+            Some(vec![
+                FileEntry {
+                    path: vec![String::from("a.txt")],
+                    length: 10,
+                },
+                FileEntry {
+                    path: vec![String::from("dir"), String::from("b.bin")],
+                    length: 30,
+                },
+            ])
+        );
+        assert_eq!(from_reader.info.files, torrent_file.info.files);
+        assert_eq!(
+            TorrentFile::try_from(test_torrent()).unwrap().info.files,
+            None
+        );
+
+        // a file without a path
+        let no_path = String::from_utf8_lossy(&buf).replace("4:pathl5:a.txte", "");
+        assert!(matches!(
+            TorrentFile::parse(no_path.as_bytes()),
+            Err(ParseError::Malformed("files"))
+        ));
+    }
+}
@@ -1,8 +1,11 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
 
 use sha1::{Digest, Sha1};
 
-use crate::{BencodeTypes, ParseError, Peers};
+use crate::{BencodeTypes, FileEntry, ParseError, Peers};
 
 pub fn get_hash(slice: &[u8]) -> Result<[u8; 20], ParseError> {
     let mut hasher = Sha1::new();
@@ -10,6 +13,11 @@ pub fn get_hash(slice: &[u8]) -> Result<[u8; 20], ParseError> {
     Ok(hasher.finalize().into())
 }
 
+/// Lowercase hex form of `bytes`, e.g. of an info hash
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 pub fn unwrap_string(string: BencodeTypes) -> Option<String> {
     if let BencodeTypes::String(s) = string {
         Some(s)
@@ -18,6 +26,16 @@ pub fn unwrap_string(string: BencodeTypes) -> Option<String> {
     }
 }
 
+/// Raw bytes of a string, whether or not it happened to be utf-8
+pub fn unwrap_bytes(bytes: BencodeTypes) -> Option<Vec<u8>> {
+    match bytes {
+        BencodeTypes::Bytes(b) => Some(b),
+        BencodeTypes::String(s) => Some(s.into_bytes()),
+        BencodeTypes::InfoHash(ih) => Some(ih.to_vec()),
+        _ => None,
+    }
+}
+
 pub fn unwrap_integer(int: BencodeTypes) -> Option<u64> {
     if let BencodeTypes::Integer(i) = int {
         Some(i)
@@ -26,26 +44,42 @@ pub fn unwrap_integer(int: BencodeTypes) -> Option<u64> {
     }
 }
 
+/// `None` if any tier isn't a list of utf-8 strings
 pub fn unwrap_announce_list(vec: BencodeTypes) -> Option<Vec<Vec<String>>> {
     let BencodeTypes::List(vec) = vec else {
         return None;
     };
 
-    let out = vec
-        .iter()
-        .map(|item| match item {
-            BencodeTypes::List(v) => v
-                .iter()
-                .map(|item| match item {
-                    BencodeTypes::String(s) => s.clone(),
-                    _ => panic!("not a String"),
-                })
-                .collect(),
-            _ => panic!("not a List"),
+    vec.into_iter()
+        .map(|tier| match tier {
+            BencodeTypes::List(v) => v.into_iter().map(unwrap_string).collect(),
+            _ => None,
         })
-        .collect();
+        .collect()
+}
+
+/// `None` if the list is empty or any entry isn't a dict with an integer `length`
+/// and a `path` list of utf-8 strings
+pub fn unwrap_files(files: BencodeTypes) -> Option<Vec<FileEntry>> {
+    let BencodeTypes::List(files) = files else {
+        return None;
+    };
+    if files.is_empty() {
+        return None;
+    }
 
-    Some(out)
+    files
+        .into_iter()
+        .map(|file| {
+            let mut file = unwrap_dict(file)?;
+            let length = unwrap_integer(file.remove("length")?)?;
+            let BencodeTypes::List(path) = file.remove("path")? else {
+                return None;
+            };
+            let path = path.into_iter().map(unwrap_string).collect::<Option<_>>()?;
+            Some(FileEntry { path, length })
+        })
+        .collect()
 }
 
 pub fn unwrap_dict(dict: BencodeTypes) -> Option<HashMap<String, BencodeTypes>> {
@@ -72,35 +106,50 @@ pub fn unwrap_pieces(pieces: BencodeTypes) -> Option<Vec<[u8; 20]>> {
     }
 }
 
-fn parse_ip(string: String) -> (u8, u8, u8, u8) {
-    let vec: Vec<_> = string
-        .split(".")
-        .map(|s| s.parse::<u8>().expect("failed to parse ip"))
-        .collect();
-    (vec[0], vec[1], vec[2], vec[3])
+/// A dict keyed by raw bytes, whether or not its keys happened to be utf-8
+pub fn unwrap_bytes_dict(dict: BencodeTypes) -> Option<HashMap<Vec<u8>, BencodeTypes>> {
+    match dict {
+        BencodeTypes::BytesDict(d) => Some(d),
+        BencodeTypes::Dict(d) => Some(d.into_iter().map(|(k, v)| (k.into_bytes(), v)).collect()),
+        _ => None,
+    }
+}
+
+/// `ip` of a non-compact peer entry, a textual address or (sent by some trackers) raw octets.
+/// Hostnames aren't resolved
+fn parse_peer_ip(ip: BencodeTypes) -> Option<IpAddr> {
+    let bytes = match ip {
+        BencodeTypes::String(s) => match s.trim().parse() {
+            Ok(ip) => return Some(ip),
+            // Octets that happen to be valid utf-8
+            Err(_) => s.into_bytes(),
+        },
+        BencodeTypes::Bytes(b) => b,
+        _ => return None,
+    };
+
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
 }
 
+/// Compact peers or the dictionary form (a list of `ip`/`port` dicts),
+/// entries that can't be read are skipped so one bad peer doesn't fail the announce
 pub fn unwrap_peers(peers: BencodeTypes) -> Option<Peers> {
     match peers {
         BencodeTypes::PeersCompact(p) => Some(p),
-        BencodeTypes::List(mut l) => Some(
-            l.iter_mut()
-                .map(|item| match item {
-                    BencodeTypes::Dict(d) => {
-                        let ip = parse_ip(
-                            unwrap_string(d.remove("ip").expect("no ip param found"))
-                                .expect("failed to get ip"),
-                        );
-
-                        let port = unwrap_integer(d.remove("port").expect("no portW param found"))
-                            .expect("failed to get port");
-
-                        (ip, port as u16)
-                    }
-                    _ => panic!("not a List"),
+        BencodeTypes::List(l) => Some(
+            l.into_iter()
+                .filter_map(|item| {
+                    let mut d = unwrap_dict(item)?;
+                    let ip = parse_peer_ip(d.remove("ip")?)?;
+                    let port = u16::try_from(unwrap_integer(d.remove("port")?)?).ok()?;
+                    Some(SocketAddr::new(ip, port))
                 })
                 .collect(),
         ),
-        _ => unimplemented!(),
+        _ => None,
     }
 }
@@ -1,9 +1,16 @@
 use std::{
     collections::VecDeque,
     fs::{self, File},
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    io::{self, Read},
+    net::IpAddr,
+    path::{Path, PathBuf},
     process::exit,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
     time::{self, Duration},
 };
 
@@ -11,77 +18,383 @@ use clap::Parser;
 use indicatif::{ProgressBar, ProgressStyle};
 use rand::thread_rng;
 use rubit::{
-    check_download_percent, get_random_id, get_tracker_list, retain_not_downloaded_pieces,
-    AnnounceConfig, FailureResponse, PeerManager, Responses,
+    bytes_left, check_torrent_data, find_moved_file, get_tracker_list, open_torrent_data,
+    parse_file_selection, piece_size, pieces_for_files, reached_percent,
+    retain_not_downloaded_pieces, safe_path, serve_metrics, torrent_data_len, Announcer, Blocklist,
+    Dht, DownloadEvent, Metrics, PeerContext, PeerIdPolicy, PeerIds, PeerManager, PeerTimeouts,
+    PieceOrder, PieceResult, PieceWriter, Proxy, ReadWriteSeek, RecentlyTried, ResumeState,
+    SwarmState,
 };
 
 use rand::seq::SliceRandom;
-use rubit_bencode::TorrentFile;
+use rubit_bencode::{json_string, ParseError, Peers, TorrentFile};
+
+mod mmap;
+
+use mmap::MappedFile;
+
+/// Set by `--quiet`, see `status!`
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--json`, see `error!`
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// `println!` for status output, errors use `error!` so `--quiet` still shows them
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Prints an error, as an `error` event with `--json`
+macro_rules! error {
+    ($($arg:tt)*) => {
+        report("error", format!($($arg)*))
+    };
+}
+
+/// Prints a problem the download keeps going after, as a `warning` event with `--json`
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        report("warning", format!($($arg)*))
+    };
+}
+
+/// Time to wait for the tracker responses of one announce round
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(20);
+/// Time between DHT lookups
+const DHT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// How often newly verified pieces are saved to the resume file
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(5);
 
 /// Simple Bittorrent client capable of downloading meta-info (.torrent) files,
 /// Writen in Rust!
 #[derive(Parser, Debug)]
-#[command(version, about)]
+// `-V` is taken by verbose, so `--version` has no short flag
+#[command(version, about, disable_version_flag = true)]
 struct Args {
-    /// Path of the .torrent file to download
+    /// Path of the .torrent file to download, `-` reads it from stdin
     #[arg(short = 't', long)]
     torrent_file: String,
-    /// [Optional] Output file Path [default: the directory rubit was run in ]
+    /// [Optional] Output file Path, the directory of a multi-file torrent
+    /// [default: the torrent's name in the directory rubit was run in ]
     #[arg(short = 'o', long)]
     out: Option<String>,
+    /// [Optional] Directory to download into, the download keeps the torrent's name.
+    /// Created if missing, can't be combined with `--out`
+    #[arg(long, conflicts_with = "out")]
+    out_dir: Option<String>,
     /// [Optional] The interval to re-announce on in Secs\n
     /// Some trackers return long intervals e.g. 30min
     /// You can set this option to something like 30s to get more peers
+    /// It is never shorter than the min interval the tracker asks for
     #[arg(short = 'i', long)]
     interval: Option<u64>,
     /// [Optional] Print extra logs, needed for development and will omit the progress bar
     #[arg(short = 'V', long, action)]
     verbose: bool,
+    /// [Optional] Print nothing but errors, for scripts and cron jobs
+    #[arg(short = 'q', long, action, conflicts_with = "verbose")]
+    quiet: bool,
+    /// [Optional] Number of threads used to verify already downloaded pieces
+    /// [default: number of available cores]
+    #[arg(short = 'j', long)]
+    verify_threads: Option<usize>,
+    /// [Optional] Verify and write pieces on a dedicated thread
+    /// instead of on the peer threads
+    #[arg(short = 'w', long, action)]
+    writer_thread: bool,
+    /// [Optional] Peers connected to at the same time, others wait for a free slot
+    #[arg(short = 'm', long, default_value_t = PeerManager::DEFAULT_MAX_THREADS)]
+    max_peers: usize,
+    /// [Optional] Stop announcing for new peers while this many are connected or waiting
+    #[arg(long, default_value_t = PeerManager::DEFAULT_MAX_KNOWN_PEERS)]
+    max_known_peers: usize,
+    /// [Optional] Seconds to wait for a peer to accept the connection [default: 2]
+    #[arg(long, value_parser = parse_secs)]
+    connect_timeout: Option<Duration>,
+    /// [Optional] Seconds to wait for each message until the peer unchoked us [default: 2]
+    #[arg(long, value_parser = parse_secs)]
+    handshake_timeout: Option<Duration>,
+    /// [Optional] Seconds to wait for each message while downloading [default: 10]
+    #[arg(long, value_parser = parse_secs)]
+    read_timeout: Option<Duration>,
+    /// [Optional] Seconds a choked peer gets to unchoke us before its slot goes to
+    /// another peer [default: 180]
+    #[arg(long, value_parser = parse_secs)]
+    unchoke_timeout: Option<Duration>,
+    /// [Optional] Seconds before a peer whose connection failed is tried again [default: 300]
+    #[arg(long, value_parser = parse_secs)]
+    peer_cooldown: Option<Duration>,
+    /// [Optional] Grow the output file to its full size before downloading
+    #[arg(long, action)]
+    preallocate: bool,
+    /// [Optional] Port advertised to trackers,
+    /// where incoming peer connections will be accepted once seeding is supported
+    #[arg(short = 'p', long, default_value_t = 6881)]
+    port: u16,
+    /// [Optional] Stop once this percentage of the pieces (of the selected files with --select)
+    /// is downloaded and verified, exits with code 2 instead of 0
+    #[arg(long, value_parser = parse_percent)]
+    stop_at: Option<f64>,
+    /// [Optional] Extra HTTP header sent to trackers as `Name: value`, can be repeated
+    #[arg(short = 'H', long = "header", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+    /// [Optional] Directory to look for already downloaded data that was moved or renamed,
+    /// the file is found by its content and the download resumes there
+    #[arg(long)]
+    relink: Option<String>,
+    /// [Optional] Memory-map the .torrent file instead of reading it,
+    /// saves memory for huge torrents
+    #[arg(long, action)]
+    mmap: bool,
+    /// [Optional] Hex info hash the torrent must have, aborts before downloading otherwise
+    #[arg(long)]
+    expect_hash: Option<String>,
+    /// [Optional] Print newline-delimited JSON progress events and a final summary
+    /// instead of the progress bar and status lines
+    #[arg(long, action, conflicts_with_all = ["verbose", "quiet", "info"])]
+    json: bool,
+    /// [Optional] Download pieces in order so media can be played before it completes
+    #[arg(long, action)]
+    sequential: bool,
+    /// [Optional] Only download these files: comma separated indices (`0,2-4`)
+    /// or globs on the file path as `--info` lists it (`*.mkv`)
+    #[arg(long)]
+    select: Option<String>,
+    /// [Optional] File of IP ranges to never connect to, one CIDR range or
+    /// PeerGuardian `description:first-last` line per line
+    #[arg(long)]
+    blocklist: Option<String>,
+    /// [Optional] Proxy for trackers and peers, `socks5://[user:pass@]host:port` or
+    /// `http://host:port` [default: `ALL_PROXY` from the environment, trackers also
+    /// use `HTTP_PROXY`. Hosts in `NO_PROXY` are reached directly]
+    #[arg(long)]
+    proxy: Option<String>,
+    /// [Optional] Also find peers through the DHT (BEP 5), never used for private torrents
+    #[arg(long, action)]
+    dht: bool,
+    /// [Optional] Serve Prometheus metrics on this port at `/metrics`
+    #[arg(long)]
+    metrics_port: Option<u16>,
+    /// [Optional] Address to serve the metrics on, `0.0.0.0` makes them reachable
+    /// from other machines
+    #[arg(long, default_value = "127.0.0.1", requires = "metrics_port")]
+    metrics_bind: IpAddr,
+    /// [Optional] Hash every downloaded piece again instead of trusting the
+    /// `<out>.rubit` resume file
+    #[arg(long, action)]
+    recheck: bool,
+    /// [Optional] Print the torrent's metadata and exit without downloading
+    #[arg(long, action)]
+    info: bool,
+    /// [Optional] Hash the pieces already in the output file, print which ones are bad
+    /// and exit without downloading. Exits with 1 unless every piece passes
+    #[arg(long, action, conflicts_with = "info")]
+    verify: bool,
+    /// Print version
+    #[arg(long, action = clap::ArgAction::Version)]
+    version: Option<bool>,
 }
 
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("`{}` is not a `Name: value` header", s)),
+    }
+}
+
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('%').parse::<f64>() {
+        Ok(p) if p > 0.0 && p <= 100.0 => Ok(p),
+        _ => Err(format!("`{}` is not a percentage between 0 and 100", s)),
+    }
+}
+
+/// Fractions are fine, e.g. `0.5` on a LAN
+fn parse_secs(s: &str) -> Result<Duration, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(format!("`{}` is not a positive number of seconds", s)),
+    }
+}
+
+/// Exit code when `--stop-at` was reached before the download completed
+const EXIT_STOPPED_AT: i32 = 2;
+/// Exit code after Ctrl-C, like shells report SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
+/// Time peers and trackers get when stopping early
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
 fn main() {
     let args = Args::parse();
+    let started = time::Instant::now();
+    QUIET.store(args.quiet || args.json, Ordering::Relaxed);
+    JSON.store(args.json, Ordering::Relaxed);
+    handle_interrupts();
 
-    let file_buf = match fs::read(args.torrent_file) {
-        Ok(f) => f,
+    let torrent_file = if args.torrent_file == "-" {
+        // Can't be memory-mapped, `--mmap` doesn't apply
+        let mut buf = Vec::new();
+        io::stdin()
+            .lock()
+            .read_to_end(&mut buf)
+            .map(|_| TorrentFile::try_from(buf))
+    } else if args.mmap {
+        File::open(&args.torrent_file)
+            .and_then(|f| MappedFile::open(&f))
+            .map(|mapped| TorrentFile::parse(&mapped))
+    } else {
+        fs::read(&args.torrent_file).map(TorrentFile::try_from)
+    };
+
+    let torrent_file = match torrent_file {
+        Ok(Ok(t)) => t,
+        Ok(Err(ParseError::MissingField)) => {
+            error!("Bad torrent file! A required field is missing");
+            exit(1)
+        }
+        Ok(Err(ParseError::Malformed(key))) => {
+            error!("Bad torrent file! `{}` has the wrong type", key);
+            exit(1)
+        }
+        Ok(Err(e)) => {
+            error!("bad torrent file!: {e:?}");
+            exit(1)
+        }
         Err(e) => {
-            println!("failed to read torrent file with Err: {}", e);
+            error!("failed to read torrent file with Err: {}", e);
             exit(1)
         }
     };
 
-    let torrent_file = TorrentFile::from(file_buf);
+    if let Some(expected) = &args.expect_hash {
+        if !torrent_file.matches_info_hash(expected) {
+            error!(
+                "info hash mismatch! expected: {} got: {}",
+                expected.trim(),
+                torrent_file.info_hash_hex()
+            );
+            exit(1)
+        }
+    }
+
+    if args.info {
+        print_info(&torrent_file);
+        exit(0)
+    }
 
     let piece_num = torrent_file.info.pieces.len();
 
-    let path_string = match args.out {
-        Some(s) => &s.clone(),
-        None => &torrent_file.info.name,
+    let verify_threads = match args.verify_threads {
+        Some(n) => n,
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
     };
 
-    let file = Arc::new(Mutex::new(
-        match File::options()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(path_string)
-        {
-            Ok(f) => f,
-            Err(e) => {
-                println!("failed to create file with Err: {}", e);
+    let relinked = args.relink.as_ref().map(|dir| {
+        if torrent_file.info.files.is_some() {
+            error!("--relink only finds the data of single-file torrents");
+            exit(1)
+        }
+        status!("Looking for the torrent's data in {}...", dir);
+        match find_moved_file(
+            Path::new(dir),
+            &torrent_file.info.pieces,
+            torrent_file.info.length,
+            torrent_file.info.piece_length,
+            verify_threads,
+        ) {
+            Some((path, completed)) => {
+                status!(
+                    "matched {} ({}/{} pieces)",
+                    path.display(),
+                    completed.len(),
+                    piece_num
+                );
+                path.to_string_lossy().to_string()
+            }
+            None => {
+                error!(
+                    "missing {}: no file in {} matches",
+                    torrent_file.info.name, dir
+                );
                 exit(1)
             }
-        },
-    ));
+        }
+    });
 
-    let completed = check_download_percent(
-        file.clone(),
-        &torrent_file.info.pieces,
-        torrent_file.info.length,
-        torrent_file.info.piece_length,
-    );
+    // The torrent's own name is used without --out, it must not escape the directory
+    let named = |dir: &str| match safe_path(
+        Path::new(dir),
+        std::slice::from_ref(&torrent_file.info.name),
+    ) {
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(e) => {
+            error!("bad torrent name {:?}: {}", torrent_file.info.name, e);
+            exit(1)
+        }
+    };
+    let path_string = &match (relinked, args.out, &args.out_dir) {
+        (Some(s), _, _) => s,
+        (None, Some(s), _) => s,
+        (None, None, Some(dir)) => named(dir),
+        (None, None, None) => named("."),
+    };
+
+    if args.verify {
+        verify(&torrent_file, Path::new(path_string), verify_threads);
+    }
+
+    if let Some(dir) = &args.out_dir {
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!("failed to create {} with Err: {}", dir, e);
+            exit(1)
+        }
+    }
+
+    let download_path = PathBuf::from(path_string);
+    let resume_path = ResumeState::path_for(&download_path);
+    let download_len = torrent_data_len(&torrent_file, &download_path);
+    let has_data = download_len > 0;
+    let resumed = (has_data && !args.recheck)
+        .then(|| ResumeState::load(&resume_path, &torrent_file.info_hash, download_len))
+        .flatten();
+
+    let completed = match resumed {
+        Some(verified) => {
+            status!(
+                "Resuming from {}, {} pieces already verified",
+                resume_path.display(),
+                verified.len()
+            );
+            verified.into_iter().filter(|i| *i < piece_num).collect()
+        }
+        None => {
+            if has_data {
+                status!("File already exists, checking downloaded hashes...");
+            }
+            check_torrent_data(&torrent_file, &download_path, verify_threads)
+        }
+    };
 
-    let progress_bar = ProgressBar::new(100);
+    // After the check, a preallocated fresh file would look like data to verify
+    let file = match open_torrent_data(&torrent_file, &download_path, args.preallocate) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("failed to create {} with Err: {}", path_string, e);
+            exit(1)
+        }
+    };
+
+    let progress_bar = if args.quiet || args.json {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(100)
+    };
     let poll_duration = Duration::from_millis(250);
     let mut poll_instant = time::Instant::now();
 
@@ -91,143 +404,664 @@ fn main() {
             .progress_chars("##-"),
     );
 
-    let last_value = ((completed.len() as f64 / piece_num as f64) * 100f64).floor() as u64;
+    let already_verified = completed.len();
+    let left = bytes_left(&torrent_file, &completed);
+    let mut resume_state = ResumeState {
+        info_hash: torrent_file.info_hash,
+        verified: completed.clone(),
+        size: download_len,
+    };
+    save_resume_state(
+        &mut resume_state,
+        &resume_path,
+        &torrent_file,
+        &download_path,
+    );
+    let mut resume_dirty = false;
+    let mut resume_instant = time::Instant::now();
+
+    let mut pieces_queue = (0..torrent_file.info.pieces.len()).collect::<Vec<usize>>();
 
-    let pieces_queue = Vec::from((0..torrent_file.info.pieces.len()).collect::<Vec<usize>>());
+    if let Some(spec) = &args.select {
+        let info = &torrent_file.info;
+        // Single-file torrents are a list of one file
+        let (paths, lengths): (Vec<String>, Vec<u64>) = match &info.files {
+            Some(files) => files
+                .iter()
+                .map(|file| (file.path.join("/"), file.length))
+                .unzip(),
+            None => (vec![info.name.clone()], vec![info.length]),
+        };
+        let selected = match parse_file_selection(spec, &paths) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("bad --select: {:?}", e);
+                exit(1)
+            }
+        };
+        let wanted = pieces_for_files(&lengths, info.piece_length, &selected);
+        pieces_queue.retain(|index| wanted.contains(index));
+    }
+
+    // The progress bar, --json progress and --stop-at only count the selected pieces
+    let selected_num = pieces_queue.len();
+    let selected_verified_before = pieces_queue
+        .iter()
+        .filter(|index| completed.contains(index))
+        .count();
+    let selected_verified =
+        |verified: usize| verified - already_verified + selected_verified_before;
+    let last_value =
+        ((selected_verified_before as f64 / selected_num as f64) * 100f64).floor() as u64;
 
     let mut cleaned_vec = retain_not_downloaded_pieces(completed, pieces_queue);
 
     if cleaned_vec.is_empty() {
-        println!("File is already completed! Exiting...");
+        status!("File is already completed! Exiting...");
+        if args.json {
+            print_summary("already_completed", piece_num, piece_num, started);
+        }
         exit(0)
     }
 
-    println!("Downloading...");
+    if torrent_file.is_private() {
+        status!("Private torrent, only its trackers are used for peers");
+    }
+    status!("Downloading...");
     progress_bar.inc(last_value);
 
-    cleaned_vec.shuffle(&mut thread_rng());
+    if !args.sequential {
+        cleaned_vec.shuffle(&mut thread_rng());
+    }
 
     let global_queue = Arc::new(Mutex::new(VecDeque::from(cleaned_vec)));
 
-    let peer_id = get_random_id();
+    let peer_ids = PeerIds::new(PeerIdPolicy::default());
+    let peer_id = peer_ids.get(&torrent_file.info_hash);
+    // Stays the same for every announce of this run
+    // Completed pieces are collected from the events for the resume file
+    let (events, event_receiver) = mpsc::channel();
+    let mut peer_manager = PeerManager::with_events(args.max_peers, events);
+    peer_manager.transfer.set_left(left);
+    if let Some(cooldown) = args.peer_cooldown {
+        peer_manager.recently_tried = Arc::new(RecentlyTried::new(cooldown));
+    }
+    if let Some(path) = &args.blocklist {
+        peer_manager.blocklist = match Blocklist::from_file(Path::new(path)) {
+            Ok(blocklist) => blocklist,
+            Err(e) => {
+                error!("failed to read blocklist with Err: {}", e);
+                exit(1)
+            }
+        };
+        status!(
+            "Blocking {} IP ranges ({} unreadable lines skipped)",
+            peer_manager.blocklist.len(),
+            peer_manager.blocklist.skipped
+        );
+    }
 
-    let peer_manager = PeerManager::new();
+    let (tracker_proxy, proxy) = match &args.proxy {
+        Some(s) => match Proxy::parse(s) {
+            Some(proxy) => (Some(proxy.clone()), Some(proxy)),
+            None => {
+                error!(
+                    "bad --proxy `{}`, expected socks5://host:port or http://host:port",
+                    s
+                );
+                exit(1)
+            }
+        },
+        None => (Proxy::trackers_from_env(), Proxy::peers_from_env()),
+    };
+    match (&tracker_proxy, &proxy) {
+        (Some(tracker_proxy), Some(proxy)) if tracker_proxy == proxy => {
+            status!("Connecting through proxy {}:{}", proxy.host, proxy.port)
+        }
+        (tracker_proxy, proxy) => {
+            if let Some(proxy) = tracker_proxy {
+                status!("Announcing through proxy {}:{}", proxy.host, proxy.port);
+            }
+            if let Some(proxy) = proxy {
+                status!(
+                    "Connecting to peers through proxy {}:{}",
+                    proxy.host,
+                    proxy.port
+                );
+            }
+        }
+    }
 
-    let announce_list = match torrent_file.announce_list.clone() {
-        Some(a) => a,
-        None => vec![vec![String::from("i guess no announce list")]],
+    let dht = match (args.dht, torrent_file.is_private(), &proxy) {
+        (false, _, _) => None,
+        (true, true, _) => {
+            status!("Private torrent, not using the DHT");
+            None
+        }
+        (true, false, Some(_)) => {
+            status!("The DHT can't go through the proxy, not using it");
+            None
+        }
+        (true, false, None) => spawn_dht(torrent_file.info_hash, args.verbose),
     };
 
-    let tracker_list = get_tracker_list(torrent_file.announce.clone(), announce_list);
-    let mut current_tracker_index = 0;
+    let announce_list = torrent_file.announce_list.clone().unwrap_or_default();
 
-    let mut announce_instant = time::Instant::now();
-    let mut duration = Duration::from_millis(1);
+    let mut tracker_tiers = get_tracker_list(torrent_file.announce.clone(), announce_list);
+    for tracker in tracker_tiers.iter_mut().flatten() {
+        tracker.extra_headers = args.headers.clone();
+        tracker.proxy = tracker_proxy.clone();
+    }
+
+    let mut announcer = Announcer::new(
+        tracker_tiers,
+        torrent_file.info_hash,
+        peer_id.to_string(),
+        args.port,
+    );
+    announcer.interval = args.interval.map(Duration::from_secs);
+    announcer.max_known_peers = args.max_known_peers;
+    announcer.timeout = ANNOUNCE_TIMEOUT;
 
     let shared_torrent_file = Arc::new(torrent_file);
-    let mut handles = Vec::new();
+
+    let verified = Arc::new(AtomicUsize::new(already_verified));
+
+    let metrics = args.metrics_port.map(|port| {
+        let metrics = Arc::new(Metrics::new(&shared_torrent_file, Arc::clone(&verified)));
+        if let Err(e) = serve_metrics((args.metrics_bind, port), Arc::clone(&metrics)) {
+            error!("failed to serve metrics on port {} with Err: {}", port, e);
+            exit(1)
+        }
+        metrics
+    });
+
+    let mut writer_handle = None;
+    let mut writer_results = None;
+    let writer = if args.writer_thread {
+        let (writer, results, handle) = PieceWriter::spawn(
+            file.clone(),
+            Arc::clone(&shared_torrent_file),
+            Arc::clone(&global_queue),
+            Arc::clone(&peer_manager.hash_failures),
+            true,
+        );
+        writer_handle = Some(handle);
+        writer_results = Some(results);
+        Some(writer)
+    } else {
+        None
+    };
+
+    let default_timeouts = PeerTimeouts::default();
+    let timeouts = PeerTimeouts {
+        connect: args.connect_timeout.unwrap_or(default_timeouts.connect),
+        handshake: args.handshake_timeout.unwrap_or(default_timeouts.handshake),
+        read: args.read_timeout.unwrap_or(default_timeouts.read),
+        unchoke: args.unchoke_timeout.unwrap_or(default_timeouts.unchoke),
+    };
+
+    let peer_context = PeerContext {
+        global_queue: Arc::clone(&global_queue),
+        torrent_file: Arc::clone(&shared_torrent_file),
+        peer_id: peer_id.as_bytes().try_into().unwrap(),
+        file: file.clone(),
+        availability: Arc::clone(&peer_manager.availability),
+        hash_failures: Arc::clone(&peer_manager.hash_failures),
+        verified: Arc::clone(&verified),
+        completions: Arc::clone(&peer_manager.completions),
+        peer_stats: Arc::clone(&peer_manager.peer_stats),
+        haves: Arc::clone(&peer_manager.haves),
+        in_progress: Arc::clone(&peer_manager.in_progress),
+        transfer: Arc::clone(&peer_manager.transfer),
+        piece_order: if args.sequential {
+            PieceOrder::Sequential
+        } else {
+            PieceOrder::Random
+        },
+        timeouts,
+        proxy,
+        dht: dht.as_ref().map(|(dht, _)| Arc::clone(dht)),
+        shutdown: Arc::clone(&peer_manager.shutdown),
+        events: peer_manager.events.clone(),
+        writer,
+        verbose: args.verbose,
+    };
 
     loop {
-        if poll_instant.elapsed() > poll_duration && !args.verbose {
-            let queue_len = global_queue.lock().unwrap().len();
+        if poll_instant.elapsed() > poll_duration && args.json {
+            let percent = selected_verified(verified.load(Ordering::Relaxed)) as f64
+                / selected_num as f64
+                * 100f64;
+            let peers_len = peer_manager.peers.lock().unwrap().len();
+            print_json(
+                "progress",
+                &[
+                    ("percent", (percent.floor() as u64).to_string()),
+                    ("peers", peers_len.to_string()),
+                ],
+            );
+
+            poll_instant = time::Instant::now();
+        }
+
+        if poll_instant.elapsed() > poll_duration && !args.verbose && !args.quiet && !args.json {
+            let queue = global_queue.lock().unwrap();
+            let queue_len = queue.len();
+            let swarm_state = peer_manager.availability.swarm_state(queue.iter());
+            std::mem::drop(queue);
             let peers_len = peer_manager.peers.lock().unwrap().len();
             print!("\r\033[K");
 
-            let value = (100f64 - ((queue_len as f64 / piece_num as f64) * 100f64)).floor() as u64;
+            let value =
+                (100f64 - ((queue_len as f64 / selected_num as f64) * 100f64)).floor() as u64;
             progress_bar.set_position(value);
-            progress_bar.set_message(format!("Peers: {}", peers_len));
+            match swarm_state {
+                SwarmState::WaitingForSeed { missing } => progress_bar.set_message(format!(
+                    "Peers: {} (missing {} pieces, waiting for a seed)",
+                    peers_len,
+                    missing.len()
+                )),
+                _ => progress_bar.set_message(format!("Peers: {}", peers_len)),
+            }
 
             poll_instant = time::Instant::now();
         }
 
+        if let Some(results) = &writer_results {
+            for result in results.try_iter() {
+                if let PieceResult::Written(index) = result {
+                    verified.fetch_add(1, Ordering::Relaxed);
+                    let size = piece_size(&shared_torrent_file, index);
+                    peer_manager.transfer.piece_verified(size);
+                    peer_manager.completions.record(index);
+                    peer_manager.haves.broadcast(index);
+                    peer_manager.emit(DownloadEvent::PieceCompleted { index });
+                }
+            }
+        }
+
+        for index in event_receiver.try_iter().filter_map(completed_index) {
+            resume_dirty |= resume_state.verified.insert(index);
+        }
+        if resume_dirty && resume_instant.elapsed() > RESUME_SAVE_INTERVAL {
+            save_resume_state(
+                &mut resume_state,
+                &resume_path,
+                &shared_torrent_file,
+                &download_path,
+            );
+            resume_dirty = false;
+            resume_instant = time::Instant::now();
+        }
+
+        if let Some((_, dht_peers)) = &dht {
+            for socket_addr in dht_peers.try_iter().flatten() {
+                peer_manager.try_add(socket_addr, peer_context.clone());
+            }
+        }
+
+        let stop = if INTERRUPTED.load(Ordering::Relaxed) {
+            Some(("interrupted", String::from("Interrupted"), EXIT_INTERRUPTED))
+        } else {
+            args.stop_at
+                .filter(|percent| {
+                    reached_percent(
+                        selected_verified(verified.load(Ordering::Relaxed)),
+                        selected_num,
+                        *percent,
+                    )
+                })
+                .map(|percent| {
+                    (
+                        "stopped",
+                        format!("Stopped at {}%", percent),
+                        EXIT_STOPPED_AT,
+                    )
+                })
+        };
+
+        if let Some((result, message, code)) = stop {
+            shut_down(&peer_manager, &file, &mut announcer);
+            resume_state
+                .verified
+                .extend(event_receiver.try_iter().filter_map(completed_index));
+            save_resume_state(
+                &mut resume_state,
+                &resume_path,
+                &shared_torrent_file,
+                &download_path,
+            );
+            status!("{}", message);
+            if args.json {
+                let verified = verified.load(Ordering::Relaxed);
+                print_summary(result, verified, piece_num, started);
+            }
+            exit(code)
+        }
+
         let queue = global_queue.lock().unwrap();
         let peers = peer_manager.peers.lock().unwrap();
+        if let Some(metrics) = &metrics {
+            metrics.peers.store(peers.len(), Ordering::Relaxed);
+        }
 
         if queue.is_empty() && peers.is_empty() {
-            println!("Download finished");
+            status!("Download finished");
+            if args.json {
+                let verified = verified.load(Ordering::Relaxed);
+                print_summary("finished", verified, piece_num, started);
+            }
             break;
         }
 
-        if peers.len() > 300 && !peers.is_empty() {
-            continue;
-        }
+        std::mem::drop(queue);
+        std::mem::drop(peers);
 
-        if announce_instant.elapsed() < duration && !peers.is_empty() {
+        if !announcer.is_due(&peer_manager) {
             continue;
         }
 
-        std::mem::drop(queue);
-        std::mem::drop(peers);
+        let round = announcer.announce(&peer_manager, &peer_context);
+        for warning in round
+            .responses
+            .iter()
+            .filter_map(|r| r.warning_message.as_ref())
+        {
+            warning!("tracker warning: {}", warning);
+        }
+        for failure in &round.failures {
+            warning!("tracker failed with reason: {}", failure.failure_reason);
+        }
 
-        #[allow(unused)]
-        let mut response: Responses = Responses::Failure(FailureResponse {
-            failure_reason: String::from("got no response"),
-        });
-
-        loop {
-            match tracker_list[current_tracker_index].announce(AnnounceConfig {
-                info_hash: shared_torrent_file.info_hash,
-                downloaded: 0,
-                left: shared_torrent_file.info.length,
-                uploaded: 0,
-                peer_id: peer_id.to_string(),
-                port: 6881,
-            }) {
-                Ok(r) => {
-                    response = r;
-                    break;
-                }
-                Err(_) => {
-                    current_tracker_index += 1;
-                    continue;
-                }
+        if let Some(metrics) = &metrics {
+            let counter = if round.failed() {
+                &metrics.announces_failed
+            } else {
+                &metrics.announces_ok
             };
+            counter.fetch_add(1, Ordering::Relaxed);
         }
 
-        let result = match response {
-            rubit::Responses::Done(d) => d,
-            rubit::Responses::Failure(f) => {
-                println!("failed with reason: {}", f.failure_reason);
-                panic!()
+        if round.failed() {
+            warning!(
+                "every tracker failed ({} tried)",
+                announcer.tracker_tiers.iter().flatten().count()
+            );
+            let stalled = peer_manager.peers.lock().unwrap().is_empty()
+                && peer_manager.in_progress.is_empty();
+            if dht.is_none() && stalled {
+                exit(1)
             }
-        };
+            // The DHT or the peers we already have keep the download going,
+            // the announcer tries the trackers again later
+            continue;
+        }
 
-        if let Some(d) = args.interval {
-            duration = Duration::from_secs(d)
-        } else {
-            duration = match result.min_interval {
-                Some(i) => i,
-                None => result.interval,
-            };
+        if round.clamped {
+            status!(
+                "--interval is below the tracker's min interval, announcing every {}s",
+                round.interval.as_secs()
+            );
         }
+    }
+
+    peer_manager.join();
+    resume_state
+        .verified
+        .extend(event_receiver.try_iter().filter_map(completed_index));
+    save_resume_state(
+        &mut resume_state,
+        &resume_path,
+        &shared_torrent_file,
+        &download_path,
+    );
 
-        announce_instant = time::Instant::now();
+    // Let the trackers know we're done
+    announcer.completed(&peer_manager);
+
+    // Let the writer thread finish the pieces it still has
+    std::mem::drop(peer_context);
+    if let Some(handle) = writer_handle {
+        handle.join().unwrap();
+    }
+}
 
-        for (octets, port) in result.peers {
-            let socket_addr = SocketAddr::V4(SocketAddrV4::new(
-                Ipv4Addr::new(octets.0, octets.1, octets.2, octets.3),
-                port,
-            ));
-            let global_queue = Arc::clone(&global_queue);
-            let torrent_file = Arc::clone(&shared_torrent_file);
+/// Looks up peers every `DHT_INTERVAL` on a background thread
+fn spawn_dht(info_hash: [u8; 20], verbose: bool) -> Option<(Arc<Dht>, Receiver<Peers>)> {
+    let dht = match Dht::bind("0.0.0.0:0") {
+        Ok(dht) => Arc::new(dht),
+        Err(e) => {
+            warning!("failed to start the DHT with Err: {}", e);
+            return None;
+        }
+    };
 
-            let handle = peer_manager.try_add(
-                global_queue,
-                socket_addr,
-                torrent_file,
-                peer_id.clone().as_bytes().try_into().unwrap(),
-                file.clone(),
-                args.verbose,
+    let (sender, receiver) = mpsc::channel();
+    let lookup = Arc::clone(&dht);
+    thread::spawn(move || loop {
+        let peers = lookup.get_peers(&info_hash);
+        if verbose {
+            println!(
+                "DHT found {} peers ({} nodes known)",
+                peers.len(),
+                lookup.nodes().len()
             );
+        }
+        if sender.send(peers).is_err() {
+            break;
+        }
+        thread::sleep(DHT_INTERVAL);
+    });
 
-            match handle {
-                Some(h) => handles.push(h),
-                None => (),
+    Some((dht, receiver))
+}
+
+fn completed_index(event: DownloadEvent) -> Option<usize> {
+    match event {
+        DownloadEvent::PieceCompleted { index } => Some(index),
+        _ => None,
+    }
+}
+
+/// A failed save only costs a full re-check on the next start.
+/// `state.size` is updated to the current length of `download` first
+fn save_resume_state(
+    state: &mut ResumeState,
+    path: &Path,
+    torrent_file: &TorrentFile,
+    download: &Path,
+) {
+    state.size = torrent_data_len(torrent_file, download);
+    if let Err(e) = state.save(path) {
+        warning!("failed to save {} with Err: {}", path.display(), e);
+    }
+}
+
+fn report(event: &str, message: String) {
+    if JSON.load(Ordering::Relaxed) {
+        print_json(event, &[("message", json_string(&message))]);
+    } else {
+        println!("{}", message);
+    }
+}
+
+fn print_json(event: &str, fields: &[(&str, String)]) {
+    let mut line = format!("{{\"event\":\"{}\"", event);
+    for (key, value) in fields {
+        line.push_str(&format!(",\"{}\":{}", key, value));
+    }
+    line.push('}');
+    println!("{}", line);
+}
+
+fn print_summary(result: &str, verified: usize, pieces: usize, started: time::Instant) {
+    print_json(
+        "summary",
+        &[
+            ("result", json_string(result)),
+            ("verified", verified.to_string()),
+            ("pieces", pieces.to_string()),
+            (
+                "elapsed_secs",
+                format!("{:.3}", started.elapsed().as_secs_f64()),
+            ),
+        ],
+    );
+}
+
+fn print_info(torrent_file: &TorrentFile) {
+    let info = &torrent_file.info;
+    println!("Name:          {}", info.name);
+    println!("Info hash:     {}", torrent_file.info_hash_hex());
+    println!("Magnet:        {}", torrent_file.to_magnet());
+    println!(
+        "Size:          {} ({} bytes)",
+        format_size(info.length),
+        info.length
+    );
+    if let Some(files) = &info.files {
+        println!("Files:");
+        for file in files {
+            println!("  {} ({})", file.path.join("/"), format_size(file.length));
+        }
+    }
+    println!("Piece length:  {}", format_size(info.piece_length));
+    println!("Pieces:        {}", info.pieces.len());
+    println!("Private:       {}", torrent_file.is_private());
+    if let Some(date) = torrent_file.creation_date {
+        println!("Created:       {} (unix time)", date);
+    }
+    if let Some(created_by) = &torrent_file.created_by {
+        println!("Created by:    {}", created_by);
+    }
+    if let Some(comment) = &torrent_file.comment {
+        println!("Comment:       {}", comment);
+    }
+    if let Some(source) = info.source.as_ref().or(torrent_file.source.as_ref()) {
+        println!("Source:        {}", source);
+    }
+    if !torrent_file.malformed.is_empty() {
+        println!(
+            "Ignored:       {} (wrong type)",
+            torrent_file.malformed.join(", ")
+        );
+    }
+
+    println!("Trackers:");
+    match &torrent_file.announce_list {
+        Some(tiers) => {
+            for (tier, trackers) in tiers.iter().enumerate() {
+                for tracker in trackers {
+                    println!("  [tier {}] {}", tier, tracker);
+                }
             }
         }
+        None => println!("  {}", torrent_file.announce),
     }
+}
 
-    for handle in handles {
-        handle.join().unwrap();
+/// `--verify`: hashes every piece of `path` and exits
+fn verify(torrent_file: &TorrentFile, path: &Path, threads: usize) -> ! {
+    if !path.exists() {
+        error!("{} doesn't exist, nothing to verify", path.display());
+        exit(1)
     }
+
+    let info = &torrent_file.info;
+    let passed = check_torrent_data(torrent_file, path, threads);
+    let mut bad: Vec<usize> = (0..info.pieces.len())
+        .filter(|index| !passed.contains(index))
+        .collect();
+    bad.sort_unstable();
+
+    let percent = if info.pieces.is_empty() {
+        100.0
+    } else {
+        passed.len() as f64 / info.pieces.len() as f64 * 100.0
+    };
+    println!(
+        "{}/{} pieces pass ({:.2}%)",
+        passed.len(),
+        info.pieces.len(),
+        percent
+    );
+    if bad.is_empty() {
+        exit(0)
+    }
+    println!("Bad pieces: {}", format_ranges(&bad));
+    exit(1)
 }
+
+/// Sorted indices as ranges, e.g. `0-3,7,9-10`
+fn format_ranges(indices: &[usize]) -> String {
+    let mut ranges: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < indices.len() {
+        let start = indices[i];
+        while i + 1 < indices.len() && indices[i + 1] == indices[i] + 1 {
+            i += 1;
+        }
+        if indices[i] == start {
+            ranges.push(start.to_string());
+        } else {
+            ranges.push(format!("{}-{}", start, indices[i]));
+        }
+        i += 1;
+    }
+    ranges.join(",")
+}
+
+/// Bytes in binary units, e.g. `1.50 MiB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", size, UNITS[unit])
+    }
+}
+
+/// Stops the peers (giving them a moment to finish the current block), tells the
+/// trackers we stopped and waits for any piece being written so none is left half written
+fn shut_down(
+    peer_manager: &PeerManager,
+    file: &Mutex<dyn ReadWriteSeek>,
+    announcer: &mut Announcer,
+) {
+    peer_manager.shutdown(SHUTDOWN_TIMEOUT);
+    announcer.stopped(peer_manager, SHUTDOWN_TIMEOUT);
+
+    let _file = file.lock().unwrap();
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn handle_interrupts() {
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        // A second Ctrl-C kills right away
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            unsafe { libc::_exit(EXIT_INTERRUPTED) }
+        }
+    }
+
+    // SAFETY: the handler only touches an atomic and calls the async-signal-safe `_exit`
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as *const () as libc::sighandler_t,
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_interrupts() {}
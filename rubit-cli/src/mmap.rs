@@ -0,0 +1,121 @@
+use std::{fs::File, io, ops::Deref};
+
+/// Read-only memory map of a whole file, so huge .torrent files can be decoded
+/// without reading them into a `Vec` first
+pub struct MappedFile {
+    #[cfg(unix)]
+    ptr: *mut libc::c_void,
+    #[cfg(not(unix))]
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl MappedFile {
+    #[cfg(unix)]
+    pub fn open(file: &File) -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let len = file.metadata()?.len() as usize;
+        // mmap fails on empty files
+        if len == 0 {
+            return Ok(Self {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+
+        // SAFETY: a private read-only mapping of a file we hold open,
+        // the mapping stays valid after the fd is closed
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { ptr, len })
+    }
+
+    /// No mmap here, falls back to reading the file
+    #[cfg(not(unix))]
+    pub fn open(mut file: &File) -> io::Result<Self> {
+        let mut buf = Vec::new();
+        io::Read::read_to_end(&mut file, &mut buf)?;
+        Ok(Self {
+            len: buf.len(),
+            buf,
+        })
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    #[cfg(unix)]
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: `ptr` points to `len` mapped bytes until drop
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    #[cfg(not(unix))]
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+#[cfg(unix)]
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            // SAFETY: unmaps exactly what `open` mapped
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rubit::{temp_path, torrent_bytes};
+    use rubit_bencode::TorrentFile;
+    use std::fs;
+
+    #[test]
+    fn decodes_large_torrent_from_mapped_file() {
+        let piece_count = 200_000;
+        let pieces: Vec<u8> = (0..piece_count * 20).map(|i| i as u8).collect();
+        let buf = torrent_bytes(
+            "http://tracker.example/announce",
+            "test.bin",
+            piece_count * 16384,
+            16384,
+            &pieces,
+        );
+
+        let path = temp_path("mmap").with_extension("torrent");
+        fs::write(&path, &buf).unwrap();
+
+        let mapped = MappedFile::open(&File::open(&path).unwrap()).unwrap();
+        let torrent_file = TorrentFile::try_from(&mapped[..]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mapped.len(), buf.len());
+        assert_eq!(torrent_file.info.pieces.len(), piece_count);
+        assert_eq!(
+            torrent_file.info_hash,
+            TorrentFile::try_from(buf).unwrap().info_hash
+        );
+    }
+}
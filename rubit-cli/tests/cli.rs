@@ -0,0 +1,296 @@
+use std::{fs, path::PathBuf, process::Command};
+
+use rubit::{multi_file_torrent_bytes, piece_hashes, temp_path, torrent_bytes, ResumeState};
+
+/// Temp dir holding an already downloaded file and its .torrent
+fn completed_download() -> (PathBuf, PathBuf, PathBuf) {
+    let dir = temp_path("cli");
+    fs::create_dir_all(&dir).unwrap();
+
+    let data = (0..32).collect::<Vec<u8>>();
+    let buf = torrent_bytes(
+        "http://tracker.example/announce",
+        "test.bin",
+        data.len(),
+        16,
+        &piece_hashes(&data, 16),
+    );
+
+    let torrent = dir.join("test.torrent");
+    let out = dir.join("test.bin");
+    fs::write(&torrent, buf).unwrap();
+    fs::write(&out, data).unwrap();
+    (dir, torrent, out)
+}
+
+#[test]
+fn quiet_prints_nothing() {
+    let (dir, torrent, out) = completed_download();
+    let run = |extra: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rubit-cli"))
+            .arg("-t")
+            .arg(&torrent)
+            .arg("-o")
+            .arg(&out)
+            .args(extra)
+            .output()
+            .unwrap()
+    };
+
+    let loud = run(&[]);
+    let quiet = run(&["--quiet"]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(loud.status.success());
+    assert!(String::from_utf8_lossy(&loud.stdout).contains("already completed"));
+    assert!(quiet.status.success());
+    assert!(quiet.stdout.is_empty());
+}
+
+#[test]
+fn info_prints_metadata_without_downloading() {
+    let (dir, torrent, _) = completed_download();
+    let missing = dir.join("missing.bin");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rubit-cli"))
+        .arg("-t")
+        .arg(&torrent)
+        .arg("-o")
+        .arg(&missing)
+        .arg("--info")
+        .output()
+        .unwrap();
+    let created = missing.exists();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert!(stdout.contains("Name:          test.bin"));
+    assert!(stdout.contains("Size:          32 B (32 bytes)"));
+    assert!(stdout.contains("Pieces:        2"));
+    assert!(stdout.contains("http://tracker.example/announce"));
+    assert!(!created, "--info must not touch the output file");
+}
+
+#[test]
+fn json_prints_a_summary() {
+    let (dir, torrent, out) = completed_download();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rubit-cli"))
+        .arg("-t")
+        .arg(&torrent)
+        .arg("-o")
+        .arg(&out)
+        .arg("--json")
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert!(output.status.success());
+    assert_eq!(lines.len(), 1, "only JSON lines on stdout: {:?}", lines);
+    assert!(lines[0].starts_with(
+        r#"{"event":"summary","result":"already_completed","verified":2,"pieces":2,"elapsed_secs":"#
+    ));
+    assert!(lines[0].ends_with('}'));
+}
+
+#[test]
+fn resumes_from_the_state_file() {
+    let (dir, torrent, out) = completed_download();
+    let run = |extra: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rubit-cli"))
+            .arg("-t")
+            .arg(&torrent)
+            .arg("-o")
+            .arg(&out)
+            .args(extra)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+
+    let first = run(&[]);
+    let saved = dir.join("test.bin.rubit").exists();
+    let second = run(&[]);
+    let rechecked = run(&["--recheck"]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(first.contains("checking downloaded hashes"));
+    assert!(saved);
+    assert!(second.contains("2 pieces already verified"));
+    assert!(second.contains("already completed"));
+    assert!(rechecked.contains("checking downloaded hashes"));
+}
+
+#[test]
+fn json_prints_errors_as_events() {
+    let (dir, torrent, out) = completed_download();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rubit-cli"))
+        .arg("-t")
+        .arg(&torrent)
+        .arg("-o")
+        .arg(&out)
+        .args(["--json", "--expect-hash", "not\"hex\\"])
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(lines.len(), 1, "only JSON lines on stdout: {:?}", lines);
+    assert!(lines[0].starts_with(
+        r#"{"event":"error","message":"info hash mismatch! expected: not\"hex\\ got: "#
+    ));
+    assert!(lines[0].ends_with("\"}"));
+}
+
+#[test]
+fn verify_doesnt_create_the_out_dir() {
+    let (dir, torrent, _) = completed_download();
+    let out_dir = dir.join("missing");
+
+    Command::new(env!("CARGO_BIN_EXE_rubit-cli"))
+        .arg("-t")
+        .arg(&torrent)
+        .arg("--out-dir")
+        .arg(&out_dir)
+        .arg("--verify")
+        .output()
+        .unwrap();
+    let created = out_dir.exists();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!created, "--verify must not create --out-dir");
+}
+
+/// Temp dir holding the already downloaded `test` directory of `a.bin` (`a_len` bytes)
+/// and `sub/b.bin`, and its .torrent
+fn completed_multi_file_download(a_len: usize) -> (PathBuf, PathBuf) {
+    let dir = temp_path("cli");
+    fs::create_dir_all(dir.join("test/sub")).unwrap();
+    let data = (0..40).collect::<Vec<u8>>();
+    let buf = multi_file_torrent_bytes(
+        "http://tracker.example/announce",
+        "test",
+        &[("a.bin", a_len), ("sub/b.bin", data.len() - a_len)],
+        16,
+        &piece_hashes(&data, 16),
+    );
+
+    let torrent = dir.join("test.torrent");
+    fs::write(&torrent, buf).unwrap();
+    fs::write(dir.join("test/a.bin"), &data[..a_len]).unwrap();
+    fs::write(dir.join("test/sub/b.bin"), &data[a_len..]).unwrap();
+    (dir, torrent)
+}
+
+#[test]
+fn checks_the_files_of_multi_file_torrents() {
+    let (dir, torrent) = completed_multi_file_download(10);
+    let run = |extra: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rubit-cli"))
+            .arg("-t")
+            .arg(&torrent)
+            .arg("--out-dir")
+            .arg(&dir)
+            .args(extra)
+            .output()
+            .unwrap();
+        (
+            output.status.success(),
+            String::from_utf8_lossy(&output.stdout).to_string(),
+        )
+    };
+
+    let info = run(&["--info"]);
+    let verified = run(&["--verify"]);
+    let downloaded = run(&[]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(info.1.contains("Size:          40 B (40 bytes)"));
+    assert!(info.1.contains("  sub/b.bin (30 B)"));
+    assert_eq!(
+        verified,
+        (true, String::from("3/3 pieces pass (100.00%)\n"))
+    );
+    assert!(downloaded.0);
+    assert!(downloaded.1.contains("already completed"));
+}
+
+#[test]
+fn selects_files_of_multi_file_torrents() {
+    // a.bin is exactly the first piece
+    let (dir, torrent) = completed_multi_file_download(16);
+    fs::remove_file(dir.join("test/sub/b.bin")).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rubit-cli"))
+        .arg("-t")
+        .arg(&torrent)
+        .arg("--out-dir")
+        .arg(&dir)
+        .args(["--select", "a.*"])
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("already completed"));
+}
+
+#[test]
+fn stop_at_counts_the_selected_pieces() {
+    let (dir, torrent) = completed_multi_file_download(16);
+    // Only piece 1 is left, half of b.bin's pieces but a third of the torrent
+    fs::remove_file(dir.join("test/a.bin")).unwrap();
+    let b = dir.join("test/sub/b.bin");
+    fs::write(&b, &fs::read(&b).unwrap()[..16]).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rubit-cli"))
+        .arg("-t")
+        .arg(&torrent)
+        .arg("--out-dir")
+        .arg(&dir)
+        .args(["--select", "sub/b.bin", "--json", "--stop-at", "40"])
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(r#""result":"stopped""#), "{}", stdout);
+}
+
+#[test]
+fn rechecks_multi_file_torrents_missing_a_file() {
+    // a.bin is exactly the first piece
+    let (dir, torrent) = completed_multi_file_download(16);
+    let run = |extra: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rubit-cli"))
+            .arg("-t")
+            .arg(&torrent)
+            .arg("--out-dir")
+            .arg(&dir)
+            .args(extra)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+
+    let first = run(&[]);
+    let state = ResumeState::from_bytes(&fs::read(dir.join("test.rubit")).unwrap()).unwrap();
+    fs::remove_file(dir.join("test/sub/b.bin")).unwrap();
+    // The piece still there is enough to stop right away instead of asking the tracker
+    let second = run(&["--json", "--stop-at", "1"]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(first.contains("already completed"));
+    assert_eq!(state.size, 40);
+    assert!(
+        second.contains(r#""result":"stopped","verified":1,"pieces":3"#),
+        "{}",
+        second
+    );
+}
@@ -0,0 +1,150 @@
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use crate::{AnnounceConfig, Responses, ScrapeStats, Tracker, TrackerError};
+
+struct Shared<T> {
+    result: Option<Result<T, TrackerError>>,
+    waker: Option<Waker>,
+}
+
+/// Result of a blocking call running on its own thread. Needs no runtime,
+/// the task is woken once the thread is done so executors aren't blocked.
+/// A panicking call resolves to `TrackerError::Panicked` instead of never waking
+pub struct ThreadFuture<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T: Send + 'static> ThreadFuture<T> {
+    pub fn spawn(f: impl FnOnce() -> Result<T, TrackerError> + Send + 'static) -> Self {
+        let shared = Arc::new(Mutex::new(Shared {
+            result: None,
+            waker: None,
+        }));
+
+        let shared_clone = Arc::clone(&shared);
+        thread::spawn(move || {
+            let result =
+                panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(Err(TrackerError::Panicked));
+            let mut shared = shared_clone.lock().unwrap();
+            shared.result = Some(result);
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        });
+
+        Self { shared }
+    }
+}
+
+impl<T> Future for ThreadFuture<T> {
+    type Output = Result<T, TrackerError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Tracker {
+    /// `announce` on a background thread, same requests and parsing
+    pub fn announce_async(&self, config: AnnounceConfig) -> ThreadFuture<Responses> {
+        let tracker = self.clone();
+        ThreadFuture::spawn(move || tracker.announce(config))
+    }
+
+    /// `scrape` on a background thread
+    pub fn scrape_async(&self, info_hashes: Vec<[u8; 20]>) -> ThreadFuture<Vec<ScrapeStats>> {
+        let tracker = self.clone();
+        ThreadFuture::spawn(move || tracker.scrape(&info_hashes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AnnounceEvent, TestTracker};
+    use std::{net::UdpSocket, task::Wake, thread::Thread, time::Duration};
+    use url::Url;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Smallest possible executor, parks until woken
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    fn config() -> AnnounceConfig {
+        AnnounceConfig {
+            info_hash: [1; 20],
+            peer_id: String::from("RB01-aaaaaaaaaaaaaaa"),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 10,
+            event: AnnounceEvent::Started,
+            num_want: None,
+            tracker_id: None,
+            key: None,
+        }
+    }
+
+    #[test]
+    fn announces_without_blocking_the_caller() {
+        let test_tracker = TestTracker::spawn("127.0.0.1:0").unwrap();
+        let tracker = Tracker::new(test_tracker.announce_url()).unwrap();
+
+        let response = block_on(tracker.announce_async(config()));
+
+        assert!(matches!(response, Ok(Responses::Done(_))));
+        assert_eq!(test_tracker.peers(&[1; 20]).len(), 1);
+    }
+
+    #[test]
+    fn surfaces_errors_of_the_blocking_call() {
+        // Never answers
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("udp://{}", server.local_addr().unwrap())).unwrap();
+        let mut tracker = Tracker::new(url).unwrap();
+        tracker.udp_timeout = Duration::from_millis(10);
+        tracker.udp_max_retries = 1;
+
+        assert!(matches!(
+            block_on(tracker.announce_async(config())),
+            Err(TrackerError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn resolves_panics_to_an_error() {
+        let future = ThreadFuture::<()>::spawn(|| panic!("tracker bug"));
+
+        assert!(matches!(block_on(future), Err(TrackerError::Panicked)));
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::random;
+
+use crate::{
+    announce_interval, announce_tiers, is_valid_peer, merge_peers, AnnounceConfig, AnnounceEvent,
+    AnnounceLimiter, AnnounceState, DownloadEvent, FailureResponse, OkResponse, PeerContext,
+    PeerManager, Responses, Tracker,
+};
+
+/// What one `Announcer::announce` round got
+#[derive(Debug)]
+pub struct AnnounceRound {
+    pub responses: Vec<OkResponse>,
+    pub failures: Vec<FailureResponse>,
+    /// Peers the trackers returned, before invalid ones and our own address are skipped
+    pub peers: usize,
+    /// Until the next round
+    pub interval: Duration,
+    /// The requested `interval` was raised to the trackers' `min interval`
+    pub clamped: bool,
+}
+
+impl AnnounceRound {
+    /// Every tracker failed
+    pub fn failed(&self) -> bool {
+        self.responses.is_empty()
+    }
+}
+
+/// Announce schedule of one download for `Download` and the CLI. It announces on the
+/// trackers' interval while no more than `max_known_peers` peers are known, and early once
+/// every peer is gone. The peers the trackers return go to a `PeerManager`
+pub struct Announcer {
+    pub tracker_tiers: Vec<Vec<Tracker>>,
+    pub info_hash: [u8; 20],
+    pub peer_id: String,
+    pub port: u16,
+    /// Same for the whole download, see `AnnounceConfig::key`
+    pub key: u32,
+    /// Fixed re-announce interval instead of the one the trackers send,
+    /// never shorter than their `min interval`
+    pub interval: Option<Duration>,
+    pub max_known_peers: usize,
+    pub threads: usize,
+    pub timeout: Duration,
+    /// Every announce waits for it, so early re-announces and events
+    /// sent right after a round don't hammer a tracker
+    pub limiter: Arc<AnnounceLimiter>,
+    /// Where we reach the trackers from, found after the first round that got an answer
+    pub local_ip: Option<IpAddr>,
+    state: AnnounceState,
+    next_announce: Instant,
+    /// Early re-announces without peers wait for this
+    earliest_announce: Instant,
+    failed: bool,
+}
+
+impl Announcer {
+    /// Wait before announcing again when every tracker failed
+    pub const TRACKER_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+    /// Shortest wait between two rounds when every peer is gone, trackers without
+    /// a `min interval` still shouldn't be asked again right away
+    const MIN_REANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+    const MAX_IN_FLIGHT_ANNOUNCES: usize = 4;
+    /// Shortest wait between two announces to the same tracker
+    const MIN_TRACKER_SPACING: Duration = Duration::from_secs(1);
+
+    pub fn new(
+        tracker_tiers: Vec<Vec<Tracker>>,
+        info_hash: [u8; 20],
+        peer_id: String,
+        port: u16,
+    ) -> Self {
+        Self {
+            tracker_tiers,
+            info_hash,
+            peer_id,
+            port,
+            key: random(),
+            interval: None,
+            max_known_peers: PeerManager::DEFAULT_MAX_KNOWN_PEERS,
+            threads: 8,
+            timeout: Duration::from_secs(10),
+            limiter: Arc::new(AnnounceLimiter::new(
+                Self::MAX_IN_FLIGHT_ANNOUNCES,
+                Self::MIN_TRACKER_SPACING,
+            )),
+            local_ip: None,
+            state: AnnounceState::default(),
+            next_announce: Instant::now(),
+            earliest_announce: Instant::now(),
+            failed: false,
+        }
+    }
+
+    /// Announce of `event` with the transfer stats of `peer_manager`
+    pub fn config(
+        &self,
+        peer_manager: &PeerManager,
+        event: AnnounceEvent,
+        num_want: Option<i32>,
+    ) -> AnnounceConfig {
+        AnnounceConfig {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id.clone(),
+            port: self.port,
+            uploaded: peer_manager.transfer.uploaded(),
+            downloaded: peer_manager.transfer.downloaded(),
+            left: peer_manager.transfer.left(),
+            event,
+            num_want,
+            tracker_id: None,
+            key: Some(self.key),
+        }
+    }
+
+    /// Whether `announce` should run now, never while paused
+    pub fn is_due(&self, peer_manager: &PeerManager) -> bool {
+        if self.state == AnnounceState::Paused
+            || peer_manager.has_enough_peers(self.max_known_peers)
+        {
+            return false;
+        }
+
+        let now = Instant::now();
+        // Every peer is gone, ask for new ones unless the trackers just failed
+        let starved = !self.failed
+            && now >= self.earliest_announce
+            && peer_manager.peers.lock().unwrap().is_empty();
+        starved || now >= self.next_announce
+    }
+
+    /// Announces to the first tier that answers and adds the peers it returned
+    /// to `peer_manager`. Retried after `TRACKER_RETRY_INTERVAL` if every tracker failed
+    pub fn announce(&mut self, peer_manager: &PeerManager, context: &PeerContext) -> AnnounceRound {
+        let event = self.state.event().unwrap_or_default();
+        let config = self.config(peer_manager, event, None);
+
+        let mut responses = Vec::new();
+        let mut failures = Vec::new();
+        for response in announce_tiers(
+            &mut self.tracker_tiers,
+            &config,
+            &self.limiter,
+            self.threads,
+            self.timeout,
+        ) {
+            match response {
+                Responses::Done(d) => responses.push(d),
+                Responses::Failure(f) => failures.push(f),
+            }
+        }
+
+        self.failed = responses.is_empty();
+        let (interval, clamped) = if self.failed {
+            (Self::TRACKER_RETRY_INTERVAL, false)
+        } else {
+            self.state.announced();
+            announce_interval(&responses, self.interval)
+                .unwrap_or((Self::TRACKER_RETRY_INTERVAL, false))
+        };
+        let min_interval = responses
+            .iter()
+            .filter_map(|response| response.min_interval)
+            .max()
+            .unwrap_or_default();
+        let now = Instant::now();
+        self.next_announce = now + interval;
+        self.earliest_announce = now + min_interval.max(Self::MIN_REANNOUNCE_INTERVAL);
+
+        if self.local_ip.is_none() && !self.failed {
+            self.local_ip = self
+                .tracker_tiers
+                .iter()
+                .filter_map(|tier| tier.first())
+                .filter(|tracker| tracker.proxy.is_none())
+                .find_map(Tracker::local_ip);
+        }
+
+        let peers = merge_peers(&responses);
+        if !self.failed {
+            peer_manager.emit(DownloadEvent::Announced { peers: peers.len() });
+        }
+        let external_ips: Vec<IpAddr> = responses
+            .iter()
+            .filter_map(|response| response.external_ip)
+            .collect();
+        for socket_addr in &peers {
+            // Trackers may hand us back our own announce
+            if !is_valid_peer(socket_addr) || self.is_own_address(socket_addr, &external_ips) {
+                continue;
+            }
+            peer_manager.try_add(*socket_addr, context.clone());
+        }
+
+        AnnounceRound {
+            responses,
+            failures,
+            peers: peers.len(),
+            interval,
+            clamped,
+        }
+    }
+
+    /// Our port on loopback, on an address the trackers saw us at (`external_ips`) or on `local_ip`
+    fn is_own_address(&self, socket_addr: &SocketAddr, external_ips: &[IpAddr]) -> bool {
+        let ip = socket_addr.ip();
+        socket_addr.port() == self.port
+            && (ip.is_loopback() || external_ips.contains(&ip) || self.local_ip == Some(ip))
+    }
+
+    /// No more announces until `resume`, the trackers that know about us get a `stopped`
+    /// (there is no `paused` event) so they stop handing us out
+    pub fn pause(&mut self, peer_manager: &PeerManager) {
+        if let Some(event) = self.state.pause() {
+            let config = self.config(peer_manager, event, Some(0));
+            announce_tiers(
+                &mut self.tracker_tiers,
+                &config,
+                &self.limiter,
+                self.threads,
+                self.timeout,
+            );
+        }
+    }
+
+    /// Announces `started` again right away and goes back to the regular schedule
+    pub fn resume(&mut self, peer_manager: &PeerManager, context: &PeerContext) -> AnnounceRound {
+        self.state.resume();
+        self.announce(peer_manager, context)
+    }
+
+    /// Tells the trackers we stopped, giving them `timeout` to answer
+    pub fn stopped(&mut self, peer_manager: &PeerManager, timeout: Duration) {
+        let config = self.config(peer_manager, AnnounceEvent::Stopped, Some(0));
+        announce_tiers(
+            &mut self.tracker_tiers,
+            &config,
+            &self.limiter,
+            self.threads,
+            timeout,
+        );
+    }
+
+    /// Tells the trackers the download is complete, nothing to do if they fail
+    pub fn completed(&mut self, peer_manager: &PeerManager) {
+        let config = self.config(peer_manager, AnnounceEvent::Completed, Some(0));
+        announce_tiers(
+            &mut self.tracker_tiers,
+            &config,
+            &self.limiter,
+            self.threads,
+            self.timeout,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_context, TestTracker};
+    use std::sync::mpsc;
+    use url::Url;
+
+    fn announcer(url: Url, port: u16) -> Announcer {
+        let tiers = vec![vec![Tracker::new(url).unwrap()]];
+        Announcer::new(tiers, [7; 20], String::from("RB01-aaaaaaaaaaaaaaa"), port)
+    }
+
+    #[test]
+    fn hands_announced_peers_to_the_peer_manager() {
+        let tracker = TestTracker::spawn("127.0.0.1:0").unwrap();
+        let (events, received) = mpsc::channel();
+        let peer_manager = PeerManager::with_events(1, events);
+        let context = test_context(&peer_manager);
+
+        // Nothing listens on the other peer's port, it's dropped once tried
+        let mut other = announcer(tracker.announce_url(), 1);
+        assert_eq!(other.announce(&peer_manager, &context).peers, 0);
+
+        let mut ours = announcer(tracker.announce_url(), 2);
+        let round = ours.announce(&peer_manager, &context);
+        peer_manager.join();
+
+        assert!(!round.failed());
+        assert_eq!(round.peers, 1);
+        assert_eq!(round.interval, Duration::from_secs(60));
+        assert_eq!(
+            received.try_iter().next(),
+            Some(DownloadEvent::Announced { peers: 0 })
+        );
+        assert!(received
+            .try_iter()
+            .any(|event| event == DownloadEvent::Announced { peers: 1 }));
+        assert_eq!(
+            tracker.peers(&[7; 20]),
+            ["127.0.0.1:1", "127.0.0.1:2"]
+                .iter()
+                .map(|peer| peer.parse::<SocketAddr>().unwrap())
+                .collect()
+        );
+        // The peer is gone, but the trackers were just asked
+        assert!(!ours.is_due(&peer_manager));
+    }
+
+    #[test]
+    fn spaces_announces_to_the_same_tracker() {
+        let tracker = TestTracker::spawn("127.0.0.1:0").unwrap();
+        let peer_manager = PeerManager::new(1);
+        let context = test_context(&peer_manager);
+        let mut announcer = announcer(tracker.announce_url(), 6881);
+        let spacing = Duration::from_millis(300);
+        announcer.limiter = Arc::new(AnnounceLimiter::new(1, spacing));
+
+        let started = Instant::now();
+        assert!(!announcer.announce(&peer_manager, &context).failed());
+        assert!(started.elapsed() < spacing);
+
+        // Waits for the spacing instead of dropping the round, and so does the stopped event
+        assert!(!announcer.announce(&peer_manager, &context).failed());
+        assert!(started.elapsed() >= spacing);
+        announcer.stopped(&peer_manager, Duration::from_secs(5));
+        assert!(started.elapsed() >= spacing * 2);
+        assert!(tracker.peers(&[7; 20]).is_empty());
+    }
+
+    #[test]
+    fn pause_and_resume_send_stopped_then_started() {
+        let tracker = TestTracker::spawn("127.0.0.1:0").unwrap();
+        let peer_manager = PeerManager::new(1);
+        let context = test_context(&peer_manager);
+        let mut announcer = announcer(tracker.announce_url(), 6881);
+        announcer.limiter = Arc::new(AnnounceLimiter::new(1, Duration::ZERO));
+
+        announcer.announce(&peer_manager, &context);
+        announcer.announce(&peer_manager, &context);
+        announcer.pause(&peer_manager);
+        assert!(tracker.peers(&[7; 20]).is_empty());
+        assert!(!announcer.is_due(&peer_manager));
+        // Already told
+        announcer.pause(&peer_manager);
+
+        assert!(!announcer.resume(&peer_manager, &context).failed());
+        assert_eq!(
+            tracker.events(&[7; 20]),
+            ["started", "", "stopped", "started"]
+        );
+        assert_eq!(tracker.peers(&[7; 20]).len(), 1);
+    }
+
+    #[test]
+    fn skips_our_own_address() {
+        let mut announcer = announcer(Url::parse("http://127.0.0.1:1/announce").unwrap(), 6881);
+        announcer.local_ip = Some("192.168.1.5".parse().unwrap());
+        let external_ips = ["203.0.113.7".parse().unwrap()];
+        let own = |addr: &str| announcer.is_own_address(&addr.parse().unwrap(), &external_ips);
+
+        // Loopback
+        assert!(own("127.0.0.1:6881"));
+        assert!(own("[::1]:6881"));
+        // The tracker's `external ip`
+        assert!(own("203.0.113.7:6881"));
+        // Where we announce from
+        assert!(own("192.168.1.5:6881"));
+
+        // Other clients on the same hosts
+        assert!(!own("127.0.0.1:6882"));
+        assert!(!own("203.0.113.7:6882"));
+        assert!(!own("192.168.1.5:51413"));
+        assert!(!own("198.51.100.1:6881"));
+    }
+
+    #[test]
+    fn finds_the_local_address_of_answering_trackers() {
+        let tracker = TestTracker::spawn("127.0.0.1:0").unwrap();
+        let peer_manager = PeerManager::new(1);
+        let context = test_context(&peer_manager);
+        let mut announcer = announcer(tracker.announce_url(), 6881);
+        assert_eq!(announcer.local_ip, None);
+
+        announcer.announce(&peer_manager, &context);
+
+        assert_eq!(announcer.local_ip, Some("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn retries_failed_rounds_later() {
+        let peer_manager = PeerManager::new(1);
+        let context = test_context(&peer_manager);
+        // Nothing listens there
+        let mut announcer = announcer(Url::parse("http://127.0.0.1:1/announce").unwrap(), 6881);
+        assert!(announcer.is_due(&peer_manager));
+
+        let round = announcer.announce(&peer_manager, &context);
+
+        assert!(round.failed());
+        assert_eq!(round.interval, Announcer::TRACKER_RETRY_INTERVAL);
+        assert!(!announcer.is_due(&peer_manager));
+    }
+}
@@ -0,0 +1,146 @@
+use std::{fs, io, net::IpAddr, path::Path};
+
+/// Blocked IP ranges, sorted and merged so a lookup is a binary search.
+/// IPv4 ranges live in the IPv4-mapped part of the IPv6 space
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    ranges: Vec<(u128, u128)>,
+    /// Lines that were neither a range, a comment nor empty
+    pub skipped: usize,
+}
+
+impl Blocklist {
+    /// Reads CIDR (`1.2.3.0/24`), plain ranges (`1.2.3.0-1.2.3.255`), single IPs and
+    /// PeerGuardian p2p lines (`Some description:1.2.3.0-1.2.3.255`).
+    /// `#` and `//` start comments
+    pub fn parse(text: &str) -> Self {
+        let mut blocklist = Self::default();
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_range(line) {
+                Some(range) => blocklist.ranges.push(range),
+                None => blocklist.skipped += 1,
+            }
+        }
+
+        blocklist.ranges.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(blocklist.ranges.len());
+        for (start, end) in blocklist.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        blocklist.ranges = merged;
+
+        blocklist
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = to_u128(ip.to_canonical());
+        let position = self.ranges.partition_point(|(start, _)| *start <= ip);
+        position > 0 && ip <= self.ranges[position - 1].1
+    }
+
+    /// Number of merged ranges
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn parse_range(line: &str) -> Option<(u128, u128)> {
+    // p2p format, the description itself may contain ':'
+    let range = match line.rsplit_once(':') {
+        Some((_, range)) if range.contains('-') && !range.contains("::") => range,
+        _ => line,
+    };
+
+    if let Some((start, end)) = range.split_once('-') {
+        let start: IpAddr = start.trim().parse().ok()?;
+        let end: IpAddr = end.trim().parse().ok()?;
+        if start.is_ipv4() != end.is_ipv4() {
+            return None;
+        }
+        let (start, end) = (to_u128(start), to_u128(end));
+        return (start <= end).then_some((start, end));
+    }
+
+    if let Some((ip, prefix)) = range.split_once('/') {
+        let ip: IpAddr = ip.trim().parse().ok()?;
+        let prefix: u32 = prefix.trim().parse().ok()?;
+        // IPv4 prefixes count from the start of the mapped address
+        let (prefix, max) = match ip {
+            IpAddr::V4(_) => (prefix + 96, 32 + 96),
+            IpAddr::V6(_) => (prefix, 128),
+        };
+        if prefix > max {
+            return None;
+        }
+        let host_bits = 128 - prefix;
+        let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+        let start = to_u128(ip) & mask;
+        return Some((start, start | !mask));
+    }
+
+    let ip: IpAddr = range.parse().ok()?;
+    Some((to_u128(ip), to_u128(ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn v4(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+    }
+
+    #[test]
+    fn blocks_cidr_and_p2p_ranges() {
+        let blocklist = Blocklist::parse(
+            "# comment\n\
+             10.0.0.0/8\n\
+             Some Anti-P2P Corp: offices:192.168.1.10-192.168.1.20\n\
+             192.168.1.21-192.168.1.30\n\
+             203.0.113.7\n\
+             2001:db8::/32\n\
+             not a range\n",
+        );
+
+        assert_eq!(blocklist.skipped, 1);
+        // the two adjacent 192.168.1.x ranges are merged
+        assert_eq!(blocklist.len(), 4);
+
+        assert!(blocklist.contains(&v4(10, 200, 3, 4)));
+        assert!(blocklist.contains(&v4(192, 168, 1, 10)));
+        assert!(blocklist.contains(&v4(192, 168, 1, 30)));
+        assert!(blocklist.contains(&v4(203, 0, 113, 7)));
+        assert!(blocklist.contains(&"2001:db8::1".parse().unwrap()));
+        // IPv4-mapped IPv6 peers are matched against the IPv4 ranges
+        assert!(blocklist.contains(&IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped())));
+
+        assert!(!blocklist.contains(&v4(11, 0, 0, 0)));
+        assert!(!blocklist.contains(&v4(192, 168, 1, 9)));
+        assert!(!blocklist.contains(&v4(192, 168, 1, 31)));
+        assert!(!blocklist.contains(&v4(203, 0, 113, 8)));
+        assert!(!blocklist.contains(&IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    }
+}
@@ -1,22 +1,34 @@
 use std::io::{Seek, SeekFrom};
 use std::{
     collections::{HashSet, VecDeque},
-    fs::File,
     io::{self, Read, Write},
-    net::{SocketAddr, TcpStream},
-    sync::{Arc, Mutex},
-    time::Duration,
+    net::{IpAddr, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use rubit_bencode::TorrentFile;
 use sha1::{Digest, Sha1};
 
-use crate::{HandShake, Message};
+use crate::{
+    emit_event, Availability, Blame, CompletionTimes, Dht, DownloadEvent, HandShake, HashFailures,
+    HaveBroadcast, InProgress, Message, PeerStatsTable, PieceWriter, Proxy, TransferStats,
+};
 
 pub enum ConnError {
     Io(io::Error),
     TimeOut,
     EmptyQueue,
+    /// The peer sent too many pieces that failed their hash check
+    Banned,
+    /// `PeerManager::shutdown` was called
+    Shutdown,
+    /// Still choked after `PeerTimeouts::unchoke`
+    NeverUnchoked,
 }
 
 impl From<io::Error> for ConnError {
@@ -34,47 +46,219 @@ pub enum State {
     None,
 }
 
+/// Any backing store pieces can be written to (and read back from),
+/// e.g. a `File` or an in-memory `Cursor<Vec<u8>>`
+pub trait ReadWriteSeek: Read + Write + Seek + Send {}
+
+impl<T: Read + Write + Seek + Send> ReadWriteSeek for T {}
+
+/// Everything a peer connection shares with the rest of the download
+#[derive(Clone)]
+pub struct PeerContext {
+    pub global_queue: Arc<Mutex<VecDeque<usize>>>,
+    pub torrent_file: Arc<TorrentFile>,
+    pub peer_id: [u8; 20],
+    pub file: Arc<Mutex<dyn ReadWriteSeek>>,
+    pub availability: Arc<Availability>,
+    pub hash_failures: Arc<HashFailures>,
+    /// Pieces verified and written by the peer threads
+    pub verified: Arc<AtomicUsize>,
+    pub completions: Arc<CompletionTimes>,
+    pub peer_stats: Arc<PeerStatsTable>,
+    /// Completed pieces are announced to every connection through it
+    pub haves: Arc<HaveBroadcast>,
+    /// Keeps two connections from downloading the same piece outside endgame
+    /// and hands out the pieces still in progress once the queue is empty
+    pub in_progress: Arc<InProgress>,
+    /// Received and verified bytes for the announces
+    pub transfer: Arc<TransferStats>,
+    pub piece_order: PieceOrder,
+    pub timeouts: PeerTimeouts,
+    /// Peer connections are tunneled through it when set
+    pub proxy: Option<Proxy>,
+    /// Gets the DHT nodes peers announce with `Message::Port`
+    pub dht: Option<Arc<Dht>>,
+    pub events: Option<Sender<DownloadEvent>>,
+    /// Set to make the connection stop between blocks
+    pub shutdown: Arc<AtomicBool>,
+    /// Hand pieces to a writer thread (which may verify them) instead of
+    /// verifying and writing them on the peer thread
+    pub writer: Option<PieceWriter>,
+    pub verbose: bool,
+}
+
+/// How long a peer gets before it's dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerTimeouts {
+    pub connect: Duration,
+    /// Reads until the peer unchoked us or sent something else than its pieces
+    pub handshake: Duration,
+    /// Every read after that, choked peers get a keep-alive instead of being dropped
+    pub read: Duration,
+    /// Total time a choked peer gets to unchoke us before its slot goes to another peer
+    pub unchoke: Duration,
+}
+
+impl Default for PeerTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(2),
+            handshake: Duration::from_secs(2),
+            read: Duration::from_secs(10),
+            unchoke: Duration::from_secs(3 * 60),
+        }
+    }
+}
+
+/// Order pieces are handed out in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PieceOrder {
+    /// Queue order, the queue is shuffled for swarm health
+    #[default]
+    Random,
+    /// Lowest index first, so media can be played while downloading
+    Sequential,
+}
+
+/// Removes and returns a queued piece found in `peer_pieces` that no other
+/// connection is downloading, marking it in progress. In endgame, when nothing queued
+/// is left to start, a piece another connection is on is handed out so slow peers don't stall the end
+fn take_available_piece(
+    queue: &mut VecDeque<usize>,
+    peer_pieces: &HashSet<usize>,
+    in_progress: &InProgress,
+    order: PieceOrder,
+) -> Option<usize> {
+    // Positions of the pieces the peer has, in the order they're handed out
+    let candidates = || -> Box<dyn Iterator<Item = usize>> {
+        let has = (0..queue.len()).filter(|position| peer_pieces.contains(&queue[*position]));
+        match order {
+            PieceOrder::Random => Box::new(has),
+            PieceOrder::Sequential => {
+                let mut sorted: Vec<usize> = has.collect();
+                sorted.sort_by_key(|position| queue[*position]);
+                Box::new(sorted.into_iter())
+            }
+        }
+    };
+
+    // Also skips a piece another connection started since it was checked
+    let free = candidates().find(|position| in_progress.try_start(queue[*position]));
+
+    if let Some(position) = free {
+        return queue.remove(position);
+    }
+
+    if !queue.iter().all(|i| in_progress.contains(*i)) {
+        return None;
+    }
+    let piece_index = in_progress.join(peer_pieces)?;
+    // A copy re-queued while it was in progress isn't needed anymore
+    if let Some(position) = queue.iter().position(|i| *i == piece_index) {
+        queue.remove(position);
+    }
+    Some(piece_index)
+}
+
 pub struct PeerConnManager {
     my_state: State,
     state: State,
+    last_sent: Instant,
+    shutdown: Arc<AtomicBool>,
+    /// DHT and the peer's ip, for `Message::Port`
+    dht_node: Option<(Arc<Dht>, IpAddr)>,
+    /// Length prefix and body read so far of a message a read timeout cut off
+    partial: Vec<u8>,
+}
+
+impl Default for PeerConnManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PeerConnManager {
+    /// Peers usually drop connections after ~2 minutes without any message
+    const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+    /// Wrongly sized blocks tolerated for a single request before dropping the peer
+    const MAX_BAD_BLOCKS: u32 = 3;
+    /// Longest message accepted, a bitfield this long covers 8 million pieces
+    const MAX_MESSAGE_LEN: usize = 1 << 20;
+
     pub fn new() -> Self {
         Self {
             my_state: State::None,
             state: State::Choked,
+            last_sent: Instant::now(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            dht_node: None,
+            partial: Vec::new(),
         }
     }
 
     pub fn handle_peer(
         &mut self,
-        global_queue: Arc<Mutex<VecDeque<usize>>>,
         socket_addr: SocketAddr,
-        torrent_file: Arc<TorrentFile>,
-        peer_id: [u8; 20],
-        file: Arc<Mutex<File>>,
-        verbose: bool,
+        context: PeerContext,
     ) -> Result<(), ConnError> {
+        let PeerContext {
+            global_queue,
+            torrent_file,
+            peer_id,
+            file,
+            availability,
+            hash_failures,
+            verified,
+            completions,
+            peer_stats,
+            haves,
+            in_progress,
+            transfer,
+            piece_order,
+            timeouts,
+            proxy,
+            dht,
+            shutdown,
+            events,
+            writer,
+            verbose,
+        } = context;
+        self.shutdown = shutdown;
+        let handshake = HandShake::new(torrent_file.info_hash, peer_id);
+        let handshake = match dht {
+            Some(dht) => {
+                self.dht_node = Some((dht, socket_addr.ip()));
+                handshake.with_dht()
+            }
+            None => handshake,
+        };
+
         // connect or else remove address from peers HashSet
-        let Ok(mut stream) = TcpStream::connect_timeout(&socket_addr, Duration::from_secs(2))
-        else {
+        let stream = match &proxy {
+            Some(proxy) if proxy.applies_to(&socket_addr.ip().to_string()) => {
+                proxy.connect(socket_addr, timeouts.connect)
+            }
+            _ => TcpStream::connect_timeout(&socket_addr, timeouts.connect),
+        };
+        let Ok(mut stream) = stream else {
             return Err(ConnError::TimeOut);
         };
 
         // Handshake timeout
-        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        stream.set_read_timeout(Some(timeouts.handshake))?;
 
         if verbose {
             println!("connected to peer {}", socket_addr);
         }
+        emit_event(&events, DownloadEvent::PeerConnected { socket_addr });
+        let completed = haves.subscribe(socket_addr);
 
         let mut peer_pieces = HashSet::new();
 
         {
-            let handshake_bytes = HandShake::new(torrent_file.info_hash, peer_id).as_bytes()?;
+            let handshake_bytes = handshake.as_bytes()?;
 
-            stream.write(&handshake_bytes)?;
+            stream.write_all(&handshake_bytes)?;
 
             // Size of handshake = 68 bytes
             let mut handshake_buf = [0u8; 68];
@@ -91,67 +275,90 @@ impl PeerConnManager {
             }
 
             // listen until choke message
-            loop {
-                match self.read_stream(&mut stream) {
-                    Ok(buf) => match buf[0] {
-                        5 => {
-                            self.read_bitfield(buf, &mut peer_pieces);
-                        }
-                        4 => {
-                            self.read_have(buf, &mut peer_pieces);
-                        }
-                        1 => {
-                            self.state = State::UnChoked;
-                            break;
-                        }
-                        _ => break,
-                    },
-                    Err(_) => {
+            while let Ok(buf) = self.read_stream(&mut stream) {
+                match buf[0] {
+                    5 => {
+                        self.read_bitfield(buf, &mut peer_pieces);
+                    }
+                    4 => {
+                        self.read_have(buf, &mut peer_pieces)?;
+                    }
+                    9 => self.read_port(&buf),
+                    1 => {
+                        self.state = State::UnChoked;
                         break;
                     }
+                    _ => break,
                 }
             }
         }
 
-        // Normal timeout: 10Secs may be too long :/
-        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        availability.add(socket_addr, peer_pieces.iter().copied());
+        peer_stats.set_choked(socket_addr, self.state == State::Choked);
+
+        stream.set_read_timeout(Some(timeouts.read))?;
 
         loop {
+            if self.is_shutting_down() {
+                return Err(ConnError::Shutdown);
+            }
+            // Also catches bans from pieces the writer thread verified
+            if hash_failures.is_banned(&socket_addr) {
+                if verbose {
+                    println!("banning peer {} for sending bad pieces", socket_addr);
+                }
+                return Err(ConnError::Banned);
+            }
+            self.send_haves(&mut stream, &completed)?;
+
             if self.my_state == State::None {
-                stream.write(&Message::Interested.as_bytes()?)?;
+                self.send(&mut stream, Message::Interested)?;
                 self.my_state = State::Interested;
             }
 
             if self.state == State::Choked {
-                loop {
-                    let buf = self.read_stream(&mut stream)?;
-                    if verbose {
-                        println!("got unchoke!");
-                    }
-                    if buf[0] == 1 {
-                        self.state = State::UnChoked;
-                        break;
-                    }
-                }
+                self.wait_for_unchoke(
+                    &mut stream,
+                    &mut peer_pieces,
+                    &completed,
+                    |index| availability.add(socket_addr, [index]),
+                    timeouts.unchoke,
+                    verbose,
+                )?;
+                peer_stats.set_choked(socket_addr, false);
             }
 
             if self.state == State::UnChoked {
                 let mut queue = global_queue.lock().unwrap();
-                let piece_index = match queue.pop_front() {
-                    Some(i) => i,
-                    None => {
-                        if verbose {
-                            println!("empty queue! returing..");
-                        }
-                        stream.write(&Message::NotInterested.as_bytes()?)?;
-                        return Err(ConnError::EmptyQueue);
+                // Pieces other connections are still on are left to help with in endgame
+                if queue.is_empty() && in_progress.is_empty() {
+                    if verbose {
+                        println!("empty queue! returing..");
+                    }
+                    self.send(&mut stream, Message::NotInterested)?;
+                    return Err(ConnError::EmptyQueue);
+                }
+
+                // Take the first queued piece this peer has, if it has none of them
+                // wait for it to announce a new one instead of spinning on the queue
+                let Some(piece_index) =
+                    take_available_piece(&mut queue, &peer_pieces, &in_progress, piece_order)
+                else {
+                    std::mem::drop(queue);
+                    self.wait_for_piece(&mut stream, &mut peer_pieces, |index| {
+                        availability.add(socket_addr, [index])
+                    })?;
+                    if self.state == State::Choked {
+                        peer_stats.set_choked(socket_addr, true);
                     }
+                    continue;
                 };
 
-                if !peer_pieces.contains(&piece_index) {
-                    queue.push_back(piece_index);
+                // Bad index from the queue, drop it instead of panicking
+                let Some(expected_hash) = torrent_file.piece_hash(piece_index) else {
+                    in_progress.finish(piece_index);
                     continue;
-                }
+                };
 
                 peer_pieces.remove(&piece_index);
 
@@ -159,91 +366,339 @@ impl PeerConnManager {
                 std::mem::drop(queue);
 
                 let piece_len = if piece_index == torrent_file.info.pieces.len() - 1
-                    && torrent_file.info.length % torrent_file.info.piece_length != 0
+                    && !torrent_file
+                        .info
+                        .length
+                        .is_multiple_of(torrent_file.info.piece_length)
                 {
                     (torrent_file.info.length % torrent_file.info.piece_length) as usize
                 } else {
                     torrent_file.info.piece_length as usize
                 };
 
-                let block_len = match piece_len {
-                    n if n < 16384 => piece_len,
-                    _ => 16384,
-                };
+                let started = Instant::now();
+                let downloaded = self.download_piece(
+                    &mut stream,
+                    piece_index,
+                    piece_len,
+                    &mut peer_pieces,
+                    |index| availability.add(socket_addr, [index]),
+                    verbose,
+                );
 
-                let num_blocks = if piece_len % block_len == 0 {
-                    (piece_len / block_len) as usize
-                } else {
-                    (piece_len as f64 / block_len as f64).ceil() as usize
+                let buf = match downloaded {
+                    Ok(Some(buf)) => buf,
+                    // Choked mid-piece, stop requesting and wait for an unchoke
+                    Ok(None) => {
+                        peer_stats.set_choked(socket_addr, true);
+                        self.give_up_piece(
+                            &global_queue,
+                            &in_progress,
+                            &mut peer_pieces,
+                            piece_index,
+                        );
+                        continue;
+                    }
+                    // Don't lose the piece when the connection drops
+                    Err(e) => {
+                        self.give_up_piece(
+                            &global_queue,
+                            &in_progress,
+                            &mut peer_pieces,
+                            piece_index,
+                        );
+                        return Err(e.into());
+                    }
                 };
+                peer_stats.add_piece(socket_addr, buf.len() as u64, started.elapsed());
+                transfer.add_downloaded(buf.len() as u64);
 
-                let mut buf: Vec<u8> = Vec::new();
-                let mut hasher = Sha1::new();
-
-                for i in 0..num_blocks {
-                    let len = if i == num_blocks - 1 && piece_len % block_len != 0 {
-                        piece_len % block_len
-                    } else {
-                        block_len
-                    };
-
-                    stream.write(
-                        &Message::Request {
-                            index: piece_index as u32,
-                            begin: (i * block_len) as u32,
-                            length: len as u32,
-                        }
-                        .as_bytes()?,
-                    )?;
-                    loop {
-                        let block = self.read_stream(&mut stream)?;
-                        if block[0] == 7 {
-                            buf.write_all(&block[9..])?;
-                            hasher.update(&block[9..]);
-                            if verbose {
-                                println!("got block {} from {}", i, socket_addr);
-                            }
-                            break;
-                        } else if block[0] == 0 {
-                            self.state = State::Choked;
-                            self.push_back_to_queue(&global_queue, &mut peer_pieces, piece_index);
-                            break;
+                // The writer thread verifies the piece itself, and drops endgame duplicates
+                if let Some(writer) = writer.as_ref().filter(|w| w.verifies()) {
+                    in_progress.finish(piece_index);
+                    writer.submit(piece_index, buf, socket_addr);
+                    // The writer re-queues the piece if it fails, this peer may fetch it again
+                    // like after a failure verified here (see `give_up_piece`)
+                    peer_pieces.insert(piece_index);
+                    continue;
+                }
+
+                let hash: [u8; 20] = Sha1::digest(&buf).into();
+
+                if verbose {
+                    println!("recivied hash: {:?}", hash);
+                    println!("original hash: {:?}", expected_hash);
+                }
+
+                if *expected_hash != hash {
+                    self.give_up_piece(&global_queue, &in_progress, &mut peer_pieces, piece_index);
+                    if hash_failures.record(piece_index, socket_addr) == Blame::Peer
+                        && hash_failures.is_banned(&socket_addr)
+                    {
+                        if verbose {
+                            println!("banning peer {} for sending bad pieces", socket_addr);
                         }
+                        return Err(ConnError::Banned);
                     }
+                    continue;
+                }
+
+                if let Some(writer) = &writer {
+                    in_progress.finish(piece_index);
+                    writer.submit(piece_index, buf, socket_addr);
+                    peer_pieces.insert(piece_index);
+                    continue;
                 }
 
-                let hash: [u8; 20] = hasher.finalize().into();
+                // Another connection got it first in endgame
+                if !in_progress.complete(piece_index) {
+                    continue;
+                }
+                self.write_piece(
+                    &file,
+                    piece_index as u64 * torrent_file.info.piece_length,
+                    &buf,
+                )?;
+                verified.fetch_add(1, Ordering::Relaxed);
+                transfer.piece_verified(buf.len() as u64);
+                completions.record(piece_index);
+                haves.broadcast(piece_index);
+                emit_event(
+                    &events,
+                    DownloadEvent::PieceCompleted { index: piece_index },
+                );
 
                 if verbose {
-                    println!("recivied hash: {:?}", hash);
-                    println!("original hash: {:?}", torrent_file.info.pieces[piece_index]);
+                    println!("wrote piece {} to disk!", piece_index);
+                }
+            }
+        }
+    }
+
+    /// Waits up to `deadline` for an unchoke, keeping the connection alive meanwhile
+    /// so a busy seed that chokes us for a while doesn't drop us (or we it)
+    fn wait_for_unchoke(
+        &mut self,
+        stream: &mut (impl Read + Write),
+        peer_pieces: &mut HashSet<usize>,
+        completed: &Receiver<usize>,
+        mut on_have: impl FnMut(usize),
+        deadline: Duration,
+        verbose: bool,
+    ) -> Result<(), ConnError> {
+        let started = Instant::now();
+        loop {
+            if self.is_shutting_down() {
+                return Err(ConnError::Shutdown);
+            }
+            if started.elapsed() > deadline {
+                return Err(ConnError::NeverUnchoked);
+            }
+            self.send_haves(stream, completed)?;
+
+            let buf = match self.read_stream(stream) {
+                Ok(buf) => buf,
+                // Nothing received yet
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    self.keep_alive(stream)?;
+                    continue;
                 }
+                Err(e) => return Err(e.into()),
+            };
 
-                if torrent_file.info.pieces[piece_index] == hash {
-                    let mut file = file.lock().unwrap();
-                    file.seek(SeekFrom::Start(
-                        piece_index as u64 * torrent_file.info.piece_length as u64,
-                    ))?;
-                    file.write(&buf)?;
+            match buf[0] {
+                1 => {
+                    if verbose {
+                        println!("got unchoke!");
+                    }
+                    self.state = State::UnChoked;
+                    return Ok(());
+                }
+                4 => on_have(self.read_have(buf, peer_pieces)?),
+                9 => self.read_port(&buf),
+                _ => {}
+            }
+            // A chatty peer never lets the read time out
+            self.keep_alive(stream)?;
+        }
+    }
 
-                    std::mem::drop(file);
+    /// Reads one message while the peer has none of the queued pieces,
+    /// a `Have` may give it one. Keeps the connection alive when nothing arrives
+    fn wait_for_piece(
+        &mut self,
+        stream: &mut (impl Read + Write),
+        peer_pieces: &mut HashSet<usize>,
+        mut on_have: impl FnMut(usize),
+    ) -> io::Result<()> {
+        match self.read_stream(stream) {
+            Ok(buf) if buf[0] == 4 => on_have(self.read_have(buf, peer_pieces)?),
+            Ok(buf) if buf[0] == 0 => self.state = State::Choked,
+            Ok(buf) if buf[0] == 9 => self.read_port(&buf),
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                self.keep_alive(stream)?;
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Requests `piece_len` bytes of `piece_index` block by block,
+    /// returns `None` as soon as the peer chokes us so no more requests are sent
+    fn download_piece(
+        &mut self,
+        stream: &mut (impl Read + Write),
+        piece_index: usize,
+        piece_len: usize,
+        peer_pieces: &mut HashSet<usize>,
+        mut on_have: impl FnMut(usize),
+        verbose: bool,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let block_len = match piece_len {
+            n if n < 16384 => piece_len,
+            _ => 16384,
+        };
+
+        let num_blocks = piece_len.div_ceil(block_len);
+
+        let mut buf: Vec<u8> = Vec::with_capacity(piece_len);
+
+        for i in 0..num_blocks {
+            if self.is_shutting_down() {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "shutting down"));
+            }
+
+            let len = if i == num_blocks - 1 && !piece_len.is_multiple_of(block_len) {
+                piece_len % block_len
+            } else {
+                block_len
+            };
+
+            let request = Message::Request {
+                index: piece_index as u32,
+                begin: (i * block_len) as u32,
+                length: len as u32,
+            };
+            self.send(stream, request.clone())?;
+
+            let mut bad_blocks = 0;
+            loop {
+                let block = self.read_stream(stream)?;
+                if block[0] == 7 {
+                    // A late duplicate of an earlier block or one we never asked for,
+                    // the request is still pending so just wait for the right one
+                    if block.len() >= 9 && !Self::block_matches(&block, &request) {
+                        bad_blocks += 1;
+                        if bad_blocks >= Self::MAX_BAD_BLOCKS {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "peer keeps sending blocks we didn't request",
+                            ));
+                        }
+                        continue;
+                    }
+
+                    // id + index + begin = 9 bytes before the block itself,
+                    // anything else than what we asked for would corrupt the piece
+                    if block.len() < 9 || block.len() - 9 != len {
+                        bad_blocks += 1;
+                        if bad_blocks >= Self::MAX_BAD_BLOCKS {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "peer keeps sending blocks of the wrong size",
+                            ));
+                        }
+                        self.send(stream, request.clone())?;
+                        continue;
+                    }
 
+                    buf.write_all(&block[9..])?;
                     if verbose {
-                        println!("wrote piece {} to disk!", piece_index);
+                        println!("got block {} of piece {}", i, piece_index);
                     }
-                } else {
-                    self.push_back_to_queue(&global_queue, &mut peer_pieces, piece_index);
+                    break;
+                } else if block[0] == 0 {
+                    self.state = State::Choked;
+                    return Ok(None);
+                } else if block[0] == 4 {
+                    on_have(self.read_have(block, peer_pieces)?);
                 }
             }
         }
+
+        Ok(Some(buf))
+    }
+
+    /// Whether a Piece message (id included) has the index and begin of `request`
+    fn block_matches(block: &[u8], request: &Message) -> bool {
+        let Message::Request { index, begin, .. } = request else {
+            return false;
+        };
+        block[1..5] == index.to_be_bytes() && block[5..9] == begin.to_be_bytes()
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    fn write_piece(
+        &self,
+        file: &Mutex<dyn ReadWriteSeek>,
+        offset: u64,
+        buf: &[u8],
+    ) -> io::Result<()> {
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(buf)
+    }
+
+    fn send(&mut self, stream: &mut impl Write, message: Message) -> io::Result<()> {
+        stream.write_all(&message.as_bytes()?)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Tells the peer about every piece completed since the last call
+    fn send_haves(
+        &mut self,
+        stream: &mut impl Write,
+        completed: &Receiver<usize>,
+    ) -> io::Result<()> {
+        for index in completed.try_iter() {
+            self.send(
+                stream,
+                Message::Have {
+                    index: index as u32,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn keep_alive(&mut self, stream: &mut impl Write) -> io::Result<()> {
+        if self.last_sent.elapsed() >= Self::KEEP_ALIVE_INTERVAL {
+            self.send(stream, Message::KeepAlive)?;
+        }
+        Ok(())
     }
 
     fn read_bitfield(&self, buf: Vec<u8>, peer_pieces: &mut HashSet<usize>) {
         let mut pointer = 0usize;
-        for index in 1..buf.len() {
+        for byte in buf.iter().skip(1) {
             for bit in 0..8 {
-                let mask = 255 >> bit;
-                let bit_is_set = (mask & buf[index]) > 0;
+                let mask = 0b1000_0000 >> bit;
+                let bit_is_set = (mask & byte) > 0;
                 if bit_is_set {
                     peer_pieces.insert(pointer);
                 }
@@ -252,50 +707,907 @@ impl PeerConnManager {
         }
     }
 
-    fn read_have(&self, buf: Vec<u8>, peer_pieces: &mut HashSet<usize>) {
-        peer_pieces.insert(u32::from_be_bytes(buf[1..5].try_into().unwrap()) as usize);
+    /// Errors on a `Have` too short to hold a piece index
+    fn read_have(&self, buf: Vec<u8>, peer_pieces: &mut HashSet<usize>) -> io::Result<usize> {
+        let Some(index) = buf.get(1..5) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer sent a truncated have",
+            ));
+        };
+        let index = u32::from_be_bytes(index.try_into().unwrap()) as usize;
+        peer_pieces.insert(index);
+        Ok(index)
     }
 
-    fn read_stream(&self, stream: &mut impl Read) -> io::Result<Vec<u8>> {
-        #[allow(unused_assignments)]
-        let mut len_prefix2 = [0; 4];
+    /// Adds the peer's DHT node, if we run one
+    fn read_port(&self, buf: &[u8]) {
+        let (Some((dht, ip)), Some(port)) = (&self.dht_node, buf.get(1..3)) else {
+            return;
+        };
+        dht.add_node(SocketAddr::new(*ip, u16::from_be_bytes([port[0], port[1]])));
+    }
 
+    /// Next message (its id first), skipping keep-alives. When a read times out
+    /// halfway through a message the bytes read so far are kept, so the next call
+    /// continues it instead of reading its body as a length prefix
+    fn read_stream(&mut self, stream: &mut impl Read) -> io::Result<Vec<u8>> {
         loop {
-            let mut len_prefix = [0; 4];
-            stream.read_exact(&mut len_prefix)?;
-            if len_prefix.len() > 0 && len_prefix.len() == 4 && u32::from_be_bytes(len_prefix) != 0
-            {
-                len_prefix2 = len_prefix;
-                break;
-            }
-        }
-        let num = u32::from_be_bytes(len_prefix2) as usize;
-
-        if num == 0 {
-            return Ok(vec![9]);
-        }
-
-        let mut buf = Vec::new();
-        buf.resize(num as usize, 0);
+            let wanted = match self.partial.get(..4) {
+                Some(prefix) => match u32::from_be_bytes(prefix.try_into().unwrap()) as usize {
+                    // Keep-alive
+                    0 => {
+                        self.partial.clear();
+                        continue;
+                    }
+                    len if len > Self::MAX_MESSAGE_LEN => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "peer sent an oversized message",
+                        ))
+                    }
+                    len if self.partial.len() == 4 + len => {
+                        let message = self.partial.split_off(4);
+                        self.partial.clear();
+                        return Ok(message);
+                    }
+                    len => 4 + len,
+                },
+                None => 4,
+            };
 
-        loop {
-            if buf.len() > 0 && buf.len() >= num {
-                stream.read_exact(&mut buf)?;
-                break;
+            let read_so_far = self.partial.len();
+            self.partial.resize(wanted, 0);
+            let read = match stream.read(&mut self.partial[read_so_far..]) {
+                Ok(read) => read,
+                Err(e) => {
+                    self.partial.truncate(read_so_far);
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+            self.partial.truncate(read_so_far + read);
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
             }
         }
-        Ok(buf)
     }
 
-    fn push_back_to_queue(
+    /// Puts a piece that failed back in the queue, unless in endgame
+    /// another connection is still downloading it or completed it
+    fn give_up_piece(
         &self,
         queue: &Arc<Mutex<VecDeque<usize>>>,
+        in_progress: &InProgress,
         peer_pieces: &mut HashSet<usize>,
         value: usize,
     ) {
         let mut queue = queue.lock().unwrap();
-        queue.push_back(value);
+        if in_progress.abandon(value) {
+            queue.push_back(value);
+        }
         peer_pieces.insert(value);
         std::mem::drop(queue);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_bitfield_message, PieceResult};
+    use std::{io::Cursor, thread};
+
+    /// Replays `input` as the peer and records everything we send
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stops_requesting_when_choked_mid_piece() {
+        let mut input = Message::Piece {
+            index: 0,
+            begin: 0,
+            piece: vec![1; 16384],
+        }
+        .as_bytes()
+        .unwrap();
+        input.extend(Message::Choke.as_bytes().unwrap());
+
+        let mut stream = MockStream {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        let mut conn = PeerConnManager::new();
+        conn.state = State::UnChoked;
+
+        // 3 blocks piece, choked after the first block
+        let piece = conn
+            .download_piece(
+                &mut stream,
+                0,
+                3 * 16384,
+                &mut HashSet::new(),
+                |_| (),
+                false,
+            )
+            .unwrap();
+
+        assert!(piece.is_none());
+        assert!(conn.state == State::Choked);
+
+        let mut expected = Vec::new();
+        for begin in [0, 16384] {
+            expected.extend(
+                Message::Request {
+                    index: 0,
+                    begin,
+                    length: 16384,
+                }
+                .as_bytes()
+                .unwrap(),
+            );
+        }
+        assert_eq!(stream.output, expected);
+    }
+
+    #[test]
+    fn re_requests_oversized_blocks() {
+        let mut input = Message::Piece {
+            index: 0,
+            begin: 0,
+            piece: vec![6; 20],
+        }
+        .as_bytes()
+        .unwrap();
+        input.extend(
+            Message::Piece {
+                index: 0,
+                begin: 0,
+                piece: vec![1; 16],
+            }
+            .as_bytes()
+            .unwrap(),
+        );
+
+        let mut stream = MockStream {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        let mut conn = PeerConnManager::new();
+
+        let piece = conn
+            .download_piece(&mut stream, 0, 16, &mut HashSet::new(), |_| (), false)
+            .unwrap();
+
+        assert_eq!(piece, Some(vec![1; 16]));
+
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 16,
+        }
+        .as_bytes()
+        .unwrap();
+        assert_eq!(stream.output, [request.clone(), request].concat());
+    }
+
+    #[test]
+    fn ignores_blocks_that_were_not_requested() {
+        let block = |index, begin, byte| {
+            Message::Piece {
+                index,
+                begin,
+                piece: vec![byte; 16],
+            }
+            .as_bytes()
+            .unwrap()
+        };
+        // Wrong piece, wrong offset, then the requested block
+        let input = [block(1, 0, 6), block(0, 16, 7), block(0, 0, 1)].concat();
+
+        let mut stream = MockStream {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        let mut conn = PeerConnManager::new();
+
+        let piece = conn
+            .download_piece(&mut stream, 0, 16, &mut HashSet::new(), |_| (), false)
+            .unwrap();
+
+        assert_eq!(piece, Some(vec![1; 16]));
+        // Still pending, so not requested again
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 16,
+        };
+        assert_eq!(stream.output, request.as_bytes().unwrap());
+
+        // A peer that never sends the right block is dropped
+        let input = [block(1, 0, 6), block(1, 0, 6), block(1, 0, 6)].concat();
+        let mut stream = MockStream {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        let result = conn.download_piece(&mut stream, 0, 16, &mut HashSet::new(), |_| (), false);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn writes_pieces_into_in_memory_sink() {
+        let sink = Arc::new(Mutex::new(Cursor::new(Vec::new())));
+        let file: Arc<Mutex<dyn ReadWriteSeek>> = sink.clone();
+        let conn = PeerConnManager::new();
+
+        conn.write_piece(&file, 4, &[5, 6, 7, 8]).unwrap();
+        conn.write_piece(&file, 0, &[1, 2, 3, 4]).unwrap();
+
+        assert_eq!(
+            sink.lock().unwrap().get_ref(),
+            &vec![1, 2, 3, 4, 5, 6, 7, 8]
+        );
+    }
+
+    #[test]
+    fn bitfield_message_round_trips_through_read_bitfield() {
+        let completed = HashSet::from([0, 3, 7, 8, 12]);
+        let bytes = build_bitfield_message(&completed, 13).as_bytes().unwrap();
+
+        // 4 bytes length prefix + 1 byte id + 2 bytes for 13 pieces
+        assert_eq!(bytes.len(), 7);
+        assert_eq!(bytes[..5], [0, 0, 0, 3, 5]);
+
+        let mut peer_pieces = HashSet::new();
+        PeerConnManager::new().read_bitfield(bytes[4..].to_vec(), &mut peer_pieces);
+
+        assert_eq!(peer_pieces, completed);
+    }
+
+    #[test]
+    fn takes_only_pieces_the_peer_has() {
+        let mut queue = VecDeque::from([1, 2, 3]);
+
+        let in_progress = InProgress::default();
+
+        assert_eq!(
+            take_available_piece(
+                &mut queue,
+                &HashSet::from([3]),
+                &in_progress,
+                PieceOrder::Random
+            ),
+            Some(3)
+        );
+        assert_eq!(queue, VecDeque::from([1, 2]));
+
+        assert_eq!(
+            take_available_piece(
+                &mut queue,
+                &HashSet::from([7]),
+                &in_progress,
+                PieceOrder::Random
+            ),
+            None
+        );
+        assert_eq!(queue, VecDeque::from([1, 2]));
+    }
+
+    #[test]
+    fn sends_have_for_completed_pieces() {
+        let haves = HaveBroadcast::default();
+        let completed = haves.subscribe("127.0.0.1:6881".parse().unwrap());
+        haves.broadcast(2);
+        haves.broadcast(5);
+
+        let mut stream = MockStream {
+            input: Cursor::new(Vec::new()),
+            output: Vec::new(),
+        };
+        let mut conn = PeerConnManager::new();
+        conn.send_haves(&mut stream, &completed).unwrap();
+
+        let expected = [
+            Message::Have { index: 2 }.as_bytes().unwrap(),
+            Message::Have { index: 5 }.as_bytes().unwrap(),
+        ]
+        .concat();
+        assert_eq!(stream.output, expected);
+    }
+
+    /// Read times out `timeouts` times before replaying `input`
+    struct SlowPeer {
+        timeouts: usize,
+        stream: MockStream,
+    }
+
+    impl Read for SlowPeer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.timeouts > 0 {
+                self.timeouts -= 1;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.stream.read(buf)
+        }
+    }
+
+    impl Write for SlowPeer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.stream.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Times out before every read and hands out at most `chunk` bytes at a time,
+    /// so messages arrive cut between length prefix and body
+    struct ChoppyPeer {
+        chunk: usize,
+        timed_out: bool,
+        stream: MockStream,
+    }
+
+    impl ChoppyPeer {
+        fn new(chunk: usize, input: Vec<u8>) -> Self {
+            Self {
+                chunk,
+                timed_out: false,
+                stream: MockStream {
+                    input: Cursor::new(input),
+                    output: Vec::new(),
+                },
+            }
+        }
+    }
+
+    impl Read for ChoppyPeer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.timed_out = !self.timed_out;
+            if self.timed_out {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let len = buf.len().min(self.chunk);
+            self.stream.read(&mut buf[..len])
+        }
+    }
+
+    impl Write for ChoppyPeer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.stream.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn keeps_messages_cut_off_by_read_timeouts() {
+        let mut input = Message::KeepAlive.as_bytes().unwrap();
+        input.extend(Message::Have { index: 7 }.as_bytes().unwrap());
+        input.extend(Message::UnChoke.as_bytes().unwrap());
+        let mut peer = ChoppyPeer::new(3, input);
+        let mut conn = PeerConnManager::new();
+
+        let mut messages = Vec::new();
+        while messages.len() < 2 {
+            match conn.read_stream(&mut peer) {
+                Ok(message) => messages.push(message),
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            }
+        }
+
+        assert_eq!(messages, vec![vec![4, 0, 0, 0, 7], vec![1]]);
+        assert!(conn.partial.is_empty());
+        assert_eq!(
+            conn.read_stream(&mut peer).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+        assert_eq!(
+            conn.read_stream(&mut peer).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let mut oversized = MockStream {
+            input: Cursor::new(u32::MAX.to_be_bytes().to_vec()),
+            output: Vec::new(),
+        };
+        assert_eq!(
+            conn.read_stream(&mut oversized).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn waits_for_a_piece_across_read_timeouts() {
+        let mut input = Message::Have { index: 3 }.as_bytes().unwrap();
+        input.extend(Message::Choke.as_bytes().unwrap());
+        let mut peer = ChoppyPeer::new(2, input);
+        let mut conn = PeerConnManager::new();
+        conn.state = State::UnChoked;
+        let mut peer_pieces = HashSet::new();
+        let mut announced = Vec::new();
+
+        while conn.state == State::UnChoked {
+            conn.wait_for_piece(&mut peer, &mut peer_pieces, |index| announced.push(index))
+                .unwrap();
+        }
+
+        assert_eq!(announced, vec![3]);
+        assert_eq!(peer_pieces, HashSet::from([3]));
+        assert!(peer.stream.output.is_empty());
+    }
+
+    #[test]
+    fn truncated_haves_are_errors() {
+        // A Have with a 1 byte index
+        let mut peer = ChoppyPeer::new(8, vec![0, 0, 0, 2, 4, 3]);
+        let mut conn = PeerConnManager::new();
+        conn.state = State::UnChoked;
+        let mut peer_pieces = HashSet::new();
+
+        let error = loop {
+            if let Err(e) = conn.wait_for_piece(&mut peer, &mut peer_pieces, |_| {}) {
+                break e;
+            }
+        };
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(peer_pieces.is_empty());
+    }
+
+    #[test]
+    fn keeps_choked_connection_alive_until_unchoke() {
+        let mut input = Message::Have { index: 1 }.as_bytes().unwrap();
+        input.extend(Message::Have { index: 2 }.as_bytes().unwrap());
+        input.extend(Message::UnChoke.as_bytes().unwrap());
+        let mut peer = SlowPeer {
+            timeouts: 3,
+            stream: MockStream {
+                input: Cursor::new(input),
+                output: Vec::new(),
+            },
+        };
+
+        let mut conn = PeerConnManager::new();
+        let (_haves, completed) = std::sync::mpsc::channel();
+        let mut peer_pieces = HashSet::new();
+        let mut announced = Vec::new();
+
+        // Choked for longer than the keep-alive interval
+        conn.last_sent = Instant::now() - PeerConnManager::KEEP_ALIVE_INTERVAL;
+        let waited = conn.wait_for_unchoke(
+            &mut peer,
+            &mut peer_pieces,
+            &completed,
+            |index| announced.push(index),
+            PeerTimeouts::default().unchoke,
+            false,
+        );
+
+        assert!(waited.is_ok());
+        assert!(conn.state == State::UnChoked);
+        assert_eq!(announced, vec![1, 2]);
+        assert_eq!(peer_pieces, HashSet::from([1, 2]));
+        // Sent once the interval passed, not on every timeout
+        assert_eq!(peer.stream.output, Message::KeepAlive.as_bytes().unwrap());
+    }
+
+    #[test]
+    fn waits_for_unchoke_across_messages_cut_by_timeouts() {
+        let mut input = Message::Have { index: 5 }.as_bytes().unwrap();
+        input.extend(Message::UnChoke.as_bytes().unwrap());
+        let mut peer = ChoppyPeer::new(1, input);
+        let mut conn = PeerConnManager::new();
+        let (_haves, completed) = std::sync::mpsc::channel();
+        let mut peer_pieces = HashSet::new();
+
+        conn.last_sent = Instant::now() - PeerConnManager::KEEP_ALIVE_INTERVAL;
+        let waited = conn.wait_for_unchoke(
+            &mut peer,
+            &mut peer_pieces,
+            &completed,
+            |_| {},
+            PeerTimeouts::default().unchoke,
+            false,
+        );
+
+        assert!(waited.is_ok());
+        assert!(conn.state == State::UnChoked);
+        assert_eq!(peer_pieces, HashSet::from([5]));
+        assert_eq!(peer.stream.output, Message::KeepAlive.as_bytes().unwrap());
+    }
+
+    #[test]
+    fn gives_up_on_peers_that_never_unchoke() {
+        let mut peer = SlowPeer {
+            timeouts: usize::MAX,
+            stream: MockStream {
+                input: Cursor::new(Vec::new()),
+                output: Vec::new(),
+            },
+        };
+        let mut conn = PeerConnManager::new();
+        let (_haves, completed) = std::sync::mpsc::channel();
+
+        let waited = conn.wait_for_unchoke(
+            &mut peer,
+            &mut HashSet::new(),
+            &completed,
+            |_| {},
+            Duration::from_millis(20),
+            false,
+        );
+
+        assert!(matches!(waited, Err(ConnError::NeverUnchoked)));
+        assert!(conn.state == State::Choked);
+    }
+
+    #[test]
+    fn adds_dht_nodes_from_port_messages() {
+        let mut input = Message::Port { port: 6881 }.as_bytes().unwrap();
+        input.extend(Message::UnChoke.as_bytes().unwrap());
+        let mut stream = MockStream {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+
+        let dht = Arc::new(Dht::bind("127.0.0.1:0").unwrap());
+        let ip: IpAddr = "10.0.0.7".parse().unwrap();
+        let mut conn = PeerConnManager::new();
+        conn.dht_node = Some((Arc::clone(&dht), ip));
+        let (_haves, completed) = std::sync::mpsc::channel();
+
+        let waited = conn.wait_for_unchoke(
+            &mut stream,
+            &mut HashSet::new(),
+            &completed,
+            |_| {},
+            PeerTimeouts::default().unchoke,
+            false,
+        );
+
+        assert!(waited.is_ok());
+        assert_eq!(dht.nodes(), vec![SocketAddr::new(ip, 6881)]);
+        let handshake = HandShake::new([0; 20], [0; 20])
+            .with_dht()
+            .as_bytes()
+            .unwrap();
+        assert_eq!(handshake[20..28], [0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn refetches_pieces_the_writer_rejected_from_the_same_peer() {
+        let data = [1, 2, 3, 4, 5, 6, 7, 8];
+        let torrent_file = Arc::new(crate::test_torrent("test", 4, &data));
+        let global_queue = Arc::new(Mutex::new(VecDeque::from([0, 1])));
+        let file = Arc::new(Mutex::new(Cursor::new(vec![0u8; 8])));
+        let peer_manager = crate::PeerManager::new(1);
+        let (writer, results, writer_handle) = PieceWriter::spawn(
+            file.clone(),
+            Arc::clone(&torrent_file),
+            Arc::clone(&global_queue),
+            Arc::clone(&peer_manager.hash_failures),
+            true,
+        );
+
+        // Sends a bad piece 0 first and holds piece 1 back until the writer re-queued it
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        let info_hash = torrent_file.info_hash;
+        let queue = Arc::clone(&global_queue);
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut handshake = [0u8; 68];
+            stream.read_exact(&mut handshake).unwrap();
+            let mut reply = HandShake::new(info_hash, [1; 20]).as_bytes().unwrap();
+            reply.extend(
+                build_bitfield_message(&HashSet::from([0, 1]), 2)
+                    .as_bytes()
+                    .unwrap(),
+            );
+            reply.extend(Message::UnChoke.as_bytes().unwrap());
+            stream.write_all(&reply).unwrap();
+
+            let mut sent_bad = false;
+            let mut conn = PeerConnManager::new();
+            while let Ok(message) = conn.read_stream(&mut stream) {
+                if message[0] != 6 {
+                    continue;
+                }
+                let index = u32::from_be_bytes(message[1..5].try_into().unwrap()) as usize;
+                let piece = if index == 0 && !sent_bad {
+                    sent_bad = true;
+                    vec![0; 4]
+                } else {
+                    while index == 1 && !queue.lock().unwrap().contains(&0) {
+                        thread::sleep(Duration::from_millis(5));
+                    }
+                    data[index * 4..index * 4 + 4].to_vec()
+                };
+                let block = Message::Piece {
+                    index: index as u32,
+                    begin: 0,
+                    piece,
+                };
+                stream.write_all(&block.as_bytes().unwrap()).unwrap();
+            }
+        });
+
+        let context = PeerContext {
+            global_queue: Arc::clone(&global_queue),
+            torrent_file,
+            peer_id: [0; 20],
+            file,
+            availability: Arc::clone(&peer_manager.availability),
+            hash_failures: Arc::clone(&peer_manager.hash_failures),
+            verified: Arc::new(AtomicUsize::new(0)),
+            completions: Arc::clone(&peer_manager.completions),
+            peer_stats: Arc::clone(&peer_manager.peer_stats),
+            haves: Arc::clone(&peer_manager.haves),
+            in_progress: Arc::clone(&peer_manager.in_progress),
+            transfer: Arc::clone(&peer_manager.transfer),
+            piece_order: PieceOrder::Random,
+            timeouts: PeerTimeouts::default(),
+            proxy: None,
+            dht: None,
+            shutdown: Arc::clone(&peer_manager.shutdown),
+            events: None,
+            writer: Some(writer),
+            verbose: false,
+        };
+        let handled = PeerConnManager::new().handle_peer(socket_addr, context);
+        writer_handle.join().unwrap();
+
+        assert!(matches!(handled, Err(ConnError::EmptyQueue)));
+        assert_eq!(
+            results.iter().collect::<Vec<_>>(),
+            vec![
+                PieceResult::HashFailed(0),
+                PieceResult::Written(1),
+                PieceResult::Written(0)
+            ]
+        );
+    }
+
+    /// Seeds `data` as a single piece to one connection, once `hold` returns false
+    fn seed_one_piece(
+        info_hash: [u8; 20],
+        data: Vec<u8>,
+        hold: impl Fn() -> bool + Send + 'static,
+    ) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut handshake = [0u8; 68];
+            stream.read_exact(&mut handshake).unwrap();
+            let mut reply = HandShake::new(info_hash, [1; 20]).as_bytes().unwrap();
+            reply.extend(
+                build_bitfield_message(&HashSet::from([0]), 1)
+                    .as_bytes()
+                    .unwrap(),
+            );
+            reply.extend(Message::UnChoke.as_bytes().unwrap());
+            stream.write_all(&reply).unwrap();
+
+            let mut conn = PeerConnManager::new();
+            while let Ok(message) = conn.read_stream(&mut stream) {
+                if message[0] != 6 {
+                    continue;
+                }
+                while hold() {
+                    thread::sleep(Duration::from_millis(5));
+                }
+                let block = Message::Piece {
+                    index: 0,
+                    begin: 0,
+                    piece: data.clone(),
+                };
+                let _ = stream.write_all(&block.as_bytes().unwrap());
+            }
+        });
+        socket_addr
+    }
+
+    #[test]
+    fn endgame_downloads_the_last_piece_from_a_second_peer() {
+        let data = vec![1, 2, 3, 4];
+        let peer_manager = crate::PeerManager::new(2);
+        let mut context = crate::test_context(&peer_manager);
+        context.torrent_file = Arc::new(crate::test_torrent("test", 4, &data));
+        let file = Arc::new(Mutex::new(Cursor::new(vec![0u8; 4])));
+        context.file = file.clone();
+        let info_hash = context.torrent_file.info_hash;
+
+        // The slow peer only answers once the fast one delivered the piece
+        let verified = Arc::clone(&context.verified);
+        let slow = seed_one_piece(info_hash, data.clone(), move || {
+            verified.load(Ordering::Relaxed) == 0
+        });
+        let fast = seed_one_piece(info_hash, data.clone(), || false);
+
+        let slow_context = context.clone();
+        let slow_conn =
+            thread::spawn(move || PeerConnManager::new().handle_peer(slow, slow_context));
+        while !peer_manager.in_progress.contains(0) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(context.global_queue.lock().unwrap().is_empty());
+
+        // Keeps checking for pieces to help with while the slow peer finishes
+        let mut fast_context = context.clone();
+        fast_context.timeouts.read = Duration::from_millis(200);
+        let fast_handled = PeerConnManager::new().handle_peer(fast, fast_context);
+        let slow_handled = slow_conn.join().unwrap();
+
+        assert!(matches!(fast_handled, Err(ConnError::EmptyQueue)));
+        assert!(matches!(slow_handled, Err(ConnError::EmptyQueue)));
+        // Written once, the slow copy is dropped
+        assert_eq!(context.verified.load(Ordering::Relaxed), 1);
+        assert_eq!(peer_manager.piece_completion_times().len(), 1);
+        assert!(peer_manager.in_progress.is_empty());
+        assert_eq!(file.lock().unwrap().get_ref(), &data);
+    }
+
+    #[test]
+    fn skips_pieces_in_progress_outside_endgame() {
+        // piece 1 was re-queued while still queued once
+        let mut queue = VecDeque::from([1, 1, 2]);
+        let in_progress = InProgress::default();
+        let both = HashSet::from([1, 2]);
+
+        assert_eq!(
+            take_available_piece(&mut queue, &both, &in_progress, PieceOrder::Random),
+            Some(1)
+        );
+        // A second peer gets another piece, not the one being downloaded
+        assert_eq!(
+            take_available_piece(&mut queue, &both, &in_progress, PieceOrder::Random),
+            Some(2)
+        );
+        assert_eq!(queue, VecDeque::from([1]));
+
+        // Only in-progress pieces are left, so it's endgame and the queued copy goes
+        assert_eq!(
+            take_available_piece(
+                &mut queue,
+                &HashSet::from([1]),
+                &in_progress,
+                PieceOrder::Random
+            ),
+            Some(1)
+        );
+        assert!(queue.is_empty());
+
+        // Piece 2 failed and is free again, the peer that only has 1 has to wait
+        in_progress.finish(2);
+        let mut queue = VecDeque::from([1, 2]);
+        assert_eq!(
+            take_available_piece(
+                &mut queue,
+                &HashSet::from([1]),
+                &in_progress,
+                PieceOrder::Random
+            ),
+            None
+        );
+
+        // Both connections on piece 1 gave up
+        assert!(!in_progress.abandon(1));
+        assert!(in_progress.abandon(1));
+        assert_eq!(
+            take_available_piece(
+                &mut queue,
+                &HashSet::from([1]),
+                &in_progress,
+                PieceOrder::Random
+            ),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn endgame_hands_out_pieces_in_progress_once_the_queue_is_empty() {
+        let mut queue = VecDeque::new();
+        let in_progress = InProgress::default();
+        assert!(in_progress.try_start(3));
+        assert!(in_progress.try_start(4));
+        assert!(in_progress.join(&HashSet::from([4])).is_some());
+
+        // The peer doesn't have piece 3, 4 has the fewest connections otherwise
+        assert_eq!(
+            take_available_piece(
+                &mut queue,
+                &HashSet::from([5]),
+                &in_progress,
+                PieceOrder::Random
+            ),
+            None
+        );
+        assert_eq!(
+            take_available_piece(
+                &mut queue,
+                &HashSet::from([3, 4]),
+                &in_progress,
+                PieceOrder::Random
+            ),
+            Some(3)
+        );
+
+        // The first connection to complete 3 wins, nothing is left to help with
+        assert!(in_progress.complete(3));
+        assert!(!in_progress.complete(3));
+        assert!(!in_progress.abandon(4));
+        assert_eq!(
+            take_available_piece(
+                &mut queue,
+                &HashSet::from([3]),
+                &in_progress,
+                PieceOrder::Random
+            ),
+            None
+        );
+        assert_eq!(
+            take_available_piece(
+                &mut queue,
+                &HashSet::from([4]),
+                &in_progress,
+                PieceOrder::Random
+            ),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn sequential_order_takes_the_lowest_piece_the_peer_has() {
+        let mut queue = VecDeque::from([5, 2, 9, 3]);
+        let in_progress = InProgress::default();
+        let peer_pieces = HashSet::from([9, 3, 5]);
+        let take = |queue: &mut VecDeque<usize>| {
+            take_available_piece(queue, &peer_pieces, &in_progress, PieceOrder::Sequential)
+        };
+
+        assert_eq!(take(&mut queue), Some(3));
+        assert_eq!(take(&mut queue), Some(5));
+        // A re-queued piece goes back to the end but is still handed out first
+        queue.push_back(4);
+        assert_eq!(take(&mut queue), Some(9));
+        assert_eq!(queue, VecDeque::from([2, 4]));
+
+        // The lowest piece is taken by another connection, the next one is handed out
+        let mut queue = VecDeque::from([7, 6, 8]);
+        let peer_pieces = HashSet::from([6, 7, 8]);
+        assert!(in_progress.try_start(6));
+        assert_eq!(
+            take_available_piece(
+                &mut queue,
+                &peer_pieces,
+                &in_progress,
+                PieceOrder::Sequential
+            ),
+            Some(7)
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rand::random;
+use rubit_bencode::{
+    decode_dict, encode, parse_compact_peers, unwrap_bytes, unwrap_dict, unwrap_string,
+    BencodeTypes, Peers, COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN,
+};
+
+use crate::is_valid_peer;
+
+/// Well known nodes to join the DHT through
+pub const DHT_BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Compact node entry, 20 bytes id + compact IPv4 peer
+const COMPACT_NODE_LEN: usize = 20 + COMPACT_PEER_V4_LEN;
+
+/// Answer to a `get_peers` query
+#[derive(Debug, Default, PartialEq)]
+pub struct GetPeersResponse {
+    pub id: [u8; 20],
+    /// Peers of the torrent, only sent by nodes that know some
+    pub peers: Peers,
+    /// Nodes closer to the info hash
+    pub nodes: Vec<([u8; 20], SocketAddr)>,
+}
+
+/// Minimal read-only Mainline DHT (BEP 5) client: iterative `get_peers` lookups,
+/// queries from other nodes are never answered (BEP 43 `ro` flag)
+pub struct Dht {
+    socket: UdpSocket,
+    pub node_id: [u8; 20],
+    /// Joined through when the routing table is empty
+    pub bootstrap: Vec<String>,
+    /// Time a round of queries waits for answers
+    pub timeout: Duration,
+    /// Nodes that answered us or were announced by peers (`Message::Port`), oldest first
+    nodes: Mutex<VecDeque<SocketAddr>>,
+    transaction_id: AtomicU16,
+}
+
+impl Dht {
+    /// Nodes queried at the same time
+    const ALPHA: usize = 8;
+    /// Closest nodes a lookup has to query before it's done
+    const K: usize = 8;
+    const MAX_ROUNDS: usize = 16;
+    const MAX_NODES: usize = 256;
+
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+            node_id: random(),
+            bootstrap: DHT_BOOTSTRAP_NODES.iter().map(|n| n.to_string()).collect(),
+            timeout: Duration::from_secs(2),
+            nodes: Mutex::new(VecDeque::new()),
+            transaction_id: AtomicU16::new(random()),
+        })
+    }
+
+    /// Adds a node to the routing table, the oldest one is dropped when full
+    pub fn add_node(&self, addr: SocketAddr) {
+        if !is_valid_peer(&addr) {
+            return;
+        }
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.contains(&addr) {
+            return;
+        }
+        if nodes.len() >= Self::MAX_NODES {
+            nodes.pop_front();
+        }
+        nodes.push_back(addr);
+    }
+
+    pub fn nodes(&self) -> Vec<SocketAddr> {
+        self.nodes.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Iterative lookup, each round queries the closest nodes not queried yet until
+    /// the `K` closest nodes known have all been asked
+    pub fn get_peers(&self, info_hash: &[u8; 20]) -> Peers {
+        let mut round = self.nodes();
+        if round.is_empty() {
+            round = self
+                .bootstrap
+                .iter()
+                .filter_map(|node| node.to_socket_addrs().ok())
+                .flatten()
+                .filter(SocketAddr::is_ipv4)
+                .collect();
+        }
+
+        let mut candidates: BTreeMap<[u8; 20], SocketAddr> = BTreeMap::new();
+        let mut queried = HashSet::new();
+        let mut peers = HashSet::new();
+
+        for _ in 0..Self::MAX_ROUNDS {
+            if round.is_empty() {
+                break;
+            }
+            queried.extend(round.iter().copied());
+
+            for (addr, response) in self.query_round(&round, info_hash) {
+                self.add_node(addr);
+                peers.extend(response.peers.into_iter().filter(is_valid_peer));
+                for (id, node) in response.nodes {
+                    if is_valid_peer(&node) {
+                        candidates.insert(distance(&id, info_hash), node);
+                    }
+                }
+            }
+
+            round = candidates
+                .values()
+                .take(Self::K)
+                .filter(|node| !queried.contains(*node))
+                .take(Self::ALPHA)
+                .copied()
+                .collect();
+        }
+
+        peers.into_iter().collect()
+    }
+
+    /// Sends `get_peers` to every node and collects the answers until `timeout`
+    fn query_round(
+        &self,
+        nodes: &[SocketAddr],
+        info_hash: &[u8; 20],
+    ) -> Vec<(SocketAddr, GetPeersResponse)> {
+        let mut pending = HashMap::new();
+        for node in nodes {
+            let transaction_id = self.transaction_id.fetch_add(1, Ordering::Relaxed);
+            let query = get_peers_query(&transaction_id.to_be_bytes(), &self.node_id, info_hash);
+            if self.socket.send_to(&query, node).is_ok() {
+                pending.insert(transaction_id.to_be_bytes().to_vec(), *node);
+            }
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let mut responses = Vec::new();
+        let mut buf = [0u8; 2048];
+
+        while !pending.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || self.socket.set_read_timeout(Some(remaining)).is_err() {
+                break;
+            }
+            let Ok((len, from)) = self.socket.recv_from(&mut buf) else {
+                break;
+            };
+
+            let Some((transaction_id, response)) = parse_get_peers_response(&buf[..len]) else {
+                continue;
+            };
+            // Answers to older lookups or from someone we didn't ask are dropped
+            if pending.get(&transaction_id) == Some(&from) {
+                pending.remove(&transaction_id);
+                responses.push((from, response));
+            }
+        }
+
+        responses
+    }
+}
+
+/// XOR metric, compared as big endian numbers
+fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut distance = [0u8; 20];
+    for (i, byte) in distance.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    distance
+}
+
+pub fn get_peers_query(transaction_id: &[u8], node_id: &[u8; 20], info_hash: &[u8; 20]) -> Vec<u8> {
+    encode(&BencodeTypes::Dict(HashMap::from([
+        (
+            String::from("t"),
+            BencodeTypes::Bytes(transaction_id.to_vec()),
+        ),
+        (String::from("y"), BencodeTypes::String(String::from("q"))),
+        (
+            String::from("q"),
+            BencodeTypes::String(String::from("get_peers")),
+        ),
+        (
+            String::from("a"),
+            BencodeTypes::Dict(HashMap::from([
+                (String::from("id"), BencodeTypes::Bytes(node_id.to_vec())),
+                (
+                    String::from("info_hash"),
+                    BencodeTypes::Bytes(info_hash.to_vec()),
+                ),
+            ])),
+        ),
+        (String::from("ro"), BencodeTypes::Integer(1)),
+    ])))
+}
+
+/// Transaction id and body of a `get_peers` answer, `None` for errors and queries
+pub fn parse_get_peers_response(buf: &[u8]) -> Option<(Vec<u8>, GetPeersResponse)> {
+    let mut dict = decode_dict(&mut 0, buf).ok()?;
+    if unwrap_string(dict.remove("y")?)? != "r" {
+        return None;
+    }
+    let transaction_id = unwrap_bytes(dict.remove("t")?)?;
+    let mut body = unwrap_dict(dict.remove("r")?)?;
+
+    let id = unwrap_bytes(body.remove("id")?)?.try_into().ok()?;
+
+    let mut peers = Vec::new();
+    if let Some(BencodeTypes::List(values)) = body.remove("values") {
+        for value in values.into_iter().filter_map(unwrap_bytes) {
+            if value.len() == COMPACT_PEER_V4_LEN || value.len() == COMPACT_PEER_V6_LEN {
+                peers.extend(parse_compact_peers(&value, value.len()));
+            }
+        }
+    }
+
+    let nodes = match body.remove("nodes").and_then(unwrap_bytes) {
+        Some(nodes) => nodes
+            .chunks_exact(COMPACT_NODE_LEN)
+            .map(|node| {
+                let (id, addr) = node.split_at(20);
+                (
+                    id.try_into().unwrap(),
+                    parse_compact_peers(addr, COMPACT_PEER_V4_LEN)[0],
+                )
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Some((transaction_id, GetPeersResponse { id, peers, nodes }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::Ipv4Addr, thread};
+
+    fn compact(addr: SocketAddr) -> Vec<u8> {
+        let SocketAddr::V4(addr) = addr else {
+            panic!("IPv4 only");
+        };
+        let mut bytes = addr.ip().octets().to_vec();
+        bytes.extend_from_slice(&addr.port().to_be_bytes());
+        bytes
+    }
+
+    /// Answers every `get_peers` with `nodes` and `peers`
+    fn fake_node(id: [u8; 20], nodes: Vec<([u8; 20], SocketAddr)>, peers: Peers) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                let mut query = decode_dict(&mut 0, &buf[..len]).unwrap();
+                assert_eq!(
+                    query.remove("q"),
+                    Some(BencodeTypes::String(String::from("get_peers")))
+                );
+
+                let compact_nodes: Vec<u8> = nodes
+                    .iter()
+                    .flat_map(|(id, node)| [id.to_vec(), compact(*node)].concat())
+                    .collect();
+                let values = peers
+                    .iter()
+                    .map(|peer| BencodeTypes::Bytes(compact(*peer)))
+                    .collect();
+                let response = BencodeTypes::Dict(HashMap::from([
+                    (String::from("t"), query.remove("t").unwrap()),
+                    (String::from("y"), BencodeTypes::String(String::from("r"))),
+                    (
+                        String::from("r"),
+                        BencodeTypes::Dict(HashMap::from([
+                            (String::from("id"), BencodeTypes::Bytes(id.to_vec())),
+                            (String::from("nodes"), BencodeTypes::Bytes(compact_nodes)),
+                            (String::from("token"), BencodeTypes::Bytes(vec![0xff, 1])),
+                            (String::from("values"), BencodeTypes::List(values)),
+                        ])),
+                    ),
+                ]));
+                socket.send_to(&encode(&response), from).unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn finds_peers_through_closer_nodes() {
+        let info_hash = [0x11; 20];
+        let peer = SocketAddr::from((Ipv4Addr::new(10, 1, 2, 3), 51413));
+
+        // The bootstrap node only knows a node closer to the info hash, which has the peer
+        let close = fake_node([0x10; 20], Vec::new(), vec![peer]);
+        let bootstrap = fake_node([0xee; 20], vec![([0x10; 20], close)], Vec::new());
+
+        let mut dht = Dht::bind("127.0.0.1:0").unwrap();
+        dht.bootstrap = vec![bootstrap.to_string()];
+        dht.timeout = Duration::from_millis(500);
+
+        assert_eq!(dht.get_peers(&info_hash), vec![peer]);
+        let nodes = dht.nodes();
+        assert!(nodes.contains(&bootstrap) && nodes.contains(&close));
+    }
+
+    #[test]
+    fn builds_read_only_get_peers_queries() {
+        let query = get_peers_query(b"aa", &[b'i'; 20], &[b'h'; 20]);
+        let expected = format!(
+            "d1:ad2:id20:{}9:info_hash20:{}e1:q9:get_peers2:roi1e1:t2:aa1:y1:qe",
+            "i".repeat(20),
+            "h".repeat(20)
+        );
+
+        assert_eq!(query, expected.as_bytes());
+    }
+}
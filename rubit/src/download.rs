@@ -0,0 +1,407 @@
+use std::{
+    collections::VecDeque,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::Sender,
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use rand::{seq::SliceRandom, thread_rng};
+use rubit_bencode::TorrentFile;
+
+use crate::{
+    bytes_left, check_torrent_data, get_tracker_list, open_torrent_data,
+    retain_not_downloaded_pieces, Announcer, DownloadEvent, PeerContext, PeerIdPolicy, PeerIds,
+    PeerManager, PeerTimeouts, PieceOrder, Proxy,
+};
+
+#[derive(Debug)]
+pub enum DownloadError {
+    Io(io::Error),
+    /// Every tracker failed while no peer was connected
+    TrackersFailed,
+    /// `Download::stop` was called
+    Stopped,
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Settings of a `Download`, the defaults are the CLI's
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Advertised to the trackers
+    pub port: u16,
+    /// Peers connected to at the same time
+    pub max_peers: usize,
+    /// No new peers are announced for above this many connected or waiting ones
+    pub max_known_peers: usize,
+    pub timeouts: PeerTimeouts,
+    pub piece_order: PieceOrder,
+    /// Fixed re-announce interval instead of the one the trackers send,
+    /// never shorter than their `min interval`
+    pub announce_interval: Option<Duration>,
+    /// Sent with every HTTP tracker request
+    pub extra_headers: Vec<(String, String)>,
+    pub proxy: Option<Proxy>,
+    /// Threads hashing the data already on disk
+    pub verify_threads: usize,
+    /// Grow the output file to the torrent's length before downloading, see `preallocate`
+    pub preallocate: bool,
+    pub verbose: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            port: 6881,
+            max_peers: PeerManager::DEFAULT_MAX_THREADS,
+            max_known_peers: PeerManager::DEFAULT_MAX_KNOWN_PEERS,
+            timeouts: PeerTimeouts::default(),
+            piece_order: PieceOrder::default(),
+            announce_interval: None,
+            extra_headers: Vec::new(),
+            proxy: None,
+            verify_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            preallocate: false,
+            verbose: false,
+        }
+    }
+}
+
+/// Downloads a torrent into `output`, the directory of its files for multi-file
+/// torrents: checks the data already there, announces
+/// to the trackers on their interval and hands their peers to a `PeerManager`
+/// until every piece is verified. A torrent without data (`length` 0) has no pieces,
+/// it only creates the empty `output` without announcing
+pub struct Download {
+    pub torrent_file: Arc<TorrentFile>,
+    pub output: PathBuf,
+    pub options: DownloadOptions,
+    stop: Arc<AtomicBool>,
+}
+
+impl Download {
+    /// Time peers and trackers get when stopping
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    pub fn new(
+        torrent_file: TorrentFile,
+        output: impl Into<PathBuf>,
+        options: DownloadOptions,
+    ) -> Self {
+        Self {
+            torrent_file: Arc::new(torrent_file),
+            output: output.into(),
+            options,
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Makes `run` stop the peers, tell the trackers and return `DownloadError::Stopped`
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Blocks until the download is complete
+    pub fn run(&self) -> Result<(), DownloadError> {
+        self.download(PeerManager::new(self.options.max_peers))
+    }
+
+    /// Same as `run`, with every `DownloadEvent` sent on `events`
+    pub fn run_with_events(&self, events: Sender<DownloadEvent>) -> Result<(), DownloadError> {
+        self.download(PeerManager::with_events(self.options.max_peers, events))
+    }
+
+    fn download(&self, peer_manager: PeerManager) -> Result<(), DownloadError> {
+        let torrent_file = &self.torrent_file;
+        let options = &self.options;
+        let piece_num = torrent_file.info.pieces.len();
+
+        let completed = check_torrent_data(torrent_file, &self.output, options.verify_threads);
+        let verified = Arc::new(AtomicUsize::new(completed.len()));
+        let transfer = Arc::clone(&peer_manager.transfer);
+        transfer.set_left(bytes_left(torrent_file, &completed));
+        let mut queue = retain_not_downloaded_pieces(completed, (0..piece_num).collect());
+        // After the check, a preallocated fresh file would look like data to verify
+        let file = open_torrent_data(
+            torrent_file,
+            &self.output,
+            options.preallocate && !queue.is_empty(),
+        )?;
+        if queue.is_empty() {
+            return Ok(());
+        }
+        if options.piece_order == PieceOrder::Random {
+            queue.shuffle(&mut thread_rng());
+        }
+
+        let peer_id = PeerIds::new(PeerIdPolicy::default()).get(&torrent_file.info_hash);
+
+        let announce_list = torrent_file.announce_list.clone().unwrap_or_default();
+        let mut tracker_tiers = get_tracker_list(torrent_file.announce.clone(), announce_list);
+        for tracker in tracker_tiers.iter_mut().flatten() {
+            tracker.extra_headers = options.extra_headers.clone();
+            tracker.proxy = options.proxy.clone();
+        }
+
+        let peer_context = PeerContext {
+            global_queue: Arc::new(Mutex::new(VecDeque::from(queue))),
+            torrent_file: Arc::clone(torrent_file),
+            peer_id: peer_id.as_bytes().try_into().unwrap(),
+            file,
+            availability: Arc::clone(&peer_manager.availability),
+            hash_failures: Arc::clone(&peer_manager.hash_failures),
+            verified: Arc::clone(&verified),
+            completions: Arc::clone(&peer_manager.completions),
+            peer_stats: Arc::clone(&peer_manager.peer_stats),
+            haves: Arc::clone(&peer_manager.haves),
+            in_progress: Arc::clone(&peer_manager.in_progress),
+            transfer: Arc::clone(&transfer),
+            piece_order: options.piece_order,
+            timeouts: options.timeouts,
+            proxy: options.proxy.clone(),
+            dht: None,
+            shutdown: Arc::clone(&peer_manager.shutdown),
+            events: peer_manager.events.clone(),
+            writer: None,
+            verbose: options.verbose,
+        };
+
+        let mut announcer =
+            Announcer::new(tracker_tiers, torrent_file.info_hash, peer_id, options.port);
+        announcer.interval = options.announce_interval;
+        announcer.max_known_peers = options.max_known_peers;
+
+        while verified.load(Ordering::Relaxed) < piece_num {
+            if self.stop.load(Ordering::Relaxed) {
+                peer_manager.shutdown(Self::SHUTDOWN_TIMEOUT);
+                announcer.stopped(&peer_manager, Self::SHUTDOWN_TIMEOUT);
+                return Err(DownloadError::Stopped);
+            }
+
+            if !announcer.is_due(&peer_manager) {
+                thread::sleep(Self::POLL_INTERVAL);
+                continue;
+            }
+
+            let round = announcer.announce(&peer_manager, &peer_context);
+            if round.failed()
+                && peer_manager.peers.lock().unwrap().is_empty()
+                && peer_manager.in_progress.is_empty()
+            {
+                return Err(DownloadError::TrackersFailed);
+            }
+        }
+
+        // Peers still connected only wait for pieces we don't need anymore
+        peer_manager.shutdown(Self::SHUTDOWN_TIMEOUT);
+        announcer.completed(&peer_manager);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        multi_file_torrent_bytes, piece_hashes, temp_path, test_torrent, AnnounceConfig,
+        AnnounceEvent, HandShake, Message, TestTracker, Tracker,
+    };
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    const PIECE_LENGTH: usize = 16384;
+
+    /// Seeds `data` to every connection: unchokes right away and answers every request
+    fn seeder(host: &str, info_hash: [u8; 20], data: Vec<u8>) -> u16 {
+        let listener = TcpListener::bind((host, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let data = Arc::new(data);
+
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let data = Arc::clone(&data);
+                thread::spawn(move || -> io::Result<()> {
+                    let mut handshake = [0u8; 68];
+                    stream.read_exact(&mut handshake)?;
+                    stream.write_all(&HandShake::new(info_hash, [b's'; 20]).as_bytes()?)?;
+
+                    let pieces = data.len().div_ceil(PIECE_LENGTH);
+                    let bitfield = crate::build_bitfield_message(&(0..pieces).collect(), pieces);
+                    stream.write_all(&bitfield.as_bytes()?)?;
+                    stream.write_all(&Message::UnChoke.as_bytes()?)?;
+
+                    loop {
+                        let mut len = [0u8; 4];
+                        stream.read_exact(&mut len)?;
+                        let mut message = vec![0u8; u32::from_be_bytes(len) as usize];
+                        stream.read_exact(&mut message)?;
+
+                        // Request
+                        if message.first() != Some(&6) {
+                            continue;
+                        }
+                        let field = |i: usize| {
+                            u32::from_be_bytes(message[1 + i * 4..5 + i * 4].try_into().unwrap())
+                        };
+                        let (index, begin, length) = (field(0), field(1), field(2));
+                        let start = index as usize * PIECE_LENGTH + begin as usize;
+                        let piece = data[start..start + length as usize].to_vec();
+                        stream.write_all(
+                            &Message::Piece {
+                                index,
+                                begin,
+                                piece,
+                            }
+                            .as_bytes()?,
+                        )?;
+                    }
+                });
+            }
+        });
+
+        port
+    }
+
+    /// Full download from a tracker and a seeder both listening on `host`,
+    /// `torrent` makes the torrent of the data for the tracker's url. Returns the
+    /// output and the data it should hold
+    fn download_from_swarm(
+        host: &str,
+        torrent: impl FnOnce(&str, &[u8]) -> TorrentFile,
+    ) -> (PathBuf, Vec<u8>) {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let tracker = TestTracker::spawn((host, 0)).unwrap();
+        let torrent_file = torrent(tracker.announce_url().as_str(), &data);
+
+        // The seeder joins the swarm first
+        let seeder_port = seeder(host, torrent_file.info_hash, data.clone());
+        Tracker::new(tracker.announce_url())
+            .unwrap()
+            .announce(AnnounceConfig {
+                info_hash: torrent_file.info_hash,
+                peer_id: String::from("RB01-sssssssssssssss"),
+                port: seeder_port,
+                uploaded: 0,
+                downloaded: 0,
+                left: 0,
+                event: AnnounceEvent::Started,
+                num_want: None,
+                tracker_id: None,
+                key: None,
+            })
+            .unwrap();
+
+        let output = temp_path("download");
+        let download = Download::new(
+            torrent_file,
+            &output,
+            DownloadOptions {
+                port: 1,
+                preallocate: true,
+                ..DownloadOptions::default()
+            },
+        );
+        let (sender, events) = mpsc::channel();
+        download.run_with_events(sender).unwrap();
+
+        let completed = events
+            .try_iter()
+            .filter(|event| matches!(event, DownloadEvent::PieceCompleted { .. }))
+            .count();
+        assert_eq!(completed, 3);
+
+        // Nothing is left to fetch the second time
+        download.run().unwrap();
+        (output, data)
+    }
+
+    fn torrent_of(announce: &str, data: &[u8]) -> TorrentFile {
+        test_torrent(announce, PIECE_LENGTH, data)
+    }
+
+    #[test]
+    fn downloads_a_torrent_from_the_swarm() {
+        let (output, data) = download_from_swarm("127.0.0.1", torrent_of);
+
+        assert_eq!(fs::read(&output).unwrap(), data);
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn downloads_multi_file_torrents_into_a_directory() {
+        let (output, data) = download_from_swarm("127.0.0.1", |announce, data| {
+            let files = [("a.bin", 100), ("dir/b.bin", data.len() - 100)];
+            let pieces = piece_hashes(data, PIECE_LENGTH);
+            TorrentFile::try_from(multi_file_torrent_bytes(
+                announce,
+                "test",
+                &files,
+                PIECE_LENGTH,
+                &pieces,
+            ))
+            .unwrap()
+        });
+
+        let a = fs::read(output.join("a.bin")).unwrap();
+        let b = fs::read(output.join("dir/b.bin")).unwrap();
+        fs::remove_dir_all(output).unwrap();
+
+        assert_eq!(a, &data[..100]);
+        assert_eq!(b, &data[100..]);
+    }
+
+    #[test]
+    fn downloads_from_ipv6_peers() {
+        // No IPv6 on this host
+        if TcpListener::bind("[::1]:0").is_err() {
+            return;
+        }
+        let (output, data) = download_from_swarm("::1", torrent_of);
+
+        assert_eq!(fs::read(&output).unwrap(), data);
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn fails_when_no_tracker_answers() {
+        // Nothing listens there
+        let torrent_file = test_torrent("http://127.0.0.1:1/announce", PIECE_LENGTH, &[1; 10]);
+        let output = temp_path("download");
+
+        let download = Download::new(torrent_file, &output, DownloadOptions::default());
+
+        assert!(matches!(download.run(), Err(DownloadError::TrackersFailed)));
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn empty_torrents_only_create_the_file() {
+        // No pieces, so the dead tracker is never asked
+        let torrent_file = test_torrent("http://127.0.0.1:1/announce", PIECE_LENGTH, &[]);
+        let output = temp_path("download");
+
+        let download = Download::new(torrent_file, &output, DownloadOptions::default());
+
+        assert!(download.run().is_ok());
+        assert_eq!(fs::metadata(&output).unwrap().len(), 0);
+        fs::remove_file(output).unwrap();
+    }
+}
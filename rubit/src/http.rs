@@ -0,0 +1,86 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+/// A client that stops sending is dropped after this, so it can't hold up the others
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub(crate) struct HttpResponse {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+/// Answers the connections of `listener` one at a time on a background thread,
+/// `handle` gets the request line (`GET /path HTTP/1.1`) and the client's ip
+pub(crate) fn serve_http(
+    listener: TcpListener,
+    handle: impl Fn(&str, IpAddr) -> HttpResponse + Send + 'static,
+) {
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // One broken or idle client must not take the server down
+            let _ = respond(stream, &handle);
+        }
+    });
+}
+
+fn respond(
+    mut stream: TcpStream,
+    handle: &impl Fn(&str, IpAddr) -> HttpResponse,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let response = handle(&request_line, stream.peer_addr()?.ip());
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, time::Instant};
+
+    #[test]
+    fn idle_clients_dont_block_the_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve_http(listener, |request_line, _| HttpResponse {
+            status: "200 OK",
+            content_type: "text/plain",
+            body: request_line.trim_end().as_bytes().to_vec(),
+        });
+
+        // Connects but never finishes its request
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"GET /idle HTTP/1.1\r\n").unwrap();
+
+        let started = Instant::now();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /next HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("GET /next HTTP/1.1"));
+        assert!(started.elapsed() < READ_TIMEOUT * 2);
+    }
+}
@@ -1,11 +1,44 @@
+#[cfg(any(test, feature = "async"))]
+mod announce_async;
+mod announcer;
+mod blocklist;
 mod conn;
+mod dht;
+mod download;
+mod http;
 mod message;
+mod metrics;
+mod proxy;
+mod resume;
+mod select;
+mod storage;
+#[cfg(any(test, feature = "testsupport"))]
+mod test_support;
+#[cfg(any(test, feature = "testtracker"))]
+mod testtracker;
 mod thread;
 mod tracker;
 mod util;
+mod writer;
 
+#[cfg(any(test, feature = "async"))]
+pub use announce_async::*;
+pub use announcer::*;
+pub use blocklist::*;
 pub use conn::*;
+pub use dht::*;
+pub use download::*;
 pub use message::*;
+pub use metrics::*;
+pub use proxy::*;
+pub use resume::*;
+pub use select::*;
+pub use storage::*;
+#[cfg(any(test, feature = "testsupport"))]
+pub use test_support::*;
+#[cfg(any(test, feature = "testtracker"))]
+pub use testtracker::*;
 pub use thread::*;
 pub use tracker::*;
 pub use util::*;
+pub use writer::*;
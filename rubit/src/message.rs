@@ -1,7 +1,10 @@
 use core::str;
-use std::io::{self, Write};
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Message {
     KeepAlive,
     Choke,
@@ -11,8 +14,9 @@ pub enum Message {
     Have {
         index: u32,
     },
-    // TODO
-    BitField,
+    BitField {
+        bitfield: Vec<u8>,
+    },
     Request {
         index: u32,
         begin: u32,
@@ -28,6 +32,10 @@ pub enum Message {
         begin: u32,
         piece: Vec<u8>,
     },
+    /// UDP port of the peer's DHT node (BEP 5)
+    Port {
+        port: u16,
+    },
 }
 
 impl Message {
@@ -58,8 +66,10 @@ impl Message {
                 buf.write_all(&[4])?;
                 buf.write_all(&index.to_be_bytes())?;
             }
-            Message::BitField => {
-                todo!();
+            Message::BitField { bitfield } => {
+                buf.write_all(&(bitfield.len() as u32 + 1).to_be_bytes())?;
+                buf.write_all(&[5])?;
+                buf.write_all(bitfield)?;
             }
             Message::Request {
                 index,
@@ -81,7 +91,7 @@ impl Message {
                 buf.write_all(&[7])?;
                 buf.write_all(&index.to_be_bytes())?;
                 buf.write_all(&begin.to_be_bytes())?;
-                buf.write_all(&piece)?;
+                buf.write_all(piece)?;
             }
             Message::Cancel {
                 index,
@@ -89,20 +99,38 @@ impl Message {
                 length,
             } => {
                 buf.write_all(&13u32.to_be_bytes())?;
-                buf.write_all(&[9])?;
+                buf.write_all(&[8])?;
                 buf.write_all(&index.to_be_bytes())?;
                 buf.write_all(&begin.to_be_bytes())?;
                 buf.write_all(&length.to_be_bytes())?;
             }
+            Message::Port { port } => {
+                buf.write_all(&3u32.to_be_bytes())?;
+                buf.write_all(&[9])?;
+                buf.write_all(&port.to_be_bytes())?;
+            }
         };
         Ok(buf)
     }
 }
 
+/// Packs the `completed` piece indices into a `Message::BitField`,
+/// high bit of the first byte is piece 0 and spare bits at the end are cleared
+pub fn build_bitfield_message(completed: &HashSet<usize>, piece_count: usize) -> Message {
+    let mut bitfield = vec![0u8; piece_count.div_ceil(8)];
+
+    for &index in completed.iter().filter(|&&index| index < piece_count) {
+        bitfield[index / 8] |= 0b1000_0000 >> (index % 8);
+    }
+
+    Message::BitField { bitfield }
+}
+
 #[derive(Debug)]
 pub struct HandShake {
     info_hash: [u8; 20],
     peer_id: [u8; 20],
+    reserved: [u8; 8],
 }
 
 impl HandShake {
@@ -112,7 +140,17 @@ impl HandShake {
     ];
 
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
-        Self { info_hash, peer_id }
+        Self {
+            info_hash,
+            peer_id,
+            reserved: [0; 8],
+        }
+    }
+
+    /// Sets the DHT bit (last reserved bit), peers with a DHT node then send `Message::Port`
+    pub fn with_dht(mut self) -> Self {
+        self.reserved[7] |= 0x01;
+        self
     }
 
     pub fn as_bytes(&self) -> io::Result<Vec<u8>> {
@@ -120,10 +158,59 @@ impl HandShake {
 
         buf.write_all(&[19])?;
         buf.write_all(&Self::BITTORRENT_PROTOCOL_BYTES)?;
-        buf.write_all(&[0u8; 8])?;
+        buf.write_all(&self.reserved)?;
         buf.write_all(&self.info_hash)?;
         buf.write_all(&self.peer_id)?;
 
         Ok(buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_use_the_spec_ids() {
+        let block = (1, 2, 3);
+        let ids: Vec<u8> = [
+            Message::Choke,
+            Message::UnChoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have { index: 1 },
+            Message::BitField { bitfield: vec![0] },
+            Message::Request {
+                index: block.0,
+                begin: block.1,
+                length: block.2,
+            },
+            Message::Piece {
+                index: block.0,
+                begin: block.1,
+                piece: vec![0],
+            },
+            Message::Cancel {
+                index: block.0,
+                begin: block.1,
+                length: block.2,
+            },
+            Message::Port { port: 6881 },
+        ]
+        .iter()
+        .map(|message| message.as_bytes().unwrap()[4])
+        .collect();
+
+        assert_eq!(ids, (0..=9).collect::<Vec<u8>>());
+        assert_eq!(
+            Message::Cancel {
+                index: 1,
+                begin: 2,
+                length: 3
+            }
+            .as_bytes()
+            .unwrap(),
+            [0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    fmt::Write as _,
+    io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use rubit_bencode::TorrentFile;
+
+use crate::http::{serve_http, HttpResponse};
+
+/// Download counters of one torrent, rendered in the Prometheus text format
+pub struct Metrics {
+    info_hash: String,
+    name: String,
+    length: u64,
+    piece_length: u64,
+    pieces: usize,
+    /// Shared with `PeerContext::verified`
+    pub verified: Arc<AtomicUsize>,
+    pub peers: AtomicUsize,
+    /// Always 0 until seeding is supported
+    pub uploaded: AtomicU64,
+    pub announces_ok: AtomicU64,
+    pub announces_failed: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(torrent_file: &TorrentFile, verified: Arc<AtomicUsize>) -> Self {
+        Self {
+            info_hash: torrent_file.info_hash_hex(),
+            name: torrent_file.info.name.clone(),
+            length: torrent_file.info.length,
+            piece_length: torrent_file.info.piece_length,
+            pieces: torrent_file.info.pieces.len(),
+            verified,
+            peers: AtomicUsize::new(0),
+            uploaded: AtomicU64::new(0),
+            announces_ok: AtomicU64::new(0),
+            announces_failed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let verified = self.verified.load(Ordering::Relaxed);
+        // Only the last piece is shorter, close enough for monitoring
+        let downloaded = (verified as u64 * self.piece_length).min(self.length);
+        let percent = if self.pieces == 0 {
+            100.0
+        } else {
+            verified as f64 / self.pieces as f64 * 100.0
+        };
+        let labels = format!(
+            "info_hash=\"{}\",name=\"{}\"",
+            self.info_hash,
+            self.name.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, String)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (extra, value) in samples {
+                let _ = writeln!(out, "{}{{{}{}}} {}", name, labels, extra, value);
+            }
+        };
+
+        metric(
+            "rubit_downloaded_bytes",
+            "counter",
+            "Bytes of verified pieces",
+            &[("", downloaded.to_string())],
+        );
+        metric(
+            "rubit_uploaded_bytes",
+            "counter",
+            "Bytes sent to peers",
+            &[("", self.uploaded.load(Ordering::Relaxed).to_string())],
+        );
+        metric(
+            "rubit_peers",
+            "gauge",
+            "Known peers",
+            &[("", self.peers.load(Ordering::Relaxed).to_string())],
+        );
+        metric(
+            "rubit_pieces_verified",
+            "gauge",
+            "Pieces downloaded and verified",
+            &[("", verified.to_string())],
+        );
+        metric(
+            "rubit_pieces_total",
+            "gauge",
+            "Pieces in the torrent",
+            &[("", self.pieces.to_string())],
+        );
+        metric(
+            "rubit_completion_percent",
+            "gauge",
+            "Verified pieces in percent",
+            &[("", format!("{:.2}", percent))],
+        );
+        metric(
+            "rubit_announces_total",
+            "counter",
+            "Tracker announces by result",
+            &[
+                (
+                    ",result=\"ok\"",
+                    self.announces_ok.load(Ordering::Relaxed).to_string(),
+                ),
+                (
+                    ",result=\"failed\"",
+                    self.announces_failed.load(Ordering::Relaxed).to_string(),
+                ),
+            ],
+        );
+
+        out
+    }
+}
+
+/// Serves `GET /metrics` on a background thread, returns the bound address
+pub fn serve_metrics(addr: impl ToSocketAddrs, metrics: Arc<Metrics>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+
+    serve_http(listener, move |request_line, _| {
+        let path = request_line.split(' ').nth(1).unwrap_or_default();
+        let (status, body) = if path == "/metrics" || path.starts_with("/metrics?") {
+            ("200 OK", metrics.render())
+        } else {
+            ("404 Not Found", String::from("not found\n"))
+        };
+
+        HttpResponse {
+            status,
+            content_type: "text/plain; version=0.0.4",
+            body: body.into_bytes(),
+        }
+    });
+
+    Ok(local_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent_bytes;
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let torrent_file =
+            TorrentFile::try_from(torrent_bytes("test", "test", 40, 16, &[0; 60])).unwrap();
+
+        let metrics = Arc::new(Metrics::new(&torrent_file, Arc::new(AtomicUsize::new(3))));
+        metrics.peers.store(17, Ordering::Relaxed);
+        metrics.announces_ok.store(2, Ordering::Relaxed);
+        metrics.announces_failed.store(1, Ordering::Relaxed);
+        let addr = serve_metrics("127.0.0.1:0", Arc::clone(&metrics)).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let labels = format!(
+            "info_hash=\"{}\",name=\"test\"",
+            torrent_file.info_hash_hex()
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        for line in [
+            format!("rubit_downloaded_bytes{{{}}} 40", labels),
+            format!("rubit_uploaded_bytes{{{}}} 0", labels),
+            format!("rubit_peers{{{}}} 17", labels),
+            format!("rubit_pieces_verified{{{}}} 3", labels),
+            format!("rubit_pieces_total{{{}}} 3", labels),
+            format!("rubit_completion_percent{{{}}} 100.00", labels),
+            format!("rubit_announces_total{{{},result=\"ok\"}} 2", labels),
+            format!("rubit_announces_total{{{},result=\"failed\"}} 1", labels),
+        ] {
+            assert!(response.lines().any(|l| l == line), "missing `{}`", line);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use rubit_bencode::{decode_dict, encode, to_hex, unwrap_integer, unwrap_string, BencodeTypes};
+
+/// Verified pieces of a download, saved next to it so a resume doesn't re-hash everything
+#[derive(Debug, PartialEq, Eq)]
+pub struct ResumeState {
+    pub info_hash: [u8; 20],
+    pub verified: HashSet<usize>,
+    /// Length of the download when the state was saved
+    pub size: u64,
+}
+
+impl ResumeState {
+    /// `movie.mkv` -> `movie.mkv.rubit`
+    pub fn path_for(download: &Path) -> PathBuf {
+        let mut path = download.as_os_str().to_owned();
+        path.push(".rubit");
+        PathBuf::from(path)
+    }
+
+    /// `None` if the file is missing, unreadable or belongs to another torrent.
+    /// Also `None` if the download is now shorter than when the state was saved,
+    /// it was truncated or replaced and the verified pieces can't be trusted
+    pub fn load(path: &Path, info_hash: &[u8; 20], download_len: u64) -> Option<HashSet<usize>> {
+        let state = Self::from_bytes(&fs::read(path).ok()?)?;
+        (state.info_hash == *info_hash && download_len >= state.size).then_some(state.verified)
+    }
+
+    /// Written to a temporary file first, so a crash never leaves a half written state
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, self.to_bytes())?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut verified: Vec<usize> = self.verified.iter().copied().collect();
+        verified.sort_unstable();
+
+        // The hash is stored as hex, strings are decoded as utf-8
+        encode(&BencodeTypes::Dict(HashMap::from([
+            (
+                String::from("info hash"),
+                BencodeTypes::String(to_hex(&self.info_hash)),
+            ),
+            (String::from("size"), BencodeTypes::Integer(self.size)),
+            (
+                String::from("verified"),
+                BencodeTypes::List(
+                    verified
+                        .into_iter()
+                        .map(|i| BencodeTypes::Integer(i as u64))
+                        .collect(),
+                ),
+            ),
+        ])))
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let mut dict = decode_dict(&mut 0, buf).ok()?;
+
+        let hex = unwrap_string(dict.remove("info hash")?)?;
+        if hex.len() != 40 || !hex.is_ascii() {
+            return None;
+        }
+        let mut info_hash = [0; 20];
+        for (i, byte) in info_hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+
+        let size = unwrap_integer(dict.remove("size")?)?;
+        let BencodeTypes::List(list) = dict.remove("verified")? else {
+            return None;
+        };
+        let verified = list
+            .into_iter()
+            .map(|item| match item {
+                BencodeTypes::Integer(i) => Some(i as usize),
+                _ => None,
+            })
+            .collect::<Option<HashSet<usize>>>()?;
+
+        Some(Self {
+            info_hash,
+            verified,
+            size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_path;
+
+    #[test]
+    fn saves_and_reloads_only_for_the_same_torrent() {
+        let path = ResumeState::path_for(&temp_path("resume").with_extension("bin"));
+        assert!(path.to_string_lossy().ends_with(".bin.rubit"));
+
+        let state = ResumeState {
+            info_hash: [0xab; 20],
+            verified: HashSet::from([0, 7, 3]),
+            size: 1024,
+        };
+        state.save(&path).unwrap();
+
+        let same = ResumeState::load(&path, &[0xab; 20], 1024);
+        let grown = ResumeState::load(&path, &[0xab; 20], 2048);
+        let truncated = ResumeState::load(&path, &[0xab; 20], 512);
+        let other = ResumeState::load(&path, &[0xcd; 20], 1024);
+        fs::write(&path, b"garbage").unwrap();
+        let corrupt = ResumeState::load(&path, &[0xab; 20], 1024);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(same, Some(HashSet::from([0, 3, 7])));
+        assert_eq!(grown, same);
+        assert_eq!(truncated, None);
+        assert_eq!(other, None);
+        assert_eq!(corrupt, None);
+        assert_eq!(ResumeState::load(&path, &[0xab; 20], 1024), None);
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashSet;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SelectionError {
+    /// Index past the end of the file list
+    BadIndex(usize),
+    /// A glob that matches no file
+    NoMatch(String),
+}
+
+/// Parses a `--select` spec: comma separated file indices (`0,3`), index ranges (`2-5`)
+/// and globs matched against the file paths (`*.mkv`, `Season 1/*E0?*`)
+pub fn parse_file_selection(
+    spec: &str,
+    paths: &[String],
+) -> Result<HashSet<usize>, SelectionError> {
+    let mut selected = HashSet::new();
+
+    for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        let range = item
+            .split_once('-')
+            .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+
+        let indices: Vec<usize> = if let Ok(index) = item.parse() {
+            vec![index]
+        } else if let Some((start, end)) = range {
+            (start..=end).collect()
+        } else {
+            let matched: Vec<usize> = (0..paths.len())
+                .filter(|i| glob_match(item, &paths[*i]))
+                .collect();
+            if matched.is_empty() {
+                return Err(SelectionError::NoMatch(item.to_string()));
+            }
+            matched
+        };
+
+        if let Some(index) = indices.iter().find(|i| **i >= paths.len()) {
+            return Err(SelectionError::BadIndex(*index));
+        }
+        selected.extend(indices);
+    }
+
+    Ok(selected)
+}
+
+/// Pieces overlapping any selected file, files laid out back to back in list order.
+/// A piece straddling a selected and an unselected file is included
+pub fn pieces_for_files(
+    file_lengths: &[u64],
+    piece_length: u64,
+    selected: &HashSet<usize>,
+) -> HashSet<usize> {
+    let mut pieces = HashSet::new();
+    let mut offset = 0u64;
+
+    for (index, length) in file_lengths.iter().enumerate() {
+        if selected.contains(&index) && *length > 0 {
+            let first = offset / piece_length;
+            let last = (offset + length - 1) / piece_length;
+            pieces.extend(first as usize..=last as usize);
+        }
+        offset += length;
+    }
+
+    pieces
+}
+
+/// `*` matches any run of characters, `?` a single one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Last `*` seen and the text position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the `*` eat one more character
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_by_index_range_and_glob() {
+        let paths: Vec<String> = [
+            "Season 1/E01.mkv",
+            "Season 1/E02.mkv",
+            "Season 1/info.nfo",
+            "cover.jpg",
+        ]
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+
+        assert_eq!(
+            parse_file_selection("0,3", &paths),
+            Ok(HashSet::from([0, 3]))
+        );
+        assert_eq!(
+            parse_file_selection("1-2", &paths),
+            Ok(HashSet::from([1, 2]))
+        );
+        assert_eq!(
+            parse_file_selection("*.mkv, cover.???", &paths),
+            Ok(HashSet::from([0, 1, 3]))
+        );
+        assert_eq!(
+            parse_file_selection("4", &paths),
+            Err(SelectionError::BadIndex(4))
+        );
+        assert_eq!(
+            parse_file_selection("*.iso", &paths),
+            Err(SelectionError::NoMatch(String::from("*.iso")))
+        );
+    }
+
+    #[test]
+    fn keeps_pieces_straddling_file_boundaries() {
+        // pieces of 16: [0..16) [16..32) [32..40), files at [0..10) [10..35) [35..35) [35..40)
+        let lengths = [10, 25, 0, 5];
+
+        assert_eq!(
+            pieces_for_files(&lengths, 16, &HashSet::from([0])),
+            HashSet::from([0])
+        );
+        assert_eq!(
+            pieces_for_files(&lengths, 16, &HashSet::from([1])),
+            HashSet::from([0, 1, 2])
+        );
+        assert_eq!(
+            pieces_for_files(&lengths, 16, &HashSet::from([3])),
+            HashSet::from([2])
+        );
+        assert!(pieces_for_files(&lengths, 16, &HashSet::from([2])).is_empty());
+    }
+}
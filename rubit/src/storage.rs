@@ -0,0 +1,389 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use rubit_bencode::{FileEntry, TorrentFile};
+
+use crate::{check_download_percent, check_storage_percent, ReadWriteSeek};
+
+/// Opens or creates the data of `torrent_file` at `path`: the file of a single-file torrent,
+/// the directory of a multi-file one. With `grow` it is preallocated to its full size
+pub fn open_torrent_data(
+    torrent_file: &TorrentFile,
+    path: &Path,
+    grow: bool,
+) -> io::Result<Arc<Mutex<dyn ReadWriteSeek>>> {
+    let info = &torrent_file.info;
+    if let Some(files) = &info.files {
+        let storage = MultiFile::create(path, files)?;
+        if grow {
+            storage.preallocate()?;
+        }
+        return Ok(Arc::new(Mutex::new(storage)));
+    }
+
+    let file = File::options()
+        .write(true)
+        .read(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    if grow {
+        preallocate(&file, info.length)?;
+    }
+    Ok(Arc::new(Mutex::new(file)))
+}
+
+/// Pieces of `torrent_file` already on disk at `path`, see `open_torrent_data`
+pub fn check_torrent_data(
+    torrent_file: &TorrentFile,
+    path: &Path,
+    threads: usize,
+) -> HashSet<usize> {
+    let info = &torrent_file.info;
+    match &info.files {
+        Some(files) => check_storage_percent(
+            || MultiFile::open(path, files),
+            &info.pieces,
+            info.length,
+            info.piece_length,
+            threads,
+        ),
+        None => check_download_percent(path, &info.pieces, info.length, info.piece_length, threads),
+    }
+}
+
+/// Bytes of `torrent_file` on disk at `path`, see `open_torrent_data`
+pub fn torrent_data_len(torrent_file: &TorrentFile, path: &Path) -> u64 {
+    match &torrent_file.info.files {
+        Some(files) => MultiFile::len_on_disk(path, files),
+        None => fs::metadata(path).map_or(0, |m| m.len()),
+    }
+}
+
+/// The files of a multi-file torrent as one contiguous byte range, so pieces can be
+/// written at `piece_index * piece_length` like into a single file (see `ReadWriteSeek`).
+/// Reads and writes crossing a file boundary are split between the files
+pub struct MultiFile {
+    /// Open file, its start offset in the torrent and its length. No file when
+    /// opened with `open` and it doesn't exist
+    files: Vec<(Option<File>, u64, u64)>,
+    length: u64,
+    position: u64,
+}
+
+impl MultiFile {
+    /// Opens or creates every file of `files` under `root`, creating parent directories
+    /// as needed. Zero-length files are created empty and hold no bytes of any piece
+    pub fn create(root: &Path, files: &[FileEntry]) -> io::Result<Self> {
+        Self::open_with(root, files, |path| {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map(Some)
+        })
+    }
+
+    /// Opens the files of `files` under `root` for reading only, without creating
+    /// anything. Missing files read as zeros, like the parts not written yet
+    pub fn open(root: &Path, files: &[FileEntry]) -> io::Result<Self> {
+        Self::open_with(root, files, |path| match File::open(path) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        })
+    }
+
+    fn open_with(
+        root: &Path,
+        files: &[FileEntry],
+        open: impl Fn(&Path) -> io::Result<Option<File>>,
+    ) -> io::Result<Self> {
+        let mut opened = Vec::with_capacity(files.len());
+        let mut offset = 0;
+
+        for file in files {
+            let path = safe_path(root, &file.path)?;
+            opened.push((open(&path)?, offset, file.length));
+            offset += file.length;
+        }
+
+        Ok(Self {
+            files: opened,
+            length: offset,
+            position: 0,
+        })
+    }
+
+    /// `preallocate` for every file
+    pub fn preallocate(&self) -> io::Result<()> {
+        for (file, _, length) in &self.files {
+            if let Some(file) = file {
+                preallocate(file, *length)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes on disk in all files together, missing files count as empty
+    pub fn len_on_disk(root: &Path, files: &[FileEntry]) -> u64 {
+        files
+            .iter()
+            .filter_map(|file| safe_path(root, &file.path).ok())
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    /// File holding the byte at `position`, the offset inside it and where the file ends
+    fn locate(&mut self) -> Option<(&mut Option<File>, u64, u64)> {
+        self.files
+            .iter_mut()
+            .find(|(_, start, length)| self.position >= *start && self.position < start + length)
+            .map(|(file, start, length)| (file, self.position - *start, *start + *length))
+    }
+}
+
+/// Grows `file` to `length` before any piece is written, so its size doesn't depend on
+/// which pieces arrived first. Longer files are left alone, their data may be someone's
+pub fn preallocate(file: &File, length: u64) -> io::Result<()> {
+    if file.metadata()?.len() < length {
+        file.set_len(length)?;
+    }
+    Ok(())
+}
+
+/// Joins `components` onto `root`, refusing anything that could escape it
+pub fn safe_path(root: &Path, components: &[String]) -> io::Result<PathBuf> {
+    let mut path = root.to_path_buf();
+    for component in components {
+        let mut parts = Path::new(component).components();
+        match (parts.next(), parts.next()) {
+            (Some(Component::Normal(part)), None) => path.push(part),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unsafe path component `{}`", component),
+                ))
+            }
+        }
+    }
+
+    if path == root {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "empty file path",
+        ));
+    }
+    Ok(path)
+}
+
+impl Read for MultiFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.position;
+        let Some((file, offset, end)) = self.locate() else {
+            return Ok(0);
+        };
+
+        let len = buf.len().min((end - position) as usize);
+        let read = match file {
+            Some(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read(&mut buf[..len])?
+            }
+            None => 0,
+        };
+        // Not written yet, the file is shorter than the torrent says
+        if read == 0 && len > 0 {
+            buf[..len].fill(0);
+            self.position += len as u64;
+            return Ok(len);
+        }
+
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for MultiFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let position = self.position;
+        let Some((file, offset, end)) = self.locate() else {
+            return Ok(0);
+        };
+
+        let Some(file) = file else {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "opened for reading only",
+            ));
+        };
+
+        let len = buf.len().min((end - position) as usize);
+        file.seek(SeekFrom::Start(offset))?;
+        let written = file.write(&buf[..len])?;
+
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for (file, _, _) in &mut self.files {
+            if let Some(file) = file {
+                file.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Seek for MultiFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(p) => self.length.checked_add_signed(p),
+            SeekFrom::Current(p) => self.position.checked_add_signed(p),
+        };
+
+        match position {
+            Some(p) => {
+                self.position = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the torrent",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_path;
+
+    fn path(components: &[&str], length: u64) -> FileEntry {
+        FileEntry {
+            path: components.iter().map(|c| c.to_string()).collect(),
+            length,
+        }
+    }
+
+    #[test]
+    fn splits_pieces_across_files_and_directories() {
+        let root = temp_path("storage");
+        let files = [
+            path(&["a.txt"], 10),
+            path(&["dir", "sub", "b.bin"], 25),
+            path(&["empty"], 0),
+            path(&["c"], 5),
+        ];
+        let mut storage = MultiFile::create(&root, &files).unwrap();
+
+        // Two pieces of 16 and the shorter last one, written out of order
+        let data: Vec<u8> = (0..40).collect();
+        for index in [2, 0, 1] {
+            let start = index * 16;
+            let end = (start + 16).min(data.len());
+            storage.seek(SeekFrom::Start(start as u64)).unwrap();
+            storage.write_all(&data[start..end]).unwrap();
+        }
+
+        let mut across = [0; 6];
+        storage.seek(SeekFrom::Start(7)).unwrap();
+        storage.read_exact(&mut across).unwrap();
+
+        let a = fs::read(root.join("a.txt")).unwrap();
+        let b = fs::read(root.join("dir/sub/b.bin")).unwrap();
+        let empty = fs::read(root.join("empty")).unwrap();
+        let c = fs::read(root.join("c")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(a, &data[..10]);
+        assert_eq!(b, &data[10..35]);
+        assert!(empty.is_empty());
+        assert_eq!(c, &data[35..]);
+        assert_eq!(across, [7, 8, 9, 10, 11, 12]);
+        // Nothing past the end of the torrent
+        storage.seek(SeekFrom::End(0)).unwrap();
+        assert!(storage.write_all(&[0]).is_err());
+    }
+
+    #[test]
+    fn torrents_of_only_empty_files_are_created() {
+        let root = temp_path("storage");
+        let mut storage =
+            MultiFile::create(&root, &[path(&["a"], 0), path(&["dir", "b"], 0)]).unwrap();
+        storage.preallocate().unwrap();
+
+        let mut buf = Vec::new();
+        storage.read_to_end(&mut buf).unwrap();
+        let a = fs::metadata(root.join("a")).unwrap().len();
+        let b = fs::metadata(root.join("dir/b")).unwrap().len();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(buf.is_empty());
+        assert_eq!((a, b), (0, 0));
+        assert!(storage.write_all(&[0]).is_err());
+    }
+
+    #[test]
+    fn opens_for_reading_without_creating_files() {
+        let root = temp_path("storage");
+        let files = [path(&["a"], 4), path(&["dir", "b"], 4)];
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a"), [1, 2]).unwrap();
+
+        let mut storage = MultiFile::open(&root, &files).unwrap();
+        let mut buf = Vec::new();
+        storage.read_to_end(&mut buf).unwrap();
+        let written = storage
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| storage.write(&[0]));
+        let on_disk = MultiFile::len_on_disk(&root, &files);
+        let created = root.join("dir").exists();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(buf, [1, 2, 0, 0, 0, 0, 0, 0]);
+        assert!(written.is_err());
+        assert_eq!(on_disk, 2);
+        assert!(!created);
+    }
+
+    #[test]
+    fn rejects_paths_escaping_the_root() {
+        let root = temp_path("storage");
+        for components in [&["..", "evil"][..], &["/etc", "passwd"], &["a/b"], &[]] {
+            assert!(MultiFile::create(&root, &[path(components, 1)]).is_err());
+        }
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn preallocates_without_shrinking() {
+        let root = temp_path("storage");
+        let multi = MultiFile::create(&root, &[path(&["a"], 10), path(&["b"], 30)]).unwrap();
+        multi.preallocate().unwrap();
+
+        assert_eq!(fs::metadata(root.join("a")).unwrap().len(), 10);
+        assert_eq!(fs::metadata(root.join("b")).unwrap().len(), 30);
+
+        let file = File::options().write(true).open(root.join("b")).unwrap();
+        preallocate(&file, 5).unwrap();
+        assert_eq!(fs::metadata(root.join("b")).unwrap().len(), 30);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
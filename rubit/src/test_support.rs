@@ -0,0 +1,126 @@
+use std::{
+    collections::VecDeque,
+    env,
+    io::Cursor,
+    path::PathBuf,
+    sync::{atomic::AtomicUsize, Arc, Mutex},
+};
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rubit_bencode::TorrentFile;
+use sha1::{Digest, Sha1};
+
+use crate::{PeerContext, PeerManager, PeerTimeouts, PieceOrder};
+
+/// Bencoded single-file torrent, `pieces` are its piece hashes one after another
+pub fn torrent_bytes(
+    announce: &str,
+    name: &str,
+    length: usize,
+    piece_length: usize,
+    pieces: &[u8],
+) -> Vec<u8> {
+    let mut buf = format!(
+        "d8:announce{}:{}4:infod6:lengthi{}e4:name{}:{}12:piece lengthi{}e6:pieces{}:",
+        announce.len(),
+        announce,
+        length,
+        name.len(),
+        name,
+        piece_length,
+        pieces.len()
+    )
+    .into_bytes();
+    buf.extend_from_slice(pieces);
+    buf.extend_from_slice(b"ee");
+    buf
+}
+
+/// Bencoded multi-file torrent of `files` (`/` separated path and length) in the
+/// directory `name`
+pub fn multi_file_torrent_bytes(
+    announce: &str,
+    name: &str,
+    files: &[(&str, usize)],
+    piece_length: usize,
+    pieces: &[u8],
+) -> Vec<u8> {
+    let mut buf = format!("d8:announce{}:{}4:infod5:filesl", announce.len(), announce);
+    for (path, length) in files {
+        buf.push_str(&format!("d6:lengthi{}e4:pathl", length));
+        for component in path.split('/') {
+            buf.push_str(&format!("{}:{}", component.len(), component));
+        }
+        buf.push_str("ee");
+    }
+    buf.push_str(&format!(
+        "e4:name{}:{}12:piece lengthi{}e6:pieces{}:",
+        name.len(),
+        name,
+        piece_length,
+        pieces.len()
+    ));
+
+    let mut buf = buf.into_bytes();
+    buf.extend_from_slice(pieces);
+    buf.extend_from_slice(b"ee");
+    buf
+}
+
+/// SHA-1 of every `piece_length` piece of `data`, ready for `torrent_bytes`
+pub fn piece_hashes(data: &[u8], piece_length: usize) -> Vec<u8> {
+    data.chunks(piece_length)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect()
+}
+
+/// Torrent of `data` named `test`
+pub fn test_torrent(announce: &str, piece_length: usize, data: &[u8]) -> TorrentFile {
+    let pieces = piece_hashes(data, piece_length);
+    TorrentFile::try_from(torrent_bytes(
+        announce,
+        "test",
+        data.len(),
+        piece_length,
+        &pieces,
+    ))
+    .unwrap()
+}
+
+/// `rubit-<name>-<random id>` in the temp dir, nothing is created
+pub fn temp_path(name: &str) -> PathBuf {
+    let id: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(char::from)
+        .collect();
+    env::temp_dir().join(format!("rubit-{}-{}", name, id))
+}
+
+/// Context of a one-piece torrent writing into memory, sharing the state of `peer_manager`
+pub fn test_context(peer_manager: &PeerManager) -> PeerContext {
+    let buf = torrent_bytes("test", "test", 4, 4, &[0; 20]);
+
+    PeerContext {
+        global_queue: Arc::new(Mutex::new(VecDeque::from([0]))),
+        torrent_file: Arc::new(TorrentFile::try_from(buf).unwrap()),
+        peer_id: [0; 20],
+        file: Arc::new(Mutex::new(Cursor::new(vec![0u8; 4]))),
+        availability: Arc::clone(&peer_manager.availability),
+        hash_failures: Arc::clone(&peer_manager.hash_failures),
+        verified: Arc::new(AtomicUsize::new(0)),
+        completions: Arc::clone(&peer_manager.completions),
+        peer_stats: Arc::clone(&peer_manager.peer_stats),
+        haves: Arc::clone(&peer_manager.haves),
+        in_progress: Arc::clone(&peer_manager.in_progress),
+        transfer: Arc::clone(&peer_manager.transfer),
+        piece_order: PieceOrder::default(),
+        timeouts: PeerTimeouts::default(),
+        proxy: None,
+        dht: None,
+        shutdown: Arc::clone(&peer_manager.shutdown),
+        events: peer_manager.events.clone(),
+        writer: None,
+        verbose: false,
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs},
+    sync::{Arc, Mutex},
+};
+
+use url::Url;
+
+use crate::http::{serve_http, HttpResponse};
+
+type Swarms = Arc<Mutex<HashMap<[u8; 20], HashSet<SocketAddr>>>>;
+/// `event` of every announce per info hash, empty for regular ones
+type Events = Arc<Mutex<HashMap<[u8; 20], Vec<String>>>>;
+
+/// Minimal HTTP tracker for tests and LAN swarms, keeps the peers of every info hash
+/// in memory and answers announces with compact peer lists (`peers` and `peers6`)
+pub struct TestTracker {
+    local_addr: SocketAddr,
+    swarms: Swarms,
+    events: Events,
+}
+
+impl TestTracker {
+    /// Re-announce interval sent to peers
+    const INTERVAL: u64 = 60;
+
+    /// Serves announces on a background thread for as long as the process runs
+    pub fn spawn(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let swarms = Swarms::default();
+        let events = Events::default();
+
+        let swarms_clone = Arc::clone(&swarms);
+        let events_clone = Arc::clone(&events);
+        serve_http(listener, move |request_line, ip| {
+            let body = match Self::announce(request_line, ip, &swarms_clone, &events_clone) {
+                Ok(body) => body,
+                Err(reason) => {
+                    format!("d14:failure reason{}:{}e", reason.len(), reason).into_bytes()
+                }
+            };
+            HttpResponse {
+                status: "200 OK",
+                content_type: "text/plain",
+                body,
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            swarms,
+            events,
+        })
+    }
+
+    pub fn announce_url(&self) -> Url {
+        Url::parse(&format!("http://{}/announce", self.local_addr)).unwrap()
+    }
+
+    /// Peers currently known for `info_hash`
+    pub fn peers(&self, info_hash: &[u8; 20]) -> HashSet<SocketAddr> {
+        let swarms = self.swarms.lock().unwrap();
+        swarms.get(info_hash).cloned().unwrap_or_default()
+    }
+
+    /// The `event` of every announce for `info_hash` so far, `""` for regular announces
+    pub fn events(&self, info_hash: &[u8; 20]) -> Vec<String> {
+        let events = self.events.lock().unwrap();
+        events.get(info_hash).cloned().unwrap_or_default()
+    }
+
+    fn announce(
+        request_line: &str,
+        ip: IpAddr,
+        swarms: &Swarms,
+        events: &Events,
+    ) -> Result<Vec<u8>, String> {
+        let target = request_line.split(' ').nth(1).ok_or("bad request")?;
+        let (path, query) = target.split_once('?').ok_or("missing announce params")?;
+        if !path.ends_with("/announce") {
+            return Err(String::from("unknown path"));
+        }
+
+        let params: HashMap<String, Vec<u8>> = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (key.to_string(), percent_decode(value)))
+            .collect();
+
+        let info_hash: [u8; 20] = params
+            .get("info_hash")
+            .and_then(|h| h.as_slice().try_into().ok())
+            .ok_or("invalid info_hash")?;
+        let port: u16 = params
+            .get("port")
+            .and_then(|p| String::from_utf8_lossy(p).parse().ok())
+            .ok_or("invalid port")?;
+        let peer = SocketAddr::new(ip, port);
+        let event = params.get("event").map(|e| String::from_utf8_lossy(e));
+        let stopped = event.as_deref() == Some("stopped");
+        events
+            .lock()
+            .unwrap()
+            .entry(info_hash)
+            .or_default()
+            .push(event.unwrap_or_default().into_owned());
+
+        let mut swarms = swarms.lock().unwrap();
+        let swarm = swarms.entry(info_hash).or_default();
+        if stopped {
+            swarm.remove(&peer);
+        } else {
+            swarm.insert(peer);
+        }
+
+        let mut peers = Vec::new();
+        let mut peers6 = Vec::new();
+        for other in swarm.iter().filter(|other| **other != peer) {
+            let list = match other.ip() {
+                IpAddr::V4(ip) => {
+                    peers.extend_from_slice(&ip.octets());
+                    &mut peers
+                }
+                IpAddr::V6(ip) => {
+                    peers6.extend_from_slice(&ip.octets());
+                    &mut peers6
+                }
+            };
+            list.extend_from_slice(&other.port().to_be_bytes());
+        }
+
+        let mut body =
+            format!("d8:intervali{}e5:peers{}:", Self::INTERVAL, peers.len()).into_bytes();
+        body.extend(peers);
+        body.extend(format!("6:peers6{}:", peers6.len()).into_bytes());
+        body.extend(peers6);
+        body.push(b'e');
+        Ok(body)
+    }
+}
+
+/// Query values are raw bytes, e.g. the info hash isn't valid UTF-8
+fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (b, _) => decoded.push(b),
+        }
+        i += 1;
+    }
+
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AnnounceConfig, AnnounceEvent, Responses, Tracker};
+
+    fn config(port: u16, event: AnnounceEvent) -> AnnounceConfig {
+        AnnounceConfig {
+            // a space, '+' and non utf-8 bytes all have to survive the url encoding
+            info_hash: [
+                b' ', b'+', 0xff, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+            ],
+            peer_id: String::from("RB01-aaaaaaaaaaaaaaa"),
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event,
+            num_want: None,
+            tracker_id: None,
+            key: None,
+        }
+    }
+
+    #[test]
+    fn announce_round_trip() {
+        let test_tracker = TestTracker::spawn("127.0.0.1:0").unwrap();
+        let tracker = Tracker::new(test_tracker.announce_url()).unwrap();
+
+        let Responses::Done(first) = tracker
+            .announce(config(6881, AnnounceEvent::Started))
+            .unwrap()
+        else {
+            panic!("expected a Done response");
+        };
+        assert!(first.peers.is_empty());
+
+        let Responses::Done(second) = tracker
+            .announce(config(6882, AnnounceEvent::Started))
+            .unwrap()
+        else {
+            panic!("expected a Done response");
+        };
+        assert_eq!(second.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(second.interval.as_secs(), TestTracker::INTERVAL);
+
+        tracker
+            .announce(config(6881, AnnounceEvent::Stopped))
+            .unwrap();
+        assert_eq!(
+            test_tracker.peers(&config(0, AnnounceEvent::None).info_hash),
+            HashSet::from(["127.0.0.1:6882".parse().unwrap()])
+        );
+    }
+}
@@ -1,59 +1,929 @@
 use std::{
-    collections::{HashSet, VecDeque},
-    fs::File,
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use rubit_bencode::TorrentFile;
+use crate::{Blocklist, ConnError, PeerConnManager, PeerContext};
 
-use crate::PeerConnManager;
+/// Progress of a download, for front-ends embedding rubit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadEvent {
+    PieceCompleted { index: usize },
+    PeerConnected { socket_addr: SocketAddr },
+    PeerDisconnected { socket_addr: SocketAddr },
+    Announced { peers: usize },
+}
+
+/// Sends `event` if anyone listens, a dropped receiver is fine
+pub fn emit_event(events: &Option<Sender<DownloadEvent>>, event: DownloadEvent) {
+    if let Some(sender) = events {
+        let _ = sender.send(event);
+    }
+}
+
+/// What the connected peers can give us of the pieces we still need
+#[derive(Debug, PartialEq, Eq)]
+pub enum SwarmState {
+    NoPeers,
+    Healthy,
+    /// Connected peers don't have these pieces between them,
+    /// the download can't finish until a seed shows up
+    WaitingForSeed {
+        missing: Vec<usize>,
+    },
+}
+
+/// Aggregated pieces of every connected peer
+#[derive(Debug, Default)]
+pub struct Availability {
+    peers: Mutex<HashMap<SocketAddr, HashSet<usize>>>,
+}
+
+impl Availability {
+    pub fn add(&self, socket_addr: SocketAddr, pieces: impl IntoIterator<Item = usize>) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.entry(socket_addr).or_default().extend(pieces);
+    }
+
+    pub fn remove(&self, socket_addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(socket_addr);
+    }
+
+    pub fn swarm_state<'a>(&self, needed: impl IntoIterator<Item = &'a usize>) -> SwarmState {
+        let peers = self.peers.lock().unwrap();
+        if peers.is_empty() {
+            return SwarmState::NoPeers;
+        }
+
+        let mut missing: Vec<usize> = needed
+            .into_iter()
+            .filter(|index| !peers.values().any(|pieces| pieces.contains(index)))
+            .copied()
+            .collect();
+
+        if missing.is_empty() {
+            SwarmState::Healthy
+        } else {
+            missing.sort_unstable();
+            SwarmState::WaitingForSeed { missing }
+        }
+    }
+}
+
+/// Snapshot of what a single peer connection did so far
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    /// Bytes of all the pieces received
+    pub downloaded: u64,
+    /// Bytes per second while downloading the last piece
+    pub rate: f64,
+    pub choked: bool,
+    /// Pieces received, whether or not they passed the hash check
+    pub pieces: usize,
+}
+
+impl Default for PeerStats {
+    fn default() -> Self {
+        Self {
+            downloaded: 0,
+            rate: 0.0,
+            choked: true,
+            pieces: 0,
+        }
+    }
+}
+
+/// Stats of every connected peer
+#[derive(Debug, Default)]
+pub struct PeerStatsTable {
+    peers: Mutex<HashMap<SocketAddr, PeerStats>>,
+}
+
+impl PeerStatsTable {
+    pub fn set_choked(&self, socket_addr: SocketAddr, choked: bool) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.entry(socket_addr).or_default().choked = choked;
+    }
+
+    pub fn add_piece(&self, socket_addr: SocketAddr, bytes: u64, took: Duration) {
+        let mut peers = self.peers.lock().unwrap();
+        let stats = peers.entry(socket_addr).or_default();
+        stats.downloaded += bytes;
+        stats.pieces += 1;
+        stats.rate = bytes as f64 / took.as_secs_f64().max(f64::EPSILON);
+    }
+
+    pub fn remove(&self, socket_addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(socket_addr);
+    }
+
+    pub fn snapshot(&self) -> HashMap<SocketAddr, PeerStats> {
+        self.peers.lock().unwrap().clone()
+    }
+}
+
+/// Who a piece that failed its hash check is blamed on
+#[derive(Debug, PartialEq, Eq)]
+pub enum Blame {
+    /// Only one peer sent a bad copy, most likely that peer's fault
+    Peer,
+    /// Several peers sent bad copies, most likely the torrent/data itself is bad
+    Data,
+}
+
+/// Tracks which peers sent pieces that failed their hash check
+#[derive(Debug)]
+pub struct HashFailures {
+    /// Distinct peers a piece has to fail from before it's blamed on the data
+    pub data_threshold: usize,
+    /// Bad pieces blamed on a single peer before it gets banned
+    pub max_bad_pieces: usize,
+    pieces: Mutex<HashMap<usize, HashSet<SocketAddr>>>,
+}
+
+impl Default for HashFailures {
+    fn default() -> Self {
+        Self::new(2, 3)
+    }
+}
+
+impl HashFailures {
+    pub fn new(data_threshold: usize, max_bad_pieces: usize) -> Self {
+        Self {
+            data_threshold,
+            max_bad_pieces,
+            pieces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, piece_index: usize, socket_addr: SocketAddr) -> Blame {
+        let mut pieces = self.pieces.lock().unwrap();
+        let peers = pieces.entry(piece_index).or_default();
+        peers.insert(socket_addr);
+
+        if peers.len() >= self.data_threshold {
+            Blame::Data
+        } else {
+            Blame::Peer
+        }
+    }
+
+    /// Only pieces blamed on the peer count, so a universally bad piece
+    /// stops counting against everyone once enough peers failed it
+    pub fn is_banned(&self, socket_addr: &SocketAddr) -> bool {
+        let pieces = self.pieces.lock().unwrap();
+        let bad_pieces = pieces
+            .values()
+            .filter(|peers| peers.len() < self.data_threshold && peers.contains(socket_addr))
+            .count();
+
+        bad_pieces >= self.max_bad_pieces
+    }
+}
+
+/// When pieces were verified, in completion order. Keeps at most `capacity` samples:
+/// once full every other sample is dropped and only every `stride`-th piece is kept
+#[derive(Debug)]
+pub struct CompletionTimes {
+    capacity: usize,
+    inner: Mutex<CompletionSamples>,
+}
+
+#[derive(Debug)]
+struct CompletionSamples {
+    times: Vec<(usize, Instant)>,
+    stride: usize,
+    seen: usize,
+}
+
+impl Default for CompletionTimes {
+    fn default() -> Self {
+        Self::new(4096)
+    }
+}
+
+impl CompletionTimes {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            inner: Mutex::new(CompletionSamples {
+                times: Vec::new(),
+                stride: 1,
+                seen: 0,
+            }),
+        }
+    }
+
+    pub fn record(&self, piece_index: usize) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.seen.is_multiple_of(inner.stride) {
+            inner.times.push((piece_index, Instant::now()));
+        }
+        inner.seen += 1;
+
+        if inner.times.len() > self.capacity {
+            let mut position = 0;
+            inner.times.retain(|_| {
+                position += 1;
+                position % 2 == 1
+            });
+            inner.stride *= 2;
+        }
+    }
+
+    pub fn piece_completion_times(&self) -> Vec<(usize, Instant)> {
+        self.inner.lock().unwrap().times.clone()
+    }
+}
+
+/// `Have` senders of every open connection, so a completed piece is announced to all of them
+#[derive(Debug, Default)]
+pub struct HaveBroadcast {
+    senders: Mutex<HashMap<SocketAddr, Sender<usize>>>,
+}
+
+impl HaveBroadcast {
+    /// The connection to `socket_addr` sends a `Have` for every index received
+    pub fn subscribe(&self, socket_addr: SocketAddr) -> Receiver<usize> {
+        let (sender, receiver) = mpsc::channel();
+        self.senders.lock().unwrap().insert(socket_addr, sender);
+        receiver
+    }
+
+    pub fn unsubscribe(&self, socket_addr: &SocketAddr) {
+        self.senders.lock().unwrap().remove(socket_addr);
+    }
+
+    /// Also drops senders whose connection is gone
+    pub fn broadcast(&self, piece_index: usize) {
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain(|_, sender| sender.send(piece_index).is_ok());
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.senders.lock().unwrap().len()
+    }
+}
+
+/// Pieces some connection is downloading right now, so a re-queued copy
+/// isn't fetched by a second peer at the same time. In endgame several connections
+/// download the same piece and the first to complete it wins
+#[derive(Debug, Default)]
+pub struct InProgress {
+    pieces: Mutex<PiecesInProgress>,
+}
+
+#[derive(Debug, Default)]
+struct PiecesInProgress {
+    /// Number of connections downloading each piece
+    downloading: HashMap<usize, usize>,
+    /// Verified by a peer thread, copies still being downloaded are dropped
+    completed: HashSet<usize>,
+}
+
+impl PiecesInProgress {
+    fn finish(&mut self, piece_index: usize) {
+        if let Entry::Occupied(mut connections) = self.downloading.entry(piece_index) {
+            *connections.get_mut() -= 1;
+            if *connections.get() == 0 {
+                connections.remove();
+            }
+        }
+    }
+}
+
+impl InProgress {
+    /// False if another connection already started `piece_index`
+    pub fn try_start(&self, piece_index: usize) -> bool {
+        let mut pieces = self.pieces.lock().unwrap();
+        if pieces.downloading.contains_key(&piece_index) {
+            return false;
+        }
+        pieces.downloading.insert(piece_index, 1);
+        true
+    }
+
+    /// Endgame, also downloads a piece in progress that `peer_pieces` has,
+    /// the one the fewest connections are on
+    pub fn join(&self, peer_pieces: &HashSet<usize>) -> Option<usize> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let PiecesInProgress {
+            downloading,
+            completed,
+        } = &mut *pieces;
+        let (piece_index, connections) = downloading
+            .iter_mut()
+            .filter(|(index, _)| peer_pieces.contains(index) && !completed.contains(index))
+            .min_by_key(|(index, connections)| (**connections, **index))?;
+        *connections += 1;
+        Some(*piece_index)
+    }
+
+    /// Call once the download finished or failed
+    pub fn finish(&self, piece_index: usize) {
+        self.pieces.lock().unwrap().finish(piece_index);
+    }
+
+    /// Call when the download failed, true if the piece goes back to the queue:
+    /// no other connection is still downloading it or completed it
+    pub fn abandon(&self, piece_index: usize) -> bool {
+        let mut pieces = self.pieces.lock().unwrap();
+        pieces.finish(piece_index);
+        !pieces.downloading.contains_key(&piece_index) && !pieces.completed.contains(&piece_index)
+    }
+
+    /// Call once the piece is verified, false if another connection completed it first
+    pub fn complete(&self, piece_index: usize) -> bool {
+        let mut pieces = self.pieces.lock().unwrap();
+        pieces.finish(piece_index);
+        pieces.completed.insert(piece_index)
+    }
+
+    pub fn contains(&self, piece_index: usize) -> bool {
+        self.pieces
+            .lock()
+            .unwrap()
+            .downloading
+            .contains_key(&piece_index)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.lock().unwrap().downloading.is_empty()
+    }
+}
+
+/// What the trackers get as `downloaded`, `uploaded` and `left`. Downloaded counts
+/// every piece received this session (even ones failing their hash), `left` the
+/// bytes of pieces not verified yet and has to be set once the existing data is checked
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Always 0 until seeding is supported
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_left(&self, bytes: u64) {
+        self.left.store(bytes, Ordering::Relaxed);
+    }
+
+    /// A piece of `bytes` was verified and written
+    pub fn piece_verified(&self, bytes: u64) {
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            });
+    }
+}
+
+/// Peers whose connection ended recently, so the next announce
+/// doesn't reconnect to them right away
+#[derive(Debug)]
+pub struct RecentlyTried {
+    pub cooldown: Duration,
+    peers: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl Default for RecentlyTried {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(300))
+    }
+}
+
+impl RecentlyTried {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts the cooldown of `socket_addr`, expired entries are dropped on the way
+    pub fn mark(&self, socket_addr: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|_, tried| tried.elapsed() < self.cooldown);
+        peers.insert(socket_addr, Instant::now());
+    }
+
+    pub fn is_cooling_down(&self, socket_addr: &SocketAddr) -> bool {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(socket_addr)
+            .is_some_and(|tried| tried.elapsed() < self.cooldown)
+    }
+}
+
+/// Connections waiting for a free worker
+struct PeerPool {
+    pending: VecDeque<(SocketAddr, PeerContext)>,
+    workers: usize,
+}
 
 pub struct PeerManager {
     pub peers: Arc<Mutex<HashSet<SocketAddr>>>,
+    pub availability: Arc<Availability>,
+    pub hash_failures: Arc<HashFailures>,
+    pub completions: Arc<CompletionTimes>,
+    pub peer_stats: Arc<PeerStatsTable>,
+    pub haves: Arc<HaveBroadcast>,
+    pub in_progress: Arc<InProgress>,
+    pub transfer: Arc<TransferStats>,
+    /// Peers whose connection failed, skipped by `try_add` until their cooldown ends
+    pub recently_tried: Arc<RecentlyTried>,
+    /// Peers in these ranges are never connected to
+    pub blocklist: Blocklist,
+    /// Peer connections handled at the same time, the rest wait in the pool
+    pub max_threads: usize,
+    /// Shared with every `PeerContext`, see `shutdown`
+    pub shutdown: Arc<AtomicBool>,
+    /// Shared with every `PeerContext`, see `with_events`
+    pub events: Option<Sender<DownloadEvent>>,
+    pool: Arc<Mutex<PeerPool>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Default for PeerManager {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_THREADS)
+    }
 }
 
 impl PeerManager {
-    pub fn new() -> Self {
+    pub const DEFAULT_MAX_THREADS: usize = 50;
+    /// Known peers (connected or waiting for a slot) above which no new peers are announced for
+    pub const DEFAULT_MAX_KNOWN_PEERS: usize = 300;
+
+    pub fn new(max_threads: usize) -> Self {
         Self {
             peers: Arc::new(Mutex::new(HashSet::new())),
+            availability: Arc::new(Availability::default()),
+            hash_failures: Arc::new(HashFailures::default()),
+            completions: Arc::new(CompletionTimes::default()),
+            peer_stats: Arc::new(PeerStatsTable::default()),
+            haves: Arc::new(HaveBroadcast::default()),
+            in_progress: Arc::new(InProgress::default()),
+            transfer: Arc::new(TransferStats::default()),
+            recently_tried: Arc::new(RecentlyTried::default()),
+            blocklist: Blocklist::default(),
+            max_threads: max_threads.max(1),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            events: None,
+            pool: Arc::new(Mutex::new(PeerPool {
+                pending: VecDeque::new(),
+                workers: 0,
+            })),
+            handles: Mutex::new(Vec::new()),
         }
     }
 
-    pub fn try_add(
-        &self,
-        global_queue: Arc<Mutex<VecDeque<usize>>>,
-        socket_addr: SocketAddr,
-        torrent_file: Arc<TorrentFile>,
-        peer_id: [u8; 20],
-        file: Arc<Mutex<File>>,
-        verbose: bool,
-    ) -> Option<JoinHandle<()>> {
-        let mut set = self.peers.lock().unwrap();
-
-        if set.insert(socket_addr) {
-            let peers_clone = Arc::clone(&self.peers);
-            Some(thread::spawn(move || {
-                let mut peer_manager = PeerConnManager::new();
-
-                match peer_manager.handle_peer(
-                    global_queue,
-                    socket_addr,
-                    torrent_file,
-                    peer_id,
-                    file,
-                    verbose,
-                ) {
-                    Err(_) => {
-                        let mut set = peers_clone.lock().unwrap();
-                        set.remove(&socket_addr);
-                    }
-                    _ => (),
-                };
-            }))
-        } else {
-            None
+    /// Same as `new`, with every `DownloadEvent` sent on `events`
+    pub fn with_events(max_threads: usize, events: Sender<DownloadEvent>) -> Self {
+        Self {
+            events: Some(events),
+            ..Self::new(max_threads)
+        }
+    }
+
+    pub fn emit(&self, event: DownloadEvent) {
+        emit_event(&self.events, event);
+    }
+
+    /// Verified pieces with the time they completed at, sampled for huge torrents
+    pub fn piece_completion_times(&self) -> Vec<(usize, Instant)> {
+        self.completions.piece_completion_times()
+    }
+
+    /// Download stats of every connected peer
+    pub fn stats(&self) -> HashMap<SocketAddr, PeerStats> {
+        self.peer_stats.snapshot()
+    }
+
+    /// Whether more than `max_known_peers` peers are connected or waiting for a slot,
+    /// announcing for more is pointless then
+    pub fn has_enough_peers(&self, max_known_peers: usize) -> bool {
+        self.peers.lock().unwrap().len() > max_known_peers
+    }
+
+    /// Worker threads currently running
+    pub fn worker_count(&self) -> usize {
+        self.pool.lock().unwrap().workers
+    }
+
+    /// Queues the connection, it's handled once a worker is free.
+    /// Returns false for known, banned, blocklisted or recently tried peers or after `shutdown`
+    pub fn try_add(&self, socket_addr: SocketAddr, context: PeerContext) -> bool {
+        if self.shutdown.load(Ordering::Relaxed)
+            || self.hash_failures.is_banned(&socket_addr)
+            || self.blocklist.contains(&socket_addr.ip())
+            || self.recently_tried.is_cooling_down(&socket_addr)
+        {
+            return false;
+        }
+
+        if !self.peers.lock().unwrap().insert(socket_addr) {
+            return false;
+        }
+
+        let mut pool = self.pool.lock().unwrap();
+        pool.pending.push_back((socket_addr, context));
+
+        if pool.workers >= self.max_threads {
+            return true;
+        }
+        pool.workers += 1;
+
+        let pool_clone = Arc::clone(&self.pool);
+        let peers_clone = Arc::clone(&self.peers);
+        let recently_tried = Arc::clone(&self.recently_tried);
+        let handle = thread::spawn(move || loop {
+            // Leave the pool in the same lock the queue is checked in,
+            // so try_add never queues a connection no worker will pick up
+            let next = {
+                let mut pool = pool_clone.lock().unwrap();
+                let next = pool.pending.pop_front();
+                if next.is_none() {
+                    pool.workers -= 1;
+                }
+                next
+            };
+            let Some((socket_addr, context)) = next else {
+                break;
+            };
+
+            let availability = Arc::clone(&context.availability);
+            let peer_stats = Arc::clone(&context.peer_stats);
+            let haves = Arc::clone(&context.haves);
+            let events = context.events.clone();
+            let mut peer_manager = PeerConnManager::new();
+
+            let result = peer_manager.handle_peer(socket_addr, context);
+            if result.is_err() {
+                recently_tried.mark(socket_addr);
+                let mut set = peers_clone.lock().unwrap();
+                set.remove(&socket_addr);
+            }
+            // Never connected otherwise
+            if !matches!(result, Err(ConnError::TimeOut)) {
+                emit_event(&events, DownloadEvent::PeerDisconnected { socket_addr });
+            }
+
+            availability.remove(&socket_addr);
+            peer_stats.remove(&socket_addr);
+            haves.unsubscribe(&socket_addr);
+        });
+        self.handles.lock().unwrap().push(handle);
+
+        true
+    }
+
+    /// Waits for every worker to finish
+    pub fn join(&self) {
+        let handles = std::mem::take(&mut *self.handles.lock().unwrap());
+        for handle in handles {
+            let _ = handle.join();
+        }
+    }
+
+    /// Tells every connection to stop (they check between blocks), drops the queued ones
+    /// and waits up to `timeout` for the workers. Returns false if some are still running
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        self.shutdown.store(true, Ordering::Relaxed);
+        self.pool.lock().unwrap().pending.clear();
+
+        let deadline = Instant::now() + timeout;
+        let mut handles = self.handles.lock().unwrap();
+
+        while Instant::now() < deadline {
+            let (finished, running): (Vec<_>, Vec<_>) =
+                handles.drain(..).partition(|h| h.is_finished());
+            *handles = running;
+            for handle in finished {
+                let _ = handle.join();
+            }
+
+            if handles.is_empty() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        handles.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_context;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn reports_pieces_no_peer_has() {
+        let availability = Availability::default();
+        assert_eq!(availability.swarm_state(&[0, 1]), SwarmState::NoPeers);
+
+        availability.add("127.0.0.1:6881".parse().unwrap(), [0, 1]);
+        availability.add("127.0.0.2:6881".parse().unwrap(), [1, 3]);
+
+        assert_eq!(availability.swarm_state(&[0, 1, 3]), SwarmState::Healthy);
+        assert_eq!(
+            availability.swarm_state(&VecDeque::from([3, 2, 0])),
+            SwarmState::WaitingForSeed { missing: vec![2] }
+        );
+
+        availability.remove(&"127.0.0.1:6881".parse().unwrap());
+        assert_eq!(
+            availability.swarm_state(&[0, 1]),
+            SwarmState::WaitingForSeed { missing: vec![0] }
+        );
+    }
+
+    #[test]
+    fn bans_only_peers_blamed_for_bad_pieces() {
+        let hash_failures = HashFailures::new(2, 2);
+        let bad_peer: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let other_peer: SocketAddr = "127.0.0.2:6881".parse().unwrap();
+
+        // Piece 0 is bad for everyone, piece 1 only from bad_peer
+        assert_eq!(hash_failures.record(0, bad_peer), Blame::Peer);
+        assert_eq!(hash_failures.record(0, other_peer), Blame::Data);
+        assert_eq!(hash_failures.record(1, bad_peer), Blame::Peer);
+
+        assert!(!hash_failures.is_banned(&bad_peer));
+        assert!(!hash_failures.is_banned(&other_peer));
+
+        assert_eq!(hash_failures.record(2, bad_peer), Blame::Peer);
+
+        assert!(hash_failures.is_banned(&bad_peer));
+        assert!(!hash_failures.is_banned(&other_peer));
+    }
+
+    #[test]
+    fn timestamps_completions_in_order() {
+        let completions = CompletionTimes::new(4);
+        for index in [7, 3, 5] {
+            completions.record(index);
+        }
+
+        let times = completions.piece_completion_times();
+        assert_eq!(
+            times.iter().map(|(index, _)| *index).collect::<Vec<_>>(),
+            vec![7, 3, 5]
+        );
+        assert!(times.windows(2).all(|w| w[0].1 <= w[1].1));
+
+        // Samples stay evenly spread and bounded
+        let completions = CompletionTimes::new(4);
+        for index in 0..100 {
+            completions.record(index);
+        }
+
+        let indices: Vec<usize> = completions
+            .piece_completion_times()
+            .iter()
+            .map(|(index, _)| *index)
+            .collect();
+        assert_eq!(indices, vec![0, 32, 64, 96]);
+    }
+
+    #[test]
+    fn counts_transferred_bytes() {
+        let transfer = TransferStats::default();
+        transfer.set_left(10);
+        transfer.add_downloaded(4);
+        transfer.add_downloaded(4);
+        transfer.piece_verified(4);
+
+        assert_eq!(transfer.downloaded(), 8);
+        assert_eq!(transfer.left(), 6);
+        assert_eq!(transfer.uploaded(), 0);
+        transfer.piece_verified(8);
+        assert_eq!(transfer.left(), 0);
+    }
+
+    #[test]
+    fn broadcasts_haves_to_open_connections() {
+        let haves = HaveBroadcast::default();
+        let first = haves.subscribe("127.0.0.1:6881".parse().unwrap());
+        let second = haves.subscribe("127.0.0.1:6882".parse().unwrap());
+
+        haves.broadcast(3);
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), vec![3]);
+        assert_eq!(second.try_iter().collect::<Vec<_>>(), vec![3]);
+
+        // The second connection is gone
+        std::mem::drop(second);
+        haves.broadcast(4);
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), vec![4]);
+        assert_eq!(haves.subscribers(), 1);
+    }
+
+    #[test]
+    fn caps_peer_threads_and_drains_the_pool() {
+        let peer_manager = PeerManager::new(2);
+        let context = test_context(&peer_manager);
+
+        // Peers accept but stay silent until the gate opens, then hang up
+        let gate = Arc::new(Mutex::new(()));
+        let closed = gate.lock().unwrap();
+        let addresses: Vec<SocketAddr> = (0..5)
+            .map(|_| {
+                let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+                let socket_addr = listener.local_addr().unwrap();
+                let gate = Arc::clone(&gate);
+                thread::spawn(move || {
+                    let _stream = listener.accept().unwrap();
+                    drop(gate.lock().unwrap());
+                });
+                socket_addr
+            })
+            .collect();
+
+        for socket_addr in addresses {
+            assert!(peer_manager.try_add(socket_addr, context.clone()));
         }
+        assert_eq!(peer_manager.worker_count(), 2);
+
+        drop(closed);
+        peer_manager.join();
+
+        assert_eq!(peer_manager.worker_count(), 0);
+        assert!(peer_manager.peers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn has_enough_peers_above_the_limit() {
+        let peer_manager = PeerManager::new(1);
+        assert!(!peer_manager.has_enough_peers(0));
+
+        peer_manager.peers.lock().unwrap().extend([
+            "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "10.0.0.2:6881".parse().unwrap(),
+        ]);
+        assert!(peer_manager.has_enough_peers(1));
+        assert!(!peer_manager.has_enough_peers(2));
+    }
+
+    #[test]
+    fn rejects_blocklisted_peers() {
+        let mut peer_manager = PeerManager::new(1);
+        peer_manager.blocklist = Blocklist::parse("Anti-P2P:10.0.0.0-10.255.255.255\n");
+        let context = test_context(&peer_manager);
+
+        assert!(!peer_manager.try_add("10.1.2.3:6881".parse().unwrap(), context.clone()));
+        assert!(!peer_manager.try_add("[::ffff:10.1.2.3]:6881".parse().unwrap(), context.clone()));
+        assert!(peer_manager.peers.lock().unwrap().is_empty());
+        assert_eq!(peer_manager.worker_count(), 0);
+
+        assert!(peer_manager.try_add("127.0.0.1:1".parse().unwrap(), context));
+        peer_manager.join();
+    }
+
+    #[test]
+    fn waits_out_the_cooldown_before_retrying_a_peer() {
+        let mut peer_manager = PeerManager::new(1);
+        peer_manager.recently_tried = Arc::new(RecentlyTried::new(Duration::from_millis(200)));
+        let context = test_context(&peer_manager);
+        // Nothing listens there
+        let socket_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        assert!(peer_manager.try_add(socket_addr, context.clone()));
+        peer_manager.join();
+        assert!(!peer_manager.try_add(socket_addr, context.clone()));
+
+        thread::sleep(Duration::from_millis(250));
+        assert!(peer_manager.try_add(socket_addr, context));
+        peer_manager.join();
+    }
+
+    #[test]
+    fn shutdown_drops_queued_peers_and_joins_workers() {
+        let peer_manager = PeerManager::new(1);
+        let context = test_context(&peer_manager);
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        // Peers hang up after a while without saying anything
+        for _ in 0..3 {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let socket_addr = listener.local_addr().unwrap();
+            let accepted = Arc::clone(&accepted);
+            thread::spawn(move || {
+                let _stream = listener.accept().unwrap();
+                accepted.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(300));
+            });
+            assert!(peer_manager.try_add(socket_addr, context.clone()));
+        }
+
+        // Let the only worker take the first peer
+        while accepted.load(Ordering::Relaxed) == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(peer_manager.shutdown(Duration::from_secs(2)));
+        assert_eq!(peer_manager.worker_count(), 0);
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+        assert!(!peer_manager.try_add("127.0.0.1:1".parse().unwrap(), context));
+    }
+
+    #[test]
+    fn accumulates_peer_stats() {
+        let stats = PeerStatsTable::default();
+        let peer: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        stats.set_choked(peer, false);
+        stats.add_piece(peer, 16384, Duration::from_secs(2));
+        stats.add_piece(peer, 16384, Duration::from_secs(1));
+
+        assert_eq!(
+            stats.snapshot().get(&peer),
+            Some(&PeerStats {
+                downloaded: 32768,
+                rate: 16384.0,
+                choked: false,
+                pieces: 2,
+            })
+        );
+
+        stats.remove(&peer);
+        assert!(stats.snapshot().is_empty());
+    }
+
+    #[test]
+    fn emits_peer_and_announce_events() {
+        let (sender, events) = std::sync::mpsc::channel();
+        let peer_manager = PeerManager::with_events(1, sender);
+        let context = test_context(&peer_manager);
+
+        // Hangs up right after accepting
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        thread::spawn(move || drop(listener.accept().unwrap()));
+
+        assert!(peer_manager.try_add(socket_addr, context));
+        peer_manager.join();
+        peer_manager.emit(DownloadEvent::Announced { peers: 3 });
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                DownloadEvent::PeerConnected { socket_addr },
+                DownloadEvent::PeerDisconnected { socket_addr },
+                DownloadEvent::Announced { peers: 3 },
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_silent_peers_after_the_handshake_timeout() {
+        let peer_manager = PeerManager::new(1);
+        let mut context = test_context(&peer_manager);
+        context.timeouts.handshake = Duration::from_millis(100);
+
+        // Accepts and never answers the handshake
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(5));
+        });
+
+        let started = Instant::now();
+        assert!(peer_manager.try_add(socket_addr, context));
+        peer_manager.join();
+
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(peer_manager.peers.lock().unwrap().is_empty());
     }
 }
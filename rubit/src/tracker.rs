@@ -1,19 +1,27 @@
 use std::{
     array::TryFromSliceError,
     borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
     io::{self, Write},
-    net::{SocketAddr, UdpSocket},
-    time::Duration,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
 use rand::{random, thread_rng, Rng};
-use rubit_bencode::{decode_dict, unwrap_integer, unwrap_peers, unwrap_string, Peers};
+use rubit_bencode::{
+    decode_dict, parse_compact_peers, unwrap_bytes, unwrap_bytes_dict, unwrap_dict, unwrap_integer,
+    unwrap_peers, unwrap_string, BencodeTypes, Peers, COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN,
+};
 use url::{form_urlencoded, Url};
 
+use crate::{Proxy, ProxyKind};
+
 #[derive(Debug)]
 pub enum TrackerError {
     Bencode(rubit_bencode::ParseError),
-    Http(ureq::Error),
+    Http(Box<ureq::Error>),
     Io(io::Error),
     Slice(TryFromSliceError),
     FailedDecode,
@@ -21,6 +29,30 @@ pub enum TrackerError {
     UnknownTrackerProtocol,
     MissMatchAction,
     MissMatchTransactionId,
+    RateLimited,
+    ScrapeNotSupported,
+    /// UDP trackers can't be reached through the proxy
+    ProxyUnsupported,
+    Timeout,
+    /// Error message sent by a UDP tracker (action = 3)
+    UdpError(String),
+    /// The thread running an async announce or scrape panicked
+    Panicked,
+}
+
+impl TrackerError {
+    /// Errors that may go away by trying again: timeouts, dropped connections
+    /// and 5xx/429 answers. Bad responses and tracker errors won't
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Timeout | Self::Io(_) => true,
+            Self::Http(e) => match e.as_ref() {
+                ureq::Error::Status(status, _) => *status >= 500 || *status == 429,
+                ureq::Error::Transport(_) => true,
+            },
+            _ => false,
+        }
+    }
 }
 
 impl From<rubit_bencode::ParseError> for TrackerError {
@@ -31,7 +63,7 @@ impl From<rubit_bencode::ParseError> for TrackerError {
 
 impl From<ureq::Error> for TrackerError {
     fn from(value: ureq::Error) -> Self {
-        Self::Http(value)
+        Self::Http(Box::new(value))
     }
 }
 
@@ -47,7 +79,84 @@ impl From<TryFromSliceError> for TrackerError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnnounceEvent {
+    /// Regular announce done at the interval
+    #[default]
+    None,
+    /// First announce of a download
+    Started,
+    /// The client is shutting down
+    Stopped,
+    /// The download finished
+    Completed,
+}
+
+impl AnnounceEvent {
+    /// Value of the `event` query param, `None` sends no param at all
+    fn as_http_param(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+            AnnounceEvent::Completed => Some("completed"),
+        }
+    }
+
+    /// https://www.bittorrent.org/beps/bep_0015.html
+    fn as_udp_value(&self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
+/// What the trackers were told about us. There is no `paused` event,
+/// the convention is to send `stopped` on pause and `started` again on resume
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnnounceState {
+    #[default]
+    NotStarted,
+    Running,
+    Paused,
+}
+
+impl AnnounceState {
+    /// Event for the next regular announce, `None` while paused (nothing should be sent)
+    pub fn event(&self) -> Option<AnnounceEvent> {
+        match self {
+            AnnounceState::NotStarted => Some(AnnounceEvent::Started),
+            AnnounceState::Running => Some(AnnounceEvent::None),
+            AnnounceState::Paused => None,
+        }
+    }
+
+    /// Call after a regular announce went through
+    pub fn announced(&mut self) {
+        if *self == AnnounceState::NotStarted {
+            *self = AnnounceState::Running;
+        }
+    }
+
+    /// Returns the event to send, only trackers that know about us need a `stopped`
+    pub fn pause(&mut self) -> Option<AnnounceEvent> {
+        let was_running = *self == AnnounceState::Running;
+        *self = AnnounceState::Paused;
+        was_running.then_some(AnnounceEvent::Stopped)
+    }
+
+    /// The next regular announce sends `started` again
+    pub fn resume(&mut self) {
+        if *self == AnnounceState::Paused {
+            *self = AnnounceState::NotStarted;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct AnnounceConfig {
     pub info_hash: [u8; 20],
     pub peer_id: String,
@@ -55,6 +164,15 @@ pub struct AnnounceConfig {
     pub uploaded: u64,
     pub downloaded: u64,
     pub left: u64,
+    pub event: AnnounceEvent,
+    /// Number of peers to ask for, `None` lets the tracker decide
+    pub num_want: Option<i32>,
+    /// `tracker id` of an earlier response, sent back as `trackerid`.
+    /// `None` sends the one the tracker remembered from its last response, if any
+    pub tracker_id: Option<String>,
+    /// Sent as `key` so trackers recognize us after an IP change, should stay the same
+    /// for the whole session. `None` sends none over HTTP and a random one over UDP
+    pub key: Option<u32>,
 }
 
 #[derive(Debug)]
@@ -77,44 +195,142 @@ pub struct OkResponse {
     /// Leechers number
     pub incomplete: Option<u64>,
     pub peers: Peers,
+    /// To be sent back on the next announces, HTTP trackers only
+    pub tracker_id: Option<String>,
+    /// Sent with an otherwise successful HTTP response, e.g. "your client is outdated"
+    pub warning_message: Option<String>,
+    /// Our address as the tracker sees it, HTTP trackers only
+    /// https://www.bittorrent.org/beps/bep_0024.html
+    pub external_ip: Option<IpAddr>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u64,
+    pub completed: u64,
+    pub leechers: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UrlProtocol {
     UDP,
     HTTP,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Tracker {
     pub url: Url,
     pub protocol: UrlProtocol,
+    /// First UDP receive timeout, doubled on every retransmit
+    pub udp_timeout: Duration,
+    pub udp_max_retries: u32,
+    /// UDP requests give up at this point even with retries left,
+    /// `announce_tiers` sets it so its abandoned threads don't keep retransmitting
+    pub udp_deadline: Option<Instant>,
+    /// Sent with every HTTP request after `Accept: */*`,
+    /// some CDN-fronted trackers reject requests without the headers they expect
+    pub extra_headers: Vec<(String, String)>,
+    pub proxy: Option<Proxy>,
+    /// Last `tracker id` received, kept by `announce_tiers`
+    pub tracker_id: Option<String>,
+    /// UDP connection id with the address it's for and when it was received,
+    /// shared by clones since `announce_all` announces on clones
+    udp_connection: Arc<Mutex<Option<(SocketAddr, u64, Instant)>>>,
 }
 
 impl Tracker {
     const UDP_MAGIC_CONSTANT: u64 = 0x41727101980;
+    /// Connect and read timeout of requests tunneled through a SOCKS5 proxy
+    const PROXY_TIMEOUT: Duration = Duration::from_secs(15);
+    /// Sent with every HTTP request, `extra_headers` can replace it
+    const USER_AGENT: &'static str = concat!("rubit/", env!("CARGO_PKG_VERSION"));
+    /// https://www.bittorrent.org/beps/bep_0015.html: clients may reuse a connection id
+    /// for one minute, trackers accept it for two
+    const UDP_CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
 
     pub fn new(url: Url) -> Result<Self, TrackerError> {
         let protocol = match url.scheme() {
             "http" => UrlProtocol::HTTP,
             "https" => UrlProtocol::HTTP,
             "udp" => UrlProtocol::UDP,
-            _ => panic!("unknown tracker protocl!"),
+            _ => return Err(TrackerError::UnknownTrackerProtocol),
         };
 
-        Ok(Self { url, protocol })
+        Ok(Self {
+            url,
+            protocol,
+            // https://www.bittorrent.org/beps/bep_0015.html: 15 * 2 ^ n seconds. The BEP goes
+            // up to n = 8 (over an hour), 3 gives up on a dead tracker after under 4 minutes
+            udp_timeout: Duration::from_secs(15),
+            udp_max_retries: 3,
+            udp_deadline: None,
+            extra_headers: Vec::new(),
+            proxy: None,
+            tracker_id: None,
+            udp_connection: Arc::new(Mutex::new(None)),
+        })
     }
 
     pub fn announce(&self, config: AnnounceConfig) -> Result<Responses, TrackerError> {
         match self.protocol {
             UrlProtocol::HTTP => self.announce_http(config),
+            UrlProtocol::UDP if self.proxy().is_some() => Err(TrackerError::ProxyUnsupported),
             UrlProtocol::UDP => self.announce_udp(config),
         }
     }
 
+    /// Gets the swarm stats of each of `info_hashes` (in the same order)
+    /// without announcing
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        match self.protocol {
+            UrlProtocol::HTTP => self.scrape_http(info_hashes),
+            UrlProtocol::UDP if self.proxy().is_some() => Err(TrackerError::ProxyUnsupported),
+            UrlProtocol::UDP => self.scrape_udp(info_hashes),
+        }
+    }
+
+    /// Same as `announce` but waits for the `AnnounceLimiter` first, returns
+    /// `TrackerError::RateLimited` without sending anything if `deadline` passes meanwhile
+    pub fn announce_limited(
+        &self,
+        config: AnnounceConfig,
+        limiter: &AnnounceLimiter,
+        deadline: Instant,
+    ) -> Result<Responses, TrackerError> {
+        let Some(_permit) = limiter.acquire_before(self, deadline) else {
+            return Err(TrackerError::RateLimited);
+        };
+
+        self.announce(config)
+    }
+
+    /// `announce` tried up to `attempts` times, waiting `base_delay` doubled after every
+    /// failure (with up to half of it as random jitter so clients don't retry in lockstep).
+    /// Only transient errors are retried, a `Responses::Failure` is returned right away
+    pub fn announce_with_retry(
+        &self,
+        config: AnnounceConfig,
+        attempts: u32,
+        base_delay: Duration,
+    ) -> Result<Responses, TrackerError> {
+        let mut delay = base_delay;
+        let mut attempt = 1;
+        loop {
+            match self.announce(config.clone()) {
+                Err(e) if e.is_transient() && attempt < attempts => {
+                    let jitter = thread_rng().gen_range(0.0..0.5);
+                    thread::sleep(delay.mul_f64(1.0 - jitter));
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
     fn decode_http_response(&self, response: Vec<u8>) -> Option<Responses> {
         let mut pointer = 0;
-        let mut dict = decode_dict(&mut pointer, &response).expect("failed to decode response");
+        let mut dict = decode_dict(&mut pointer, &response).ok()?;
 
         if dict.contains_key("failure reason") {
             let failure_reason = unwrap_string(dict.remove("failure reason")?)?;
@@ -138,7 +354,29 @@ impl Tracker {
             None => None,
         };
 
-        let peers = unwrap_peers(dict.remove("peers")?)?;
+        // IPv6 only trackers may send `peers6` without `peers`
+        let mut peers = match (dict.remove("peers"), dict.remove("peers6")) {
+            (None, None) => return None,
+            (peers, peers6) => {
+                let mut all = Vec::new();
+                for p in [peers, peers6].into_iter().flatten() {
+                    all.extend(unwrap_peers(p)?);
+                }
+                all
+            }
+        };
+        peers.retain(is_valid_peer);
+
+        let tracker_id = dict.remove("tracker id").and_then(unwrap_string);
+        let warning_message = dict.remove("warning message").and_then(unwrap_string);
+        let external_ip = dict
+            .remove("external ip")
+            .and_then(unwrap_bytes)
+            .and_then(|ip| match ip.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).ok()?)),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip).ok()?)),
+                _ => None,
+            });
 
         Some(Responses::Done(OkResponse {
             interval,
@@ -146,18 +384,44 @@ impl Tracker {
             complete,
             incomplete,
             peers,
+            tracker_id,
+            warning_message,
+            external_ip,
         }))
     }
 
-    fn announce_http(&self, config: AnnounceConfig) -> Result<Responses, TrackerError> {
+    /// Builds the full announce url, `Url` keeps the brackets of IPv6 literal hosts
+    /// e.g. `http://[2001:db8::1]:80/announce`
+    fn http_announce_url(&self, config: &AnnounceConfig) -> String {
         // necessary get request params
-        let params = form_urlencoded::Serializer::new(String::new())
+        let mut params = form_urlencoded::Serializer::new(String::new());
+        params
             .append_pair("peer_id", &config.peer_id)
             .append_pair("port", &config.port.to_string())
             .append_pair("left", &config.left.to_string())
             .append_pair("uploaded", &config.uploaded.to_string())
             .append_pair("downloaded", &config.downloaded.to_string())
+            // Trackers ignoring `compact` send the dictionary form, `unwrap_peers` takes both
             .append_pair("compact", "1")
+            .append_pair("no_peer_id", "1");
+
+        if let Some(event) = config.event.as_http_param() {
+            params.append_pair("event", event);
+        }
+
+        if let Some(num_want) = config.num_want {
+            params.append_pair("numwant", &num_want.to_string());
+        }
+
+        if let Some(tracker_id) = config.tracker_id.as_ref().or(self.tracker_id.as_ref()) {
+            params.append_pair("trackerid", tracker_id);
+        }
+
+        if let Some(key) = config.key {
+            params.append_pair("key", &format!("{:08x}", key));
+        }
+
+        let params = params
             // a hack to convert info hash to its encoded form needed in:
             // https://www.bittorrent.org/beps/bep_0003.html
             .encoding_override(Some(&|input| {
@@ -170,12 +434,70 @@ impl Tracker {
             .append_pair("info_hash", "!")
             .finish();
 
-        // get request
+        format!("{}?{}", self.url, params)
+    }
+
+    /// `proxy` unless `NO_PROXY` exempts this tracker
+    /// Local address the OS would reach the tracker from, nothing is sent.
+    /// `None` if the host doesn't resolve
+    pub fn local_ip(&self) -> Option<IpAddr> {
+        let remote = self
+            .url
+            .socket_addrs(|| Some(80))
+            .ok()?
+            .into_iter()
+            .next()?;
+        let socket = Self::bind_udp(SocketAddr::new(remote.ip(), 0)).ok()?;
+        socket.connect(remote).ok()?;
+        Some(socket.local_addr().ok()?.ip())
+    }
+
+    fn proxy(&self) -> Option<&Proxy> {
+        self.proxy
+            .as_ref()
+            .filter(|proxy| proxy.applies_to(self.url.host_str().unwrap_or_default()))
+    }
+
+    /// Body of a GET to `url`, through `proxy` if set
+    fn http_get(&self, url: &str) -> Result<Vec<u8>, TrackerError> {
+        let agent = match self.proxy() {
+            Some(proxy) if proxy.kind == ProxyKind::Socks5 => {
+                // ureq is built without SOCKS support
+                let url = Url::parse(url).map_err(|_| TrackerError::UnknownTrackerProtocol)?;
+                let mut headers = self.extra_headers.clone();
+                if !headers
+                    .iter()
+                    .any(|(name, _)| name.eq_ignore_ascii_case("User-Agent"))
+                {
+                    headers.push((String::from("User-Agent"), Self::USER_AGENT.to_string()));
+                }
+                return Ok(proxy.http_get(&url, &headers, Self::PROXY_TIMEOUT)?);
+            }
+            Some(proxy) => ureq::AgentBuilder::new()
+                .proxy(ureq::Proxy::new(proxy.to_url())?)
+                .build(),
+            None => ureq::agent(),
+        };
+
+        let request = self.extra_headers.iter().fold(
+            agent
+                .get(url)
+                .set("Accept", "*/*")
+                .set("User-Agent", Self::USER_AGENT),
+            |request, (name, value)| request.set(name, value),
+        );
+
         let mut response_buf = Vec::new();
-        ureq::get(&format!("{}?{}", &self.url.to_string(), params))
+        request
             .call()?
             .into_reader()
             .read_to_end(&mut response_buf)?;
+        Ok(response_buf)
+    }
+
+    fn announce_http(&self, config: AnnounceConfig) -> Result<Responses, TrackerError> {
+        // get request
+        let response_buf = self.http_get(&self.http_announce_url(&config))?;
 
         // decode the bencode response
         match self.decode_http_response(response_buf) {
@@ -184,10 +506,149 @@ impl Tracker {
         }
     }
 
-    fn connect_udp(&self, receiver_ip: SocketAddr) -> Result<u64, TrackerError> {
+    /// https://www.bittorrent.org/beps/bep_0048.html
+    /// The scrape url replaces the `announce` at the start of the last path segment
+    /// with `scrape`, trackers without it don't support scraping
+    fn http_scrape_url(&self, info_hashes: &[[u8; 20]]) -> Result<String, TrackerError> {
+        let mut url = self.url.clone();
+
+        let path = url.path().to_string();
+        let (base, last_segment) = path.rsplit_once('/').unwrap_or(("", &path));
+        let Some(rest) = last_segment.strip_prefix("announce") else {
+            return Err(TrackerError::ScrapeNotSupported);
+        };
+        url.set_path(&format!("{}/scrape{}", base, rest));
+
+        let params: Vec<String> = info_hashes
+            .iter()
+            .map(|info_hash| {
+                format!(
+                    "info_hash={}",
+                    form_urlencoded::byte_serialize(info_hash).collect::<String>()
+                )
+            })
+            .collect();
+
+        // Keep any existing query e.g. a passkey
+        let separator = if url.query().is_some() { '&' } else { '?' };
+
+        Ok(format!("{}{}{}", url, separator, params.join("&")))
+    }
+
+    fn decode_http_scrape(
+        &self,
+        response: Vec<u8>,
+        info_hashes: &[[u8; 20]],
+    ) -> Option<Vec<ScrapeStats>> {
+        let mut pointer = 0;
+        let mut dict = decode_dict(&mut pointer, &response).ok()?;
+        let mut files = unwrap_scrape_files(dict.remove("files")?)?;
+
+        let get_stat = |stats: &mut HashMap<String, _>, key: &str| match stats.remove(key) {
+            Some(i) => unwrap_integer(i).unwrap_or(0),
+            None => 0,
+        };
+
+        // Torrents unknown to the tracker are left out of the response
+        Some(
+            info_hashes
+                .iter()
+                .map(|info_hash| match files.get_mut(info_hash) {
+                    Some(stats) => ScrapeStats {
+                        seeders: get_stat(stats, "complete"),
+                        completed: get_stat(stats, "downloaded"),
+                        leechers: get_stat(stats, "incomplete"),
+                    },
+                    None => ScrapeStats {
+                        seeders: 0,
+                        completed: 0,
+                        leechers: 0,
+                    },
+                })
+                .collect(),
+        )
+    }
+
+    fn scrape_http(&self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let response_buf = self.http_get(&self.http_scrape_url(info_hashes)?)?;
+
+        match self.decode_http_scrape(response_buf, info_hashes) {
+            None => Err(TrackerError::FailedDecode),
+            Some(s) => Ok(s),
+        }
+    }
+
+    /// Sends `write_buf` and waits for the response, retransmitting with
+    /// an exponentially growing timeout since UDP packets can get lost
+    fn udp_request(
+        &self,
+        socket: &UdpSocket,
+        write_buf: &[u8],
+        receiver_ip: SocketAddr,
+        rec_buf: &mut [u8],
+    ) -> Result<usize, TrackerError> {
+        for n in 0..=self.udp_max_retries {
+            let mut timeout = self.udp_timeout * 2u32.pow(n);
+            if let Some(deadline) = self.udp_deadline {
+                timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+            }
+            // A zero read timeout is an error
+            if timeout.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(timeout))?;
+            socket.send_to(write_buf, receiver_ip)?;
+
+            match socket.recv_from(rec_buf) {
+                Ok((len, _)) => return Ok(len),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Err(TrackerError::Timeout)
+    }
+
+    /// Action 3 = error, a human-readable message follows the transaction id
+    fn udp_error_message(rec_buf: &[u8]) -> Option<String> {
+        if rec_buf.len() < 8 || rec_buf[0..4] != 3u32.to_be_bytes() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&rec_buf[8..]).to_string())
+    }
+
+    /// Socket on a random port of the tracker's address family,
+    /// a v4 socket can't reach an IPv6 tracker
+    fn bind_udp(receiver_ip: SocketAddr) -> io::Result<UdpSocket> {
         let port = thread_rng().gen_range(1025..u16::MAX);
+        match receiver_ip {
+            SocketAddr::V4(_) => UdpSocket::bind(("0.0.0.0", port)),
+            SocketAddr::V6(_) => UdpSocket::bind(("::", port)),
+        }
+    }
+
+    /// The first resolved address of the tracker a socket can be bound for,
+    /// e.g. an IPv6 record is skipped on a host without IPv6
+    fn udp_receiver(&self) -> Result<(SocketAddr, UdpSocket), TrackerError> {
+        let mut error = io::Error::new(io::ErrorKind::NotFound, "tracker host has no address");
+        for receiver_ip in self.url.socket_addrs(|| None)? {
+            match Self::bind_udp(receiver_ip) {
+                Ok(socket) => return Ok((receiver_ip, socket)),
+                Err(e) => error = e,
+            }
+        }
+        Err(error.into())
+    }
 
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", port))?;
+    fn connect_udp(&self, receiver_ip: SocketAddr) -> Result<u64, TrackerError> {
+        let socket = Self::bind_udp(receiver_ip)?;
 
         let transaction_id: u32 = random();
 
@@ -197,17 +658,15 @@ impl Tracker {
         write_buf.write_all(&0u32.to_be_bytes())?;
         write_buf.write_all(&transaction_id.to_be_bytes())?;
 
-        socket.send_to(&write_buf, receiver_ip)?;
-
         let mut rec_buf = [0u8; 2048];
-        let mut retries = 0;
+        let len = self.udp_request(&socket, &write_buf, receiver_ip, &mut rec_buf)?;
+        let rec_buf = &rec_buf[..len];
 
-        while retries < 5 {
-            socket.recv_from(&mut rec_buf)?;
-            if rec_buf != [0u8; 2048] {
-                break;
-            }
-            retries += 1;
+        if let Some(message) = Self::udp_error_message(rec_buf) {
+            return Err(TrackerError::UdpError(message));
+        }
+        if rec_buf.len() < 16 {
+            return Err(TrackerError::FailedDecode);
         }
 
         let action = u32::from_be_bytes(rec_buf[0..4].try_into()?);
@@ -224,68 +683,118 @@ impl Tracker {
         Ok(connection_id)
     }
 
-    /// https://www.bittorrent.org/beps/bep_0015.html
-    fn announce_udp(&self, config: AnnounceConfig) -> Result<Responses, TrackerError> {
-        let receiver_ip = self.url.socket_addrs(|| None)?[0];
-
-        let port = thread_rng().gen_range(1025..u16::MAX);
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", port))?;
+    /// Cached connection id while it's fresh, a new one from `connect_udp` otherwise.
+    /// The bool says whether it came from the cache
+    fn udp_connection_id(&self, receiver_ip: SocketAddr) -> Result<(u64, bool), TrackerError> {
+        if let Some((addr, connection_id, received)) = *self.udp_connection.lock().unwrap() {
+            if addr == receiver_ip && received.elapsed() < Self::UDP_CONNECTION_ID_TTL {
+                return Ok((connection_id, true));
+            }
+        }
 
         let connection_id = self.connect_udp(receiver_ip)?;
-        let transaction_id: u32 = random();
-        let key: u32 = random();
-
-        let mut write_buf = Vec::new();
-        write_buf.write_all(&connection_id.to_be_bytes())?;
-        // Action: 1 = announce
-        write_buf.write_all(&1u32.to_be_bytes())?;
-        write_buf.write_all(&transaction_id.to_be_bytes())?;
-        write_buf.write_all(&config.info_hash)?;
-        write_buf.write_all(&config.peer_id.as_bytes())?;
-        write_buf.write_all(&config.downloaded.to_be_bytes())?;
-        write_buf.write_all(&config.left.to_be_bytes())?;
-        write_buf.write_all(&config.uploaded.to_be_bytes())?;
-        // Event: 0 = None
-        write_buf.write_all(&0u32.to_be_bytes())?;
-        // Ip Adress: 0 = default
-        // Is specified in certian cases when the client is behind some kind of proxy
-        write_buf.write_all(&0u32.to_be_bytes())?;
-        write_buf.write_all(&key.to_be_bytes())?;
-        // num_want: -1 = default
-        // Specifies the number of peers to return -1 means as much as u can
-        write_buf.write_all(&i32::from(-1).to_be_bytes())?;
-        write_buf.write_all(&config.port.to_be_bytes())?;
+        *self.udp_connection.lock().unwrap() = Some((receiver_ip, connection_id, Instant::now()));
+        Ok((connection_id, false))
+    }
 
-        socket.send_to(&write_buf, receiver_ip)?;
+    /// Runs `request` with a connection id. A failed request drops the id, and one made
+    /// with a cached id that got an error action back (`is_error_action`) is tried once more
+    /// on a fresh connection, the tracker may have forgotten the id early (e.g. after a restart)
+    fn with_udp_connection<T>(
+        &self,
+        receiver_ip: SocketAddr,
+        request: impl Fn(u64) -> Result<T, TrackerError>,
+        is_error_action: fn(&Result<T, TrackerError>) -> bool,
+    ) -> Result<T, TrackerError> {
+        let (connection_id, cached) = self.udp_connection_id(receiver_ip)?;
+        let mut result = request(connection_id);
 
-        let mut rec_buf = [0u8; 2048];
-        let mut retries = 0;
+        if result.is_err() || is_error_action(&result) {
+            self.forget_udp_connection();
 
-        while retries < 5 {
-            socket.recv_from(&mut rec_buf)?;
-            if rec_buf != [0u8; 2048] {
-                break;
+            if cached && is_error_action(&result) {
+                let (connection_id, _) = self.udp_connection_id(receiver_ip)?;
+                result = request(connection_id);
+                if result.is_err() || is_error_action(&result) {
+                    self.forget_udp_connection();
+                }
             }
-            retries += 1;
         }
 
-        if rec_buf == [0u8; 2048] {}
+        result
+    }
+
+    /// Drops the cached connection id after a failed request, the tracker may
+    /// have forgotten it early (e.g. after a restart)
+    fn forget_udp_connection(&self) {
+        *self.udp_connection.lock().unwrap() = None;
+    }
+
+    /// https://www.bittorrent.org/beps/bep_0015.html
+    fn announce_udp(&self, config: AnnounceConfig) -> Result<Responses, TrackerError> {
+        let (receiver_ip, socket) = self.udp_receiver()?;
+
+        let key: u32 = config.key.unwrap_or_else(random);
+
+        self.with_udp_connection(
+            receiver_ip,
+            |connection_id| {
+                let transaction_id: u32 = random();
+
+                let mut write_buf = Vec::new();
+                write_buf.write_all(&connection_id.to_be_bytes())?;
+                // Action: 1 = announce
+                write_buf.write_all(&1u32.to_be_bytes())?;
+                write_buf.write_all(&transaction_id.to_be_bytes())?;
+                write_buf.write_all(&config.info_hash)?;
+                write_buf.write_all(config.peer_id.as_bytes())?;
+                write_buf.write_all(&config.downloaded.to_be_bytes())?;
+                write_buf.write_all(&config.left.to_be_bytes())?;
+                write_buf.write_all(&config.uploaded.to_be_bytes())?;
+                // Event: 0 = None, 1 = Completed, 2 = Started, 3 = Stopped
+                write_buf.write_all(&config.event.as_udp_value().to_be_bytes())?;
+                // Ip Adress: 0 = default
+                // Is specified in certian cases when the client is behind some kind of proxy
+                write_buf.write_all(&0u32.to_be_bytes())?;
+                write_buf.write_all(&key.to_be_bytes())?;
+                // num_want: -1 = default
+                // Specifies the number of peers to return -1 means as much as u can
+                write_buf.write_all(&config.num_want.unwrap_or(-1).to_be_bytes())?;
+                write_buf.write_all(&config.port.to_be_bytes())?;
+
+                // Big enough for any UDP datagram so large peer lists aren't truncated
+                let mut rec_buf = vec![0u8; 65536];
+                let len = self.udp_request(&socket, &write_buf, receiver_ip, &mut rec_buf)?;
+                // IPv6 trackers answer with 18 byte peers
+                let peer_len = match receiver_ip {
+                    SocketAddr::V4(_) => COMPACT_PEER_V4_LEN,
+                    SocketAddr::V6(_) => COMPACT_PEER_V6_LEN,
+                };
+                Self::decode_udp_announce(&rec_buf[..len], transaction_id, peer_len)
+            },
+            |response| matches!(response, Ok(Responses::Failure(_))),
+        )
+    }
+
+    fn decode_udp_announce(
+        rec_buf: &[u8],
+        transaction_id: u32,
+        peer_len: usize,
+    ) -> Result<Responses, TrackerError> {
+        if let Some(failure_reason) = Self::udp_error_message(rec_buf) {
+            return Ok(Responses::Failure(FailureResponse { failure_reason }));
+        }
+        if rec_buf.len() < 20 {
+            return Err(TrackerError::FailedDecode);
+        }
 
         let rec_action = u32::from_be_bytes(rec_buf[0..4].try_into()?);
         let rec_transaction_id = u32::from_be_bytes(rec_buf[4..8].try_into()?);
         let interval = Duration::from_secs(u32::from_be_bytes(rec_buf[8..12].try_into()?) as u64);
         let incomplete = Some(u32::from_be_bytes(rec_buf[12..16].try_into()?) as u64);
         let complete = Some(u32::from_be_bytes(rec_buf[16..20].try_into()?) as u64);
-        let peers: Peers = rec_buf[20..]
-            .chunks(6)
-            .filter(|chunk| *chunk != [0u8; 6])
-            .map(|chunk| {
-                (
-                    (chunk[0], chunk[1], chunk[2], chunk[3]),
-                    u16::from_be_bytes([chunk[4], chunk[5]]),
-                )
-            })
-            .collect();
+        let mut peers = parse_compact_peers(&rec_buf[20..], peer_len);
+        peers.retain(is_valid_peer);
 
         if rec_action != 1 {
             return Err(TrackerError::MissMatchAction);
@@ -300,6 +809,1147 @@ impl Tracker {
             complete,
             incomplete,
             peers,
+            tracker_id: None,
+            warning_message: None,
+            external_ip: None,
         }))
     }
+
+    /// https://www.bittorrent.org/beps/bep_0015.html
+    /// Trackers may only answer around 74 info hashes per request
+    fn scrape_udp(&self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let (receiver_ip, socket) = self.udp_receiver()?;
+
+        self.with_udp_connection(
+            receiver_ip,
+            |connection_id| {
+                let transaction_id: u32 = random();
+
+                let mut write_buf = Vec::new();
+                write_buf.write_all(&connection_id.to_be_bytes())?;
+                // Action: 2 = scrape
+                write_buf.write_all(&2u32.to_be_bytes())?;
+                write_buf.write_all(&transaction_id.to_be_bytes())?;
+                for info_hash in info_hashes {
+                    write_buf.write_all(info_hash)?;
+                }
+
+                let mut rec_buf = [0u8; 2048];
+                let len = self.udp_request(&socket, &write_buf, receiver_ip, &mut rec_buf)?;
+                Self::decode_udp_scrape(&rec_buf[..len], transaction_id)
+            },
+            |stats| matches!(stats, Err(TrackerError::UdpError(_))),
+        )
+    }
+
+    fn decode_udp_scrape(
+        rec_buf: &[u8],
+        transaction_id: u32,
+    ) -> Result<Vec<ScrapeStats>, TrackerError> {
+        if let Some(message) = Self::udp_error_message(rec_buf) {
+            return Err(TrackerError::UdpError(message));
+        }
+        if rec_buf.len() < 8 {
+            return Err(TrackerError::FailedDecode);
+        }
+
+        let rec_action = u32::from_be_bytes(rec_buf[0..4].try_into()?);
+        let rec_transaction_id = u32::from_be_bytes(rec_buf[4..8].try_into()?);
+
+        if rec_action != 2 {
+            return Err(TrackerError::MissMatchAction);
+        }
+        if rec_transaction_id != transaction_id {
+            return Err(TrackerError::MissMatchTransactionId);
+        }
+
+        // seeders, completed, leechers triples for each info hash
+        Ok(rec_buf[8..]
+            .chunks_exact(12)
+            .map(|chunk| ScrapeStats {
+                seeders: u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as u64,
+                completed: u32::from_be_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64,
+                leechers: u32::from_be_bytes([chunk[8], chunk[9], chunk[10], chunk[11]]) as u64,
+            })
+            .collect())
+    }
+}
+
+/// Caps the number of in-flight announces and spaces out announces
+/// to the same tracker by at least `min_spacing`
+#[derive(Debug)]
+pub struct AnnounceLimiter {
+    max_in_flight: usize,
+    min_spacing: Duration,
+    in_flight: Mutex<usize>,
+    last_announce: Mutex<HashMap<Url, Instant>>,
+}
+
+/// Holds an in-flight announce slot, released on drop
+pub struct AnnouncePermit<'a> {
+    limiter: &'a AnnounceLimiter,
+}
+
+impl AnnounceLimiter {
+    /// How often `acquire_before` checks for a free slot
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    pub fn new(max_in_flight: usize, min_spacing: Duration) -> Self {
+        Self {
+            max_in_flight,
+            min_spacing,
+            in_flight: Mutex::new(0),
+            last_announce: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn try_acquire(&self, tracker: &Tracker) -> Option<AnnouncePermit<'_>> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if *in_flight >= self.max_in_flight {
+            return None;
+        }
+
+        let mut last_announce = self.last_announce.lock().unwrap();
+        if let Some(instant) = last_announce.get(&tracker.url) {
+            if instant.elapsed() < self.min_spacing {
+                return None;
+            }
+        }
+
+        last_announce.insert(tracker.url.clone(), Instant::now());
+        *in_flight += 1;
+
+        Some(AnnouncePermit { limiter: self })
+    }
+
+    /// `try_acquire` retried until it succeeds, `None` once `deadline` passed
+    pub fn acquire_before(
+        &self,
+        tracker: &Tracker,
+        deadline: Instant,
+    ) -> Option<AnnouncePermit<'_>> {
+        loop {
+            if let Some(permit) = self.try_acquire(tracker) {
+                return Some(permit);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            thread::sleep(Self::POLL_INTERVAL);
+        }
+    }
+}
+
+impl Drop for AnnouncePermit<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.limiter.in_flight.lock().unwrap();
+        *in_flight -= 1;
+    }
+}
+
+/// The `files` dict of a scrape, keyed by raw info hashes.
+/// Entries that aren't an info hash and a stats dict are skipped
+fn unwrap_scrape_files(
+    files: BencodeTypes,
+) -> Option<HashMap<[u8; 20], HashMap<String, BencodeTypes>>> {
+    Some(
+        unwrap_bytes_dict(files)?
+            .into_iter()
+            .filter_map(|(info_hash, stats)| {
+                Some((info_hash.try_into().ok()?, unwrap_dict(stats)?))
+            })
+            .collect(),
+    )
+}
+
+/// Filters out bogus peers trackers sometimes hand out: unspecified or
+/// port 0 addresses, multicast/broadcast/reserved ranges and IPv4-mapped IPv6
+pub fn is_valid_peer(addr: &SocketAddr) -> bool {
+    if addr.port() == 0 || addr.ip().is_unspecified() || addr.ip().is_multicast() {
+        return false;
+    }
+
+    match addr.ip() {
+        // 240.0.0.0/4 is reserved, 255.255.255.255 included
+        IpAddr::V4(ip) => ip.octets()[0] < 240,
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().is_none(),
+    }
+}
+
+/// Announces to every tracker in parallel on at most `max_threads` threads, as far as `limiter`
+/// allows. Returns the responses that arrived within `timeout`, slower ones are dropped
+pub fn announce_all(
+    trackers: &[Tracker],
+    config: &AnnounceConfig,
+    limiter: &Arc<AnnounceLimiter>,
+    max_threads: usize,
+    timeout: Duration,
+) -> Vec<Responses> {
+    announce_indexed(trackers, config, limiter, max_threads, timeout)
+        .into_iter()
+        .map(|(_, response)| response)
+        .collect()
+}
+
+/// https://www.bittorrent.org/beps/bep_0012.html
+/// Tries the tiers in order (announcing to a whole tier at once) until one answers,
+/// the trackers that answered are moved to the front of their tier and keep the
+/// `tracker id` they sent for the next announces
+pub fn announce_tiers(
+    tiers: &mut [Vec<Tracker>],
+    config: &AnnounceConfig,
+    limiter: &Arc<AnnounceLimiter>,
+    max_threads: usize,
+    timeout: Duration,
+) -> Vec<Responses> {
+    for tier in tiers.iter_mut() {
+        let answered = announce_indexed(tier, config, limiter, max_threads, timeout);
+        if answered.is_empty() {
+            continue;
+        }
+
+        for (index, response) in &answered {
+            if let Responses::Done(OkResponse {
+                tracker_id: Some(tracker_id),
+                ..
+            }) = response
+            {
+                tier[*index].tracker_id = Some(tracker_id.clone());
+            }
+        }
+
+        let indices: HashSet<usize> = answered.iter().map(|(index, _)| *index).collect();
+        let (mut front, back): (Vec<_>, Vec<_>) = tier
+            .drain(..)
+            .enumerate()
+            .partition(|(index, _)| indices.contains(index));
+        front.extend(back);
+        tier.extend(front.into_iter().map(|(_, tracker)| tracker));
+
+        return answered.into_iter().map(|(_, response)| response).collect();
+    }
+
+    Vec::new()
+}
+
+/// Responses paired with the index of the tracker that sent them
+fn announce_indexed(
+    trackers: &[Tracker],
+    config: &AnnounceConfig,
+    limiter: &Arc<AnnounceLimiter>,
+    max_threads: usize,
+    timeout: Duration,
+) -> Vec<(usize, Responses)> {
+    let deadline = Instant::now() + timeout;
+    let queue = Arc::new(Mutex::new(
+        trackers
+            .iter()
+            .cloned()
+            .map(|mut tracker| {
+                tracker.udp_deadline = Some(deadline);
+                tracker
+            })
+            .enumerate()
+            .collect::<VecDeque<_>>(),
+    ));
+    let (sender, receiver) = mpsc::channel();
+
+    for _ in 0..max_threads.min(trackers.len()) {
+        let queue = Arc::clone(&queue);
+        let sender = sender.clone();
+        let config = config.clone();
+        let limiter = Arc::clone(limiter);
+
+        // Not joined, a hanging tracker must not hold up the caller
+        thread::spawn(move || loop {
+            let Some((index, tracker)) = queue.lock().unwrap().pop_front() else {
+                break;
+            };
+            if let Ok(response) = tracker.announce_limited(config.clone(), &limiter, deadline) {
+                // Receiver gone means the timeout passed
+                if sender.send((index, response)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    std::mem::drop(sender);
+
+    let deadline = Instant::now() + timeout;
+    let mut responses = Vec::new();
+
+    while let Some(time_left) = deadline.checked_duration_since(Instant::now()) {
+        match receiver.recv_timeout(time_left) {
+            Ok(response) => responses.push(response),
+            // Timed out or every worker is done
+            Err(_) => break,
+        }
+    }
+
+    responses
+}
+
+/// When to announce again: `requested` if set, else as soon as the most eager tracker allows.
+/// `requested` is raised to the strictest `min interval` so no tracker sees us too early,
+/// the bool says whether that happened. `None` without `requested` or any response
+pub fn announce_interval(
+    responses: &[OkResponse],
+    requested: Option<Duration>,
+) -> Option<(Duration, bool)> {
+    let min_interval = responses
+        .iter()
+        .filter_map(|result| result.min_interval)
+        .max();
+
+    match requested {
+        Some(requested) => match min_interval {
+            Some(min_interval) if requested < min_interval => Some((min_interval, true)),
+            _ => Some((requested, false)),
+        },
+        None => {
+            let interval = responses
+                .iter()
+                .map(|result| result.min_interval.unwrap_or(result.interval))
+                .min()?;
+            Some((interval, false))
+        }
+    }
+}
+
+/// Peers of all `responses` in order, without duplicates
+pub fn merge_peers(responses: &[OkResponse]) -> Peers {
+    let mut seen = HashSet::new();
+    responses
+        .iter()
+        .flat_map(|response| response.peers.iter().copied())
+        .filter(|peer| seen.insert(*peer))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_announce_url_keeps_ipv6_brackets() {
+        let tracker =
+            Tracker::new(Url::parse("http://[2001:db8::1]:8080/announce").unwrap()).unwrap();
+        let url = tracker.http_announce_url(&AnnounceConfig {
+            info_hash: [0u8; 20],
+            peer_id: String::from("RB01-aaaaaaaaaaaaaaa"),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: AnnounceEvent::Started,
+            num_want: Some(50),
+            tracker_id: None,
+            key: None,
+        });
+
+        assert!(url.starts_with("http://[2001:db8::1]:8080/announce?"));
+        assert!(url.contains("&compact=1&no_peer_id=1&event=started&numwant=50&"));
+        assert_eq!(
+            Url::parse(&url).unwrap().host(),
+            Some(url::Host::Ipv6("2001:db8::1".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn pause_and_resume_send_stopped_then_started() {
+        let mut state = AnnounceState::default();
+        assert_eq!(state.event(), Some(AnnounceEvent::Started));
+
+        // Paused before the first announce, the trackers don't know us yet
+        let mut fresh = state;
+        assert_eq!(fresh.pause(), None);
+
+        state.announced();
+        assert_eq!(state.event(), Some(AnnounceEvent::None));
+
+        assert_eq!(state.pause(), Some(AnnounceEvent::Stopped));
+        assert_eq!(state.event(), None);
+        assert_eq!(state.pause(), None);
+
+        state.resume();
+        assert_eq!(state.event(), Some(AnnounceEvent::Started));
+        state.announced();
+        assert_eq!(state.event(), Some(AnnounceEvent::None));
+    }
+
+    #[test]
+    fn rejects_unknown_tracker_schemes() {
+        assert!(matches!(
+            Tracker::new(Url::parse("wss://tracker.example/announce").unwrap()),
+            Err(TrackerError::UnknownTrackerProtocol)
+        ));
+    }
+
+    #[test]
+    fn http_scrape_url_replaces_announce() {
+        let tracker =
+            Tracker::new(Url::parse("http://tracker.example/x/announce.php?passkey=1").unwrap())
+                .unwrap();
+        let url = tracker.http_scrape_url(&[[0xff; 20]]).unwrap();
+
+        assert!(url.starts_with("http://tracker.example/x/scrape.php?passkey=1&info_hash="));
+        assert!(url.ends_with(&format!("info_hash={}", "%FF".repeat(20))));
+
+        let tracker = Tracker::new(Url::parse("http://tracker.example/a").unwrap()).unwrap();
+        assert!(matches!(
+            tracker.http_scrape_url(&[[0; 20]]),
+            Err(TrackerError::ScrapeNotSupported)
+        ));
+    }
+
+    #[test]
+    fn decodes_http_response_with_only_peers6() {
+        let tracker = Tracker::new(Url::parse("http://tracker.example/announce").unwrap()).unwrap();
+        let mut response = b"d8:intervali1800e6:peers618:".to_vec();
+        response.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        response.extend_from_slice(&[0x1A, 0xE1, b'e']);
+
+        let Some(Responses::Done(response)) = tracker.decode_http_response(response) else {
+            panic!("expected a Done response");
+        };
+
+        assert_eq!(response.peers, vec!["[2001:db8::1]:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn decodes_the_external_ip_of_http_responses() {
+        let tracker = Tracker::new(Url::parse("http://tracker.example/announce").unwrap()).unwrap();
+        let decode = |external_ip: &[u8]| {
+            let mut response = format!("d11:external ip{}:", external_ip.len()).into_bytes();
+            response.extend_from_slice(external_ip);
+            response.extend_from_slice(b"8:intervali1800e5:peers0:e");
+            match tracker.decode_http_response(response) {
+                Some(Responses::Done(response)) => response.external_ip,
+                _ => panic!("expected a Done response"),
+            }
+        };
+
+        assert_eq!(
+            decode(&[203, 0, 113, 7]),
+            Some("203.0.113.7".parse().unwrap())
+        );
+        let ipv6 = "2001:db8::7".parse::<std::net::Ipv6Addr>().unwrap();
+        assert_eq!(decode(&ipv6.octets()), Some(IpAddr::V6(ipv6)));
+        assert_eq!(decode(b"203.0.113.7"), None);
+    }
+
+    #[test]
+    fn decodes_http_response_with_dictionary_peers() {
+        let tracker = Tracker::new(Url::parse("http://tracker.example/announce").unwrap()).unwrap();
+        // A textual ip, raw octets, a hostname and an entry without port
+        let mut response = b"d8:intervali1800e5:peersl\
+            d2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881ee\
+            d2:ip4:"
+            .to_vec();
+        response.extend_from_slice(&[10, 0, 0, 2]);
+        response.extend_from_slice(b"4:porti6882eed2:ip11:example.com4:porti1eed2:ip8:10.0.0.3eee");
+
+        let Some(Responses::Done(response)) = tracker.decode_http_response(response) else {
+            panic!("expected a Done response");
+        };
+
+        assert_eq!(
+            response.peers,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_warning_message_of_http_responses() {
+        let tracker = Tracker::new(Url::parse("http://tracker.example/announce").unwrap()).unwrap();
+        let response =
+            b"d8:intervali1800e5:peers0:15:warning message23:your client is outdatede".to_vec();
+
+        let Some(Responses::Done(response)) = tracker.decode_http_response(response) else {
+            panic!("expected a Done response");
+        };
+
+        assert_eq!(
+            response.warning_message.as_deref(),
+            Some("your client is outdated")
+        );
+    }
+
+    #[test]
+    fn garbled_http_response_is_not_a_panic() {
+        let tracker = Tracker::new(Url::parse("http://tracker.example/announce").unwrap()).unwrap();
+
+        assert!(tracker
+            .decode_http_response(b"<html>502</html>".to_vec())
+            .is_none());
+        assert!(tracker
+            .decode_http_response(b"d8:intervali1800e5:peersi3ee".to_vec())
+            .is_none());
+    }
+
+    #[test]
+    fn decodes_http_scrape_response() {
+        let tracker = Tracker::new(Url::parse("http://tracker.example/announce").unwrap()).unwrap();
+        let mut response = b"d5:filesd20:".to_vec();
+        response.extend_from_slice(&[1; 20]);
+        response.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+
+        assert_eq!(
+            tracker
+                .decode_http_scrape(response, &[[1; 20], [2; 20]])
+                .unwrap(),
+            vec![
+                ScrapeStats {
+                    seeders: 5,
+                    completed: 50,
+                    leechers: 10
+                },
+                ScrapeStats {
+                    seeders: 0,
+                    completed: 0,
+                    leechers: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_udp_announce_using_received_length() {
+        let mut rec_buf = Vec::new();
+        for n in [1u32, 9, 1800, 3, 4] {
+            rec_buf.extend_from_slice(&n.to_be_bytes());
+        }
+        rec_buf.extend_from_slice(&[10, 0, 0, 0, 1, 0]);
+        rec_buf.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+        let Responses::Done(response) =
+            Tracker::decode_udp_announce(&rec_buf, 9, COMPACT_PEER_V4_LEN).unwrap()
+        else {
+            panic!("expected a Done response");
+        };
+
+        assert_eq!(response.interval, Duration::from_secs(1800));
+        assert_eq!(response.incomplete, Some(3));
+        assert_eq!(response.complete, Some(4));
+        // the all-zero entry is not a real peer
+        assert_eq!(response.peers, vec!["10.0.0.0:256".parse().unwrap()]);
+    }
+
+    #[test]
+    fn udp_request_retransmits_until_answered() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0u8; 16];
+            // Drop the first packet, answer the retransmit
+            server.recv_from(&mut buf).unwrap();
+            let (_, addr) = server.recv_from(&mut buf).unwrap();
+            server.send_to(b"pong", addr).unwrap();
+        });
+
+        let mut tracker = Tracker::new(Url::parse("udp://127.0.0.1:1337").unwrap()).unwrap();
+        tracker.udp_timeout = Duration::from_millis(50);
+        tracker.udp_max_retries = 3;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut rec_buf = [0u8; 16];
+        let len = tracker
+            .udp_request(&socket, b"ping", server_addr, &mut rec_buf)
+            .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(&rec_buf[..len], b"pong");
+    }
+
+    #[test]
+    fn udp_request_times_out_after_last_retry() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let mut tracker = Tracker::new(Url::parse("udp://127.0.0.1:1337").unwrap()).unwrap();
+        tracker.udp_timeout = Duration::from_millis(10);
+        tracker.udp_max_retries = 2;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut rec_buf = [0u8; 16];
+
+        assert!(matches!(
+            tracker.udp_request(&socket, b"ping", server.local_addr().unwrap(), &mut rec_buf),
+            Err(TrackerError::Timeout)
+        ));
+    }
+
+    /// Answers connects and announces on `addr`, counts the connects. Announces get one
+    /// peer of the tracker's address family, ones with an unknown connection id
+    /// get an error like real trackers send
+    fn fake_udp_tracker(addr: &str, connects: Arc<Mutex<usize>>) -> Url {
+        let server = UdpSocket::bind(addr).unwrap();
+        let url = Url::parse(&format!("udp://{}", server.local_addr().unwrap())).unwrap();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            let mut connection_id = 0u64;
+            while let Ok((len, from)) = server.recv_from(&mut buf) {
+                let action = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+                let transaction_id = buf[12..16].to_vec();
+                let mut response = Vec::new();
+
+                if action == 0 {
+                    *connects.lock().unwrap() += 1;
+                    connection_id += 1;
+                    response.extend_from_slice(&0u32.to_be_bytes());
+                    response.extend_from_slice(&transaction_id);
+                    response.extend_from_slice(&connection_id.to_be_bytes());
+                } else if len >= 16 && buf[0..8] == connection_id.to_be_bytes() {
+                    response.extend_from_slice(&1u32.to_be_bytes());
+                    response.extend_from_slice(&transaction_id);
+                    response.extend_from_slice(&[0; 12]);
+                    match from.ip() {
+                        IpAddr::V4(_) => response.extend_from_slice(&[10, 0, 0, 1]),
+                        IpAddr::V6(_) => response.extend_from_slice(
+                            &"2001:db8::1"
+                                .parse::<std::net::Ipv6Addr>()
+                                .unwrap()
+                                .octets(),
+                        ),
+                    }
+                    response.extend_from_slice(&6881u16.to_be_bytes());
+                } else {
+                    response.extend_from_slice(&3u32.to_be_bytes());
+                    response.extend_from_slice(&transaction_id);
+                    response.extend_from_slice(b"bad connection id");
+                }
+                server.send_to(&response, from).unwrap();
+            }
+        });
+
+        url
+    }
+
+    #[test]
+    fn reuses_the_udp_connection_id() {
+        let connects = Arc::new(Mutex::new(0));
+        let url = fake_udp_tracker("127.0.0.1:0", Arc::clone(&connects));
+        let mut tracker = Tracker::new(url).unwrap();
+        tracker.udp_timeout = Duration::from_millis(200);
+
+        for _ in 0..3 {
+            assert!(matches!(
+                tracker.clone().announce(test_config()),
+                Ok(Responses::Done(_))
+            ));
+        }
+        assert_eq!(*connects.lock().unwrap(), 1);
+
+        // A connection id the tracker forgot gets an error back, the announce
+        // reconnects and is sent again
+        tracker.udp_connection.lock().unwrap().as_mut().unwrap().1 = 42;
+        assert!(matches!(
+            tracker.announce(test_config()),
+            Ok(Responses::Done(_))
+        ));
+        assert_eq!(*connects.lock().unwrap(), 2);
+        assert!(matches!(
+            tracker.announce(test_config()),
+            Ok(Responses::Done(_))
+        ));
+        assert_eq!(*connects.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn udp_retries_stop_at_the_deadline() {
+        // Never answers
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("udp://{}", server.local_addr().unwrap())).unwrap();
+        let mut tracker = Tracker::new(url).unwrap();
+        tracker.udp_deadline = Some(Instant::now() + Duration::from_millis(100));
+
+        let started = Instant::now();
+        assert!(matches!(
+            tracker.announce(test_config()),
+            Err(TrackerError::Timeout)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn announces_to_ipv6_udp_trackers() {
+        // No IPv6 on this host
+        if UdpSocket::bind("[::1]:0").is_err() {
+            return;
+        }
+        let url = fake_udp_tracker("[::1]:0", Arc::new(Mutex::new(0)));
+        let mut tracker = Tracker::new(url).unwrap();
+        tracker.udp_timeout = Duration::from_millis(200);
+
+        let Ok(Responses::Done(response)) = tracker.announce(test_config()) else {
+            panic!("expected a Done response");
+        };
+        assert_eq!(response.peers, vec!["[2001:db8::1]:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn decodes_udp_error_responses() {
+        let mut rec_buf = Vec::new();
+        rec_buf.extend_from_slice(&3u32.to_be_bytes());
+        rec_buf.extend_from_slice(&9u32.to_be_bytes());
+        rec_buf.extend_from_slice(b"unregistered torrent");
+
+        let Responses::Failure(failure) =
+            Tracker::decode_udp_announce(&rec_buf, 9, COMPACT_PEER_V4_LEN).unwrap()
+        else {
+            panic!("expected a Failure response");
+        };
+        assert_eq!(failure.failure_reason, "unregistered torrent");
+
+        assert!(matches!(
+            Tracker::decode_udp_scrape(&rec_buf, 9),
+            Err(TrackerError::UdpError(message)) if message == "unregistered torrent"
+        ));
+    }
+
+    #[test]
+    fn decodes_udp_scrape_response() {
+        let mut rec_buf = Vec::new();
+        rec_buf.extend_from_slice(&2u32.to_be_bytes());
+        rec_buf.extend_from_slice(&7u32.to_be_bytes());
+        for n in [10u32, 20, 30, 1, 2, 3] {
+            rec_buf.extend_from_slice(&n.to_be_bytes());
+        }
+
+        assert_eq!(
+            Tracker::decode_udp_scrape(&rec_buf, 7).unwrap(),
+            vec![
+                ScrapeStats {
+                    seeders: 10,
+                    completed: 20,
+                    leechers: 30
+                },
+                ScrapeStats {
+                    seeders: 1,
+                    completed: 2,
+                    leechers: 3
+                },
+            ]
+        );
+        assert!(matches!(
+            Tracker::decode_udp_scrape(&rec_buf, 8),
+            Err(TrackerError::MissMatchTransactionId)
+        ));
+    }
+
+    #[test]
+    fn limiter_spaces_announces_to_same_tracker() {
+        let tracker = Tracker::new(Url::parse("udp://tracker.example:1337").unwrap()).unwrap();
+        let other = Tracker::new(Url::parse("udp://other.example:1337").unwrap()).unwrap();
+        let limiter = AnnounceLimiter::new(4, Duration::from_millis(100));
+
+        let start = Instant::now();
+        drop(limiter.try_acquire(&tracker).unwrap());
+
+        // too soon for the same tracker, other trackers are unaffected
+        assert!(limiter.try_acquire(&tracker).is_none());
+        assert!(limiter.try_acquire(&other).is_some());
+
+        while limiter.try_acquire(&tracker).is_none() {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn limiter_caps_in_flight_announces() {
+        let first = Tracker::new(Url::parse("udp://first.example:1337").unwrap()).unwrap();
+        let second = Tracker::new(Url::parse("udp://second.example:1337").unwrap()).unwrap();
+        let limiter = AnnounceLimiter::new(1, Duration::ZERO);
+
+        let permit = limiter.try_acquire(&first).unwrap();
+        assert!(limiter.try_acquire(&second).is_none());
+
+        drop(permit);
+        assert!(limiter.try_acquire(&second).is_some());
+    }
+
+    /// Request line and headers of an HTTP request, empty if the client hung up
+    fn read_request(stream: &mut std::net::TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut byte = [0u8];
+        while !request.ends_with(b"\r\n\r\n") && io::Read::read(stream, &mut byte).unwrap_or(0) == 1
+        {
+            request.push(byte[0]);
+        }
+        String::from_utf8(request).unwrap()
+    }
+
+    fn write_response(stream: &mut std::net::TcpStream, body: &[u8]) {
+        let _ = write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        let _ = stream.write_all(body);
+    }
+
+    /// HTTP server answering one connection with each of `bodies` in turn,
+    /// the thread returns the requests it got
+    fn http_stub(bodies: Vec<&'static [u8]>) -> (Url, std::thread::JoinHandle<Vec<String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+
+        let server = std::thread::spawn(move || {
+            bodies
+                .into_iter()
+                .map(|body| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let request = read_request(&mut stream);
+                    write_response(&mut stream, body);
+                    request
+                })
+                .collect()
+        });
+
+        (Url::parse(&url).unwrap(), server)
+    }
+
+    /// HTTP tracker on localhost answering every announce after `delay`
+    fn slow_http_tracker(delay: Duration) -> Tracker {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                read_request(&mut stream);
+
+                std::thread::sleep(delay);
+
+                write_response(
+                    &mut stream,
+                    b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+                );
+            }
+        });
+
+        Tracker::new(Url::parse(&url).unwrap()).unwrap()
+    }
+
+    fn test_config() -> AnnounceConfig {
+        AnnounceConfig {
+            info_hash: [0u8; 20],
+            peer_id: String::from("RB01-aaaaaaaaaaaaaaa"),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: AnnounceEvent::None,
+            num_want: None,
+            tracker_id: None,
+            key: None,
+        }
+    }
+
+    fn unlimited() -> Arc<AnnounceLimiter> {
+        Arc::new(AnnounceLimiter::new(usize::MAX, Duration::ZERO))
+    }
+
+    #[test]
+    fn announce_all_runs_announces_in_parallel() {
+        let delay = Duration::from_millis(300);
+        let trackers: Vec<Tracker> = (0..3).map(|_| slow_http_tracker(delay)).collect();
+
+        let started = Instant::now();
+        let responses = announce_all(
+            &trackers,
+            &test_config(),
+            &unlimited(),
+            3,
+            Duration::from_secs(5),
+        );
+
+        assert_eq!(responses.len(), 3);
+        // serially this takes at least 3 * delay
+        assert!(started.elapsed() < delay * 2);
+    }
+
+    #[test]
+    fn announce_all_drops_trackers_slower_than_timeout() {
+        let trackers = vec![
+            slow_http_tracker(Duration::ZERO),
+            slow_http_tracker(Duration::from_secs(3)),
+        ];
+
+        let started = Instant::now();
+        let responses = announce_all(
+            &trackers,
+            &test_config(),
+            &unlimited(),
+            2,
+            Duration::from_millis(500),
+        );
+
+        assert_eq!(responses.len(), 1);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn merges_peers_without_duplicates() {
+        let response = |peers: &[&str]| OkResponse {
+            interval: Duration::from_secs(1800),
+            min_interval: None,
+            complete: None,
+            incomplete: None,
+            peers: peers.iter().map(|p| p.parse().unwrap()).collect(),
+            tracker_id: None,
+            warning_message: None,
+            external_ip: None,
+        };
+
+        let merged = merge_peers(&[
+            response(&["10.0.0.1:6881", "10.0.0.2:6881"]),
+            response(&["[::1]:6881", "10.0.0.1:6881"]),
+        ]);
+
+        assert_eq!(
+            merged,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6881".parse().unwrap(),
+                "[::1]:6881".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bogus_peer_addresses() {
+        let valid = |addr: &str| is_valid_peer(&addr.parse().unwrap());
+
+        assert!(valid("93.184.216.34:6881"));
+        assert!(valid("[2001:db8::1]:6881"));
+
+        // unspecified
+        assert!(!valid("0.0.0.0:6881"));
+        assert!(!valid("[::]:6881"));
+        // port 0
+        assert!(!valid("93.184.216.34:0"));
+        // multicast
+        assert!(!valid("224.0.0.1:6881"));
+        assert!(!valid("[ff02::1]:6881"));
+        // reserved and broadcast
+        assert!(!valid("240.0.0.1:6881"));
+        assert!(!valid("255.255.255.255:6881"));
+        // IPv4-mapped IPv6
+        assert!(!valid("[::ffff:93.184.216.34]:6881"));
+    }
+
+    #[test]
+    fn announce_tiers_falls_back_and_promotes_working_trackers() {
+        // Nothing listens on a dropped listener's port
+        let dead_tracker = || {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/announce", listener.local_addr().unwrap());
+            Tracker::new(Url::parse(&url).unwrap()).unwrap()
+        };
+
+        let live = slow_http_tracker(Duration::ZERO);
+        let live_url = live.url.clone();
+        let mut tiers = vec![vec![dead_tracker()], vec![dead_tracker(), live]];
+
+        let responses = announce_tiers(
+            &mut tiers,
+            &test_config(),
+            &unlimited(),
+            2,
+            Duration::from_secs(5),
+        );
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(tiers[1][0].url, live_url);
+        assert_eq!(tiers[1].len(), 2);
+    }
+
+    #[test]
+    fn http_announce_sends_configured_headers() {
+        let (url, server) = http_stub(vec![b"d8:intervali1800e5:peers0:e"]);
+
+        let mut tracker = Tracker::new(url).unwrap();
+        tracker.extra_headers = vec![(String::from("X-Api-Key"), String::from("secret"))];
+        tracker.announce(test_config()).unwrap();
+
+        let request = server.join().unwrap()[0].to_lowercase();
+        assert!(request.contains("\r\naccept: */*\r\n"));
+        assert!(request.contains("\r\nx-api-key: secret\r\n"));
+    }
+
+    #[test]
+    fn echoes_the_tracker_id_on_later_announces() {
+        // Sends a tracker id to the first announce
+        let (url, server) = http_stub(vec![
+            b"d8:intervali1800e5:peers0:10:tracker id3:abce",
+            b"d8:intervali1800e5:peers0:e",
+        ]);
+
+        let mut tiers = vec![vec![Tracker::new(url).unwrap()]];
+        let responses = announce_tiers(
+            &mut tiers,
+            &test_config(),
+            &unlimited(),
+            1,
+            Duration::from_secs(5),
+        );
+        let Some(Responses::Done(response)) = responses.first() else {
+            panic!("expected a Done response");
+        };
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(response.warning_message, None);
+        announce_tiers(
+            &mut tiers,
+            &test_config(),
+            &unlimited(),
+            1,
+            Duration::from_secs(5),
+        );
+
+        let request_lines: Vec<String> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|request| request.lines().next().unwrap().to_string())
+            .collect();
+        assert!(!request_lines[0].contains("trackerid="));
+        assert!(request_lines[1].contains("&trackerid=abc&"));
+
+        // An explicit one wins
+        let config = AnnounceConfig {
+            tracker_id: Some(String::from("mine")),
+            ..test_config()
+        };
+        assert!(tiers[0][0]
+            .http_announce_url(&config)
+            .contains("&trackerid=mine&"));
+    }
+
+    #[test]
+    fn sends_the_key_and_a_user_agent() {
+        let (url, server) = http_stub(vec![b"d8:intervali1800e5:peers0:e"]);
+
+        let tracker = Tracker::new(url).unwrap();
+        let config = AnnounceConfig {
+            key: Some(0xc0ffee),
+            ..test_config()
+        };
+        assert!(matches!(tracker.announce(config), Ok(Responses::Done(_))));
+
+        let request = server.join().unwrap()[0].to_lowercase();
+        assert!(request.contains("&key=00c0ffee&"));
+        assert!(request.contains(&format!(
+            "user-agent: rubit/{}\r\n",
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert!(!tracker.http_announce_url(&test_config()).contains("key="));
+    }
+
+    /// HTTP tracker that hangs up on the first `drops` connections and then answers
+    /// every announce with `body`, also returns the number of connections seen
+    fn flaky_http_tracker(drops: usize, body: &'static [u8]) -> (Tracker, Arc<Mutex<usize>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let connections = Arc::new(Mutex::new(0));
+
+        let connections_clone = Arc::clone(&connections);
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                read_request(&mut stream);
+
+                let mut connections = connections_clone.lock().unwrap();
+                *connections += 1;
+                if *connections <= drops {
+                    continue;
+                }
+
+                write_response(&mut stream, body);
+            }
+        });
+
+        (
+            Tracker::new(Url::parse(&url).unwrap()).unwrap(),
+            connections,
+        )
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let body = b"d8:intervali1800e5:peers0:e";
+        let (tracker, connections) = flaky_http_tracker(2, body);
+
+        let response = tracker.announce_with_retry(test_config(), 3, Duration::from_millis(1));
+
+        assert!(matches!(response, Ok(Responses::Done(_))));
+        assert_eq!(*connections.lock().unwrap(), 3);
+
+        let (tracker, connections) = flaky_http_tracker(5, body);
+        let response = tracker.announce_with_retry(test_config(), 2, Duration::from_millis(1));
+
+        assert!(response.as_ref().is_err_and(TrackerError::is_transient));
+        assert_eq!(*connections.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn does_not_retry_permanent_failures() {
+        let (tracker, connections) = flaky_http_tracker(0, b"d14:failure reason4:nopee");
+        let response = tracker.announce_with_retry(test_config(), 3, Duration::from_millis(1));
+
+        assert!(matches!(response, Ok(Responses::Failure(_))));
+        assert_eq!(*connections.lock().unwrap(), 1);
+
+        let (tracker, connections) = flaky_http_tracker(0, b"garbage");
+        let response = tracker.announce_with_retry(test_config(), 3, Duration::from_millis(1));
+
+        assert!(response.is_err_and(|e| !e.is_transient()));
+        assert_eq!(*connections.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn clamps_requested_intervals_to_min_interval() {
+        let response = |interval, min_interval: Option<u64>| OkResponse {
+            interval: Duration::from_secs(interval),
+            min_interval: min_interval.map(Duration::from_secs),
+            complete: None,
+            incomplete: None,
+            peers: Vec::new(),
+            tracker_id: None,
+            warning_message: None,
+            external_ip: None,
+        };
+        let responses = [response(1800, Some(300)), response(600, None)];
+        let secs = Duration::from_secs;
+
+        assert_eq!(
+            announce_interval(&responses, None),
+            Some((secs(300), false))
+        );
+        assert_eq!(
+            announce_interval(&responses, Some(secs(60))),
+            Some((secs(300), true))
+        );
+        assert_eq!(
+            announce_interval(&responses, Some(secs(900))),
+            Some((secs(900), false))
+        );
+        assert_eq!(
+            announce_interval(&responses[1..], Some(secs(60))),
+            Some((secs(60), false))
+        );
+        assert_eq!(announce_interval(&[], None), None);
+        assert_eq!(
+            announce_interval(&[], Some(secs(60))),
+            Some((secs(60), false))
+        );
+    }
 }
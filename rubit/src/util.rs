@@ -1,15 +1,20 @@
 use std::{
-    collections::HashSet,
-    fs::File,
-    io::{Read, Seek, SeekFrom},
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
 };
 
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rand::{distributions::Alphanumeric, seq::SliceRandom, thread_rng, Rng};
 use sha1::{Digest, Sha1};
 use url::Url;
 
-use crate::Tracker;
+use rubit_bencode::TorrentFile;
+
+use crate::{ReadWriteSeek, Tracker};
 
 pub fn get_random_id() -> String {
     let mut peer_id = String::from("RB01-");
@@ -22,69 +27,427 @@ pub fn get_random_id() -> String {
     peer_id
 }
 
-pub fn get_tracker_list(announce: String, announce_list: Vec<Vec<String>>) -> Vec<Tracker> {
-    let mut flattened_list: Vec<&String> = announce_list.iter().flatten().collect();
-    flattened_list.push(&announce);
+/// Whether each torrent announces and handshakes with its own peer_id
+/// or all torrents share one session-wide peer_id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerIdPolicy {
+    #[default]
+    PerTorrent,
+    SessionWide,
+}
+
+/// Hands out peer_ids according to a `PeerIdPolicy`,
+/// asking twice for the same torrent always returns the same id
+#[derive(Debug)]
+pub struct PeerIds {
+    policy: PeerIdPolicy,
+    session_id: String,
+    per_torrent: Mutex<HashMap<[u8; 20], String>>,
+}
+
+impl PeerIds {
+    pub fn new(policy: PeerIdPolicy) -> Self {
+        Self {
+            policy,
+            session_id: get_random_id(),
+            per_torrent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20]) -> String {
+        match self.policy {
+            PeerIdPolicy::SessionWide => self.session_id.clone(),
+            PeerIdPolicy::PerTorrent => self
+                .per_torrent
+                .lock()
+                .unwrap()
+                .entry(*info_hash)
+                .or_insert_with(get_random_id)
+                .clone(),
+        }
+    }
+}
+
+/// https://www.bittorrent.org/beps/bep_0012.html
+/// Keeps the tiers of the announce list (shuffling each one), `announce` is tried last
+/// if it isn't in any tier. Unparsable or unsupported urls and empty tiers are dropped
+pub fn get_tracker_list(announce: String, announce_list: Vec<Vec<String>>) -> Vec<Vec<Tracker>> {
+    let mut tiers = announce_list;
+    if !tiers.iter().flatten().any(|url| *url == announce) {
+        tiers.push(vec![announce]);
+    }
 
     let mut vec = Vec::new();
 
-    for url in flattened_list {
-        let parsed = match Url::parse(&url) {
-            Ok(u) => u,
-            Err(_) => continue,
-        };
-        let tracker = match Tracker::new(parsed) {
-            Ok(t) => t,
-            Err(_) => continue,
-        };
+    for tier in tiers {
+        let mut trackers = Vec::new();
+
+        for url in tier {
+            let parsed = match Url::parse(&url) {
+                Ok(u) => u,
+                Err(_) => continue,
+            };
+            let tracker = match Tracker::new(parsed) {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+
+            trackers.push(tracker);
+        }
 
-        vec.push(tracker);
+        if !trackers.is_empty() {
+            trackers.shuffle(&mut thread_rng());
+            vec.push(trackers);
+        }
     }
     vec
 }
 
+/// Hashes the pieces already on disk at `path` using up to `threads` workers,
+/// each reading its own contiguous range of pieces through its own file handle
 pub fn check_download_percent(
-    file: Arc<Mutex<File>>,
-    pieces: &Vec<[u8; 20]>,
+    path: &Path,
+    pieces: &[[u8; 20]],
     total_length: u64,
     piece_len: u64,
+    threads: usize,
 ) -> HashSet<usize> {
-    println!("File already exists, checking downloaded hashes...");
-
-    let mut file = file.lock().unwrap();
-    if file.seek(SeekFrom::End(0)).unwrap() == 0 {
-        return HashSet::new();
+    match fs::metadata(path) {
+        Ok(m) if m.len() > 0 => (),
+        _ => return HashSet::new(),
     }
 
+    check_storage_percent(
+        || File::open(path),
+        pieces,
+        total_length,
+        piece_len,
+        threads,
+    )
+}
+
+/// `check_download_percent` for data that isn't one file, e.g. a `MultiFile`.
+/// Every worker reads through its own handle from `open`
+pub fn check_storage_percent<R: Read + Seek>(
+    open: impl Fn() -> io::Result<R> + Sync,
+    pieces: &[[u8; 20]],
+    total_length: u64,
+    piece_len: u64,
+    threads: usize,
+) -> HashSet<usize> {
+    let open = &open;
+    let threads = threads.clamp(1, pieces.len().max(1));
+    let chunk_len = pieces.len().div_ceil(threads);
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|worker| {
+                scope.spawn(move || {
+                    let start = worker * chunk_len;
+                    let end = (start + chunk_len).min(pieces.len());
+                    check_pieces(open, pieces, start..end, total_length, piece_len)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+fn check_pieces<R: Read + Seek>(
+    open: impl Fn() -> io::Result<R>,
+    pieces: &[[u8; 20]],
+    range: Range<usize>,
+    total_length: u64,
+    piece_len: u64,
+) -> HashSet<usize> {
     let mut completed = HashSet::new();
-    let mut cursor = 0;
-
-    for i in 0..pieces.len() {
-        let mut buf = Vec::new();
-        if i == pieces.len() - 1 {
-            buf.resize((total_length % piece_len) as usize, 0);
-        } else {
-            buf.resize(piece_len as usize, 0);
+
+    let Ok(mut file) = open() else {
+        return completed;
+    };
+
+    for i in range {
+        if piece_matches(&mut file, pieces, i, total_length, piece_len) {
+            completed.insert(i);
         }
-        let mut hasher = Sha1::new();
+    }
 
-        file.seek(SeekFrom::Start(cursor)).unwrap();
-        file.read(&mut buf).unwrap();
+    completed
+}
 
-        hasher.update(&buf);
-        let hash: [u8; 20] = hasher.finalize().into();
+/// Reads piece `index` back from `file` and checks it against its hash
+fn piece_matches(
+    file: &mut (impl Read + Seek + ?Sized),
+    pieces: &[[u8; 20]],
+    index: usize,
+    total_length: u64,
+    piece_len: u64,
+) -> bool {
+    let Some(expected) = pieces.get(index) else {
+        return false;
+    };
 
-        if hash == pieces[i] {
-            completed.insert(i);
+    let size = if index == pieces.len() - 1 && !total_length.is_multiple_of(piece_len) {
+        total_length % piece_len
+    } else {
+        piece_len
+    };
+    let mut buf = vec![0; size as usize];
+
+    if file
+        .seek(SeekFrom::Start(index as u64 * piece_len))
+        .is_err()
+    {
+        return false;
+    }
+
+    // A short read means the piece isn't (fully) on disk yet
+    if file.read_exact(&mut buf).is_err() {
+        return false;
+    }
+
+    let hash: [u8; 20] = Sha1::digest(&buf).into();
+    hash == *expected
+}
+
+/// Spot-checks a single piece in the storage without a full recheck
+pub fn verify_piece(
+    file: &Mutex<dyn ReadWriteSeek>,
+    torrent_file: &TorrentFile,
+    index: usize,
+) -> bool {
+    let mut file = file.lock().unwrap();
+    piece_matches(
+        &mut *file,
+        &torrent_file.info.pieces,
+        index,
+        torrent_file.info.length,
+        torrent_file.info.piece_length,
+    )
+}
+
+/// Looks through `dir` (recursively) for a file whose content matches the torrent,
+/// even if it was renamed or moved, returns it with its completed pieces
+pub fn find_moved_file(
+    dir: &Path,
+    pieces: &[[u8; 20]],
+    total_length: u64,
+    piece_len: u64,
+    threads: usize,
+) -> Option<(PathBuf, HashSet<usize>)> {
+    let mut candidates = Vec::new();
+    collect_files(dir, &mut candidates);
+
+    // A (partially) downloaded file is never bigger than the torrent,
+    // try the ones with the exact length first
+    candidates.retain(|(_, len)| *len > 0 && *len <= total_length);
+    candidates.sort_by_key(|(_, len)| *len != total_length);
+
+    let mut best: Option<(PathBuf, HashSet<usize>)> = None;
+
+    for (path, _) in candidates {
+        let completed = check_download_percent(&path, pieces, total_length, piece_len, threads);
+        if completed.len() == pieces.len() {
+            return Some((path, completed));
+        }
+        if !completed.is_empty() && best.as_ref().is_none_or(|(_, b)| completed.len() > b.len()) {
+            best = Some((path, completed));
         }
+    }
+
+    best
+}
 
-        cursor += piece_len;
+fn collect_files(dir: &Path, out: &mut Vec<(PathBuf, u64)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            collect_files(&entry.path(), out);
+        } else if metadata.is_file() {
+            out.push((entry.path(), metadata.len()));
+        }
     }
+}
 
-    completed
+/// Whether `verified` out of `piece_count` pieces reach `percent` (0 to 100)
+pub fn reached_percent(verified: usize, piece_count: usize, percent: f64) -> bool {
+    verified as f64 * 100.0 >= percent * piece_count as f64
+}
+
+/// Length of piece `index`, only the last one can be shorter
+pub fn piece_size(torrent_file: &TorrentFile, index: usize) -> u64 {
+    let info = &torrent_file.info;
+    let start = index as u64 * info.piece_length;
+    info.length.saturating_sub(start).min(info.piece_length)
+}
+
+/// Bytes of the pieces not in `completed`, the `left` of an announce
+pub fn bytes_left(torrent_file: &TorrentFile, completed: &HashSet<usize>) -> u64 {
+    let done: u64 = completed
+        .iter()
+        .map(|index| piece_size(torrent_file, *index))
+        .sum();
+    torrent_file.info.length.saturating_sub(done)
 }
 
 pub fn retain_not_downloaded_pieces(completed: HashSet<usize>, mut buf: Vec<usize>) -> Vec<usize> {
     buf.retain(|e| !completed.contains(e));
     buf
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{temp_path, test_torrent};
+    use std::{fs, io::Write};
+
+    fn hash(buf: &[u8]) -> [u8; 20] {
+        Sha1::digest(buf).into()
+    }
+
+    #[test]
+    fn peer_ids_follow_policy() {
+        let per_torrent = PeerIds::new(PeerIdPolicy::PerTorrent);
+        assert_ne!(per_torrent.get(&[1; 20]), per_torrent.get(&[2; 20]));
+        assert_eq!(per_torrent.get(&[1; 20]), per_torrent.get(&[1; 20]));
+
+        let session_wide = PeerIds::new(PeerIdPolicy::SessionWide);
+        assert_eq!(session_wide.get(&[1; 20]), session_wide.get(&[2; 20]));
+        assert_eq!(session_wide.get(&[1; 20]).len(), 20);
+    }
+
+    #[test]
+    fn check_download_percent_finds_evenly_sized_last_piece() {
+        let path = temp_path("check-even");
+        let data: Vec<u8> = (0..64u8).collect();
+        fs::File::create(&path).unwrap().write_all(&data).unwrap();
+
+        let pieces: Vec<[u8; 20]> = data.chunks(16).map(hash).collect();
+        let completed = check_download_percent(&path, &pieces, 64, 16, 3);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(completed, HashSet::from([0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn check_download_percent_skips_truncated_pieces() {
+        let path = temp_path("check-short");
+        let data: Vec<u8> = (0..60u8).collect();
+        // Only the first two pieces and half of the third are on disk
+        fs::File::create(&path)
+            .unwrap()
+            .write_all(&data[..40])
+            .unwrap();
+
+        let pieces: Vec<[u8; 20]> = data.chunks(16).map(hash).collect();
+        let completed = check_download_percent(&path, &pieces, 60, 16, 2);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(completed, HashSet::from([0, 1]));
+    }
+
+    #[test]
+    fn finds_moved_file_by_content() {
+        let dir = temp_path("relink");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+
+        let data: Vec<u8> = (0..48u8).collect();
+        let pieces: Vec<[u8; 20]> = data.chunks(16).map(hash).collect();
+
+        fs::write(dir.join("other.bin"), vec![7u8; 48]).unwrap();
+        fs::write(dir.join("too-big.bin"), vec![7u8; 100]).unwrap();
+        fs::write(dir.join("nested").join("renamed.bin"), &data).unwrap();
+
+        let found = find_moved_file(&dir, &pieces, 48, 16, 2);
+        let missing = find_moved_file(&dir.join("nested"), &[[0; 20]], 48, 16, 2);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            found,
+            Some((
+                dir.join("nested").join("renamed.bin"),
+                HashSet::from([0, 1, 2])
+            ))
+        );
+        assert_eq!(missing, None);
+    }
+
+    #[test]
+    fn skips_trackers_with_unknown_schemes() {
+        let trackers = get_tracker_list(
+            String::from("http://tracker.example/announce"),
+            vec![vec![String::from("wss://tracker.example/announce")]],
+        );
+
+        assert_eq!(trackers.len(), 1);
+        assert_eq!(trackers[0].len(), 1);
+    }
+
+    #[test]
+    fn keeps_tracker_tiers_in_order() {
+        let url = |n: usize| format!("udp://tracker{}.example:1337/announce", n);
+        let trackers = get_tracker_list(
+            url(0),
+            vec![vec![url(0), url(1), url(2)], vec![url(3)], vec![]],
+        );
+
+        let tiers: Vec<HashSet<String>> = trackers
+            .iter()
+            .map(|tier| tier.iter().map(|t| t.url.to_string()).collect())
+            .collect();
+
+        assert_eq!(
+            tiers,
+            vec![
+                HashSet::from([url(0), url(1), url(2)]),
+                HashSet::from([url(3)])
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_configured_percent() {
+        assert!(!reached_percent(0, 10, 50.0));
+        assert!(!reached_percent(4, 10, 50.0));
+        assert!(reached_percent(5, 10, 50.0));
+        // 1 of 3 pieces is 33.3%
+        assert!(!reached_percent(1, 3, 34.0));
+        assert!(reached_percent(1, 3, 33.0));
+        assert!(reached_percent(3, 3, 100.0));
+    }
+
+    #[test]
+    fn counts_the_bytes_left() {
+        let torrent_file = test_torrent("test", 4, &[0; 10]);
+
+        assert_eq!(piece_size(&torrent_file, 1), 4);
+        assert_eq!(piece_size(&torrent_file, 2), 2);
+        assert_eq!(piece_size(&torrent_file, 3), 0);
+        assert_eq!(bytes_left(&torrent_file, &HashSet::new()), 10);
+        // The last piece is the short one
+        assert_eq!(bytes_left(&torrent_file, &HashSet::from([2])), 8);
+        assert_eq!(bytes_left(&torrent_file, &HashSet::from([0, 1, 2])), 0);
+    }
+
+    #[test]
+    fn verifies_single_pieces_from_storage() {
+        let torrent_file = test_torrent("test", 4, &[1, 2, 3, 4, 5, 6]);
+
+        // second piece corrupted
+        let storage = Mutex::new(std::io::Cursor::new(vec![1, 2, 3, 4, 5, 0]));
+
+        assert!(verify_piece(&storage, &torrent_file, 0));
+        assert!(!verify_piece(&storage, &torrent_file, 1));
+        assert!(!verify_piece(&storage, &torrent_file, 2));
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    io::SeekFrom,
+    net::SocketAddr,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use rubit_bencode::TorrentFile;
+use sha1::{Digest, Sha1};
+
+use crate::{HashFailures, ReadWriteSeek};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PieceResult {
+    Written(usize),
+    /// The piece was pushed back to the queue
+    HashFailed(usize),
+    /// Writing to the storage failed, the piece was pushed back to the queue
+    WriteFailed(usize),
+}
+
+struct PieceJob {
+    index: usize,
+    data: Vec<u8>,
+    /// Blamed in `HashFailures` if the piece is bad
+    peer: SocketAddr,
+}
+
+/// Handle to a dedicated thread writing reassembled pieces to the storage,
+/// optionally verifying their SHA1 first so peer threads only do network I/O
+#[derive(Clone)]
+pub struct PieceWriter {
+    sender: Sender<PieceJob>,
+    verify: bool,
+}
+
+impl PieceWriter {
+    /// The thread runs until every `PieceWriter` clone is dropped,
+    /// failed pieces go back to `global_queue` and every outcome is sent on the returned `Receiver`.
+    /// Hash failures are recorded against the peer that sent the piece, a piece already
+    /// written (a second copy from endgame) is dropped without any outcome
+    pub fn spawn(
+        file: Arc<Mutex<dyn ReadWriteSeek>>,
+        torrent_file: Arc<TorrentFile>,
+        global_queue: Arc<Mutex<VecDeque<usize>>>,
+        hash_failures: Arc<HashFailures>,
+        verify: bool,
+    ) -> (Self, Receiver<PieceResult>, JoinHandle<()>) {
+        let (sender, jobs) = mpsc::channel::<PieceJob>();
+        let (results_sender, results) = mpsc::channel();
+
+        let handle = thread::spawn(move || {
+            let mut written = HashSet::new();
+            for job in jobs {
+                if written.contains(&job.index) {
+                    continue;
+                }
+                let result = if verify
+                    && torrent_file.piece_hash(job.index) != Some(&Sha1::digest(&job.data).into())
+                {
+                    hash_failures.record(job.index, job.peer);
+                    PieceResult::HashFailed(job.index)
+                } else {
+                    let offset = job.index as u64 * torrent_file.info.piece_length;
+                    let mut file = file.lock().unwrap();
+                    match file
+                        .seek(SeekFrom::Start(offset))
+                        .and_then(|_| file.write_all(&job.data))
+                    {
+                        Ok(_) => PieceResult::Written(job.index),
+                        Err(_) => PieceResult::WriteFailed(job.index),
+                    }
+                };
+
+                if let PieceResult::Written(index) = result {
+                    written.insert(index);
+                } else {
+                    global_queue.lock().unwrap().push_back(job.index);
+                }
+
+                // Nobody listening for results is fine
+                let _ = results_sender.send(result);
+            }
+        });
+
+        (Self { sender, verify }, results, handle)
+    }
+
+    /// Whether submitted pieces get their SHA1 checked before being written
+    pub fn verifies(&self) -> bool {
+        self.verify
+    }
+
+    pub fn submit(&self, index: usize, data: Vec<u8>, peer: SocketAddr) {
+        let _ = self.sender.send(PieceJob { index, data, peer });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_torrent;
+    use std::io::Cursor;
+
+    #[test]
+    fn reports_verification_results_from_writer_thread() {
+        let torrent_file = Arc::new(test_torrent("test", 4, &[1, 2, 3, 4, 5, 6, 7, 8]));
+
+        let sink = Arc::new(Mutex::new(Cursor::new(vec![0u8; 8])));
+        let global_queue = Arc::new(Mutex::new(VecDeque::new()));
+
+        // Banned on the first bad piece
+        let hash_failures = Arc::new(HashFailures::new(2, 1));
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+
+        let (writer, results, handle) = PieceWriter::spawn(
+            sink.clone(),
+            torrent_file,
+            global_queue.clone(),
+            Arc::clone(&hash_failures),
+            true,
+        );
+
+        writer.submit(1, vec![9, 9, 9, 9], peer);
+        writer.submit(0, vec![1, 2, 3, 4], peer);
+        // Endgame copy of a piece already written
+        writer.submit(0, vec![0, 0, 0, 0], peer);
+        drop(writer);
+        handle.join().unwrap();
+
+        assert_eq!(
+            results.iter().collect::<Vec<_>>(),
+            vec![PieceResult::HashFailed(1), PieceResult::Written(0)]
+        );
+        assert_eq!(*global_queue.lock().unwrap(), VecDeque::from([1]));
+        assert!(hash_failures.is_banned(&peer));
+        assert_eq!(
+            sink.lock().unwrap().get_ref(),
+            &vec![1, 2, 3, 4, 0, 0, 0, 0]
+        );
+    }
+}